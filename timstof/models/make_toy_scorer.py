@@ -0,0 +1,95 @@
+"""Write models/toy_scorer.onnx, a tiny stand-in for the DIA-BERT scoring model.
+
+Inputs : rsm [batch, repeats, fragments, rt], precursor_feat [batch, 8]
+Output : score [batch] = sigmoid(log(1 + mean(rsm)) - 0.001 * mean(precursor_feat) - 5)
+
+Only the standard library is needed; the protobuf messages are encoded by hand.
+"""
+import struct
+import sys
+
+
+def varint(value):
+    out = bytearray()
+    value &= (1 << 64) - 1
+    while True:
+        byte = value & 0x7F
+        value >>= 7
+        if value:
+            out.append(byte | 0x80)
+        else:
+            out.append(byte)
+            return bytes(out)
+
+
+def field(number, wire_type, payload):
+    return varint(number << 3 | wire_type) + payload
+
+
+def int_field(number, value):
+    return field(number, 0, varint(value))
+
+
+def bytes_field(number, value):
+    if isinstance(value, str):
+        value = value.encode()
+    return field(number, 2, varint(len(value)) + value)
+
+
+FLOAT = 1
+
+
+def tensor_type(dims):
+    shape = b"".join(
+        bytes_field(1, bytes_field(2, d) if isinstance(d, str) else int_field(1, d)) for d in dims
+    )
+    return bytes_field(1, int_field(1, FLOAT) + bytes_field(2, shape))
+
+
+def value_info(name, dims):
+    return bytes_field(1, name) + bytes_field(2, tensor_type(dims))
+
+
+def scalar(name, value):
+    return int_field(2, FLOAT) + bytes_field(4, struct.pack("<f", value)) + bytes_field(8, name)
+
+
+def node(op, inputs, outputs, attributes=b""):
+    body = b"".join(bytes_field(1, i) for i in inputs)
+    body += b"".join(bytes_field(2, o) for o in outputs)
+    body += bytes_field(3, outputs[0]) + bytes_field(4, op) + attributes
+    return body
+
+
+def axes(values):
+    # AttributeProto: name, ints, type = INTS (7); keepdims = 0 (INT, 2)
+    ints = bytes_field(1, "axes") + b"".join(int_field(8, v) for v in values) + int_field(20, 7)
+    keep = bytes_field(1, "keepdims") + int_field(3, 0) + int_field(20, 2)
+    return bytes_field(5, ints) + bytes_field(5, keep)
+
+
+nodes = [
+    node("ReduceMean", ["rsm"], ["rsm_mean"], axes([1, 2, 3])),
+    node("Add", ["rsm_mean", "one"], ["rsm_mean_1"]),
+    node("Log", ["rsm_mean_1"], ["log_signal"]),
+    node("ReduceMean", ["precursor_feat"], ["feat_mean"], axes([1])),
+    node("Mul", ["feat_mean", "feat_weight"], ["feat_term"]),
+    node("Add", ["log_signal", "feat_term"], ["logit_raw"]),
+    node("Sub", ["logit_raw", "bias"], ["logit"]),
+    node("Sigmoid", ["logit"], ["score"]),
+]
+graph = b"".join(bytes_field(1, n) for n in nodes)
+graph += bytes_field(2, "toy_scorer")
+graph += b"".join(bytes_field(5, scalar(*s)) for s in [("one", 1.0), ("feat_weight", -0.001), ("bias", 5.0)])
+graph += bytes_field(11, value_info("rsm", ["batch", "repeats", "fragments", "rt"]))
+graph += bytes_field(11, value_info("precursor_feat", ["batch", 8]))
+graph += bytes_field(12, value_info("score", ["batch"]))
+
+model = int_field(1, 7)  # ir_version
+model += bytes_field(2, "dia_peak")
+model += bytes_field(7, graph)
+model += bytes_field(8, bytes_field(1, "") + int_field(2, 13))  # opset 13
+
+path = sys.argv[1] if len(sys.argv) > 1 else "models/toy_scorer.onnx"
+with open(path, "wb") as f:
+    f.write(model)
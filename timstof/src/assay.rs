@@ -0,0 +1,183 @@
+// File: src/assay.rs
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use csv::ReaderBuilder;
+
+use crate::chemistry::{ModifiedPeptide, fragment_type_code, parse_fragment_annotation};
+use crate::utils::LibraryRecord;
+
+/// Targets of an assay list as library records plus their RT/IM, in file order.
+pub struct AssayList {
+    pub records: Vec<LibraryRecord>,
+    pub ids: Vec<String>,
+    pub rt_dict: HashMap<String, f32>,
+    pub im_dict: HashMap<String, f32>,
+}
+
+fn parse_list(field: &str) -> Vec<&str> {
+    field.split(';').map(str::trim).filter(|v| !v.is_empty()).collect()
+}
+
+/// Take the `^charge` suffix out of a fragment label, wherever it sits
+/// (`y5^2-H2O` or `y5-H2O^2`); charge 1 without one.
+fn split_fragment_charge(label: &str) -> (String, &str) {
+    match label.split_once('^') {
+        Some((head, tail)) => {
+            let digits = tail.find(|c: char| !c.is_ascii_digit()).unwrap_or(tail.len());
+            let charge = if digits == 0 { "1" } else { &tail[..digits] };
+            (format!("{}{}", head, &tail[digits..]), charge)
+        }
+        None => (label.to_string(), "1"),
+    }
+}
+
+/// Read a targeted assay list (tab- or comma-separated, `#` comments), one
+/// row per target: `id`, `precursor_mz`, `charge`, `rt` (minutes), optional
+/// `im` (empty or 0 extracts the full mobility range), `;`-separated
+/// `fragment_mz` with optional matching `fragment_intensity` and
+/// `fragment_label`, and an optional modified `sequence` for peptides.
+/// Labels such as `y7`, `y5^2-H2O` or `b5-H2O^2` are annotated like library
+/// fragments; any other label (e.g. a metabolite fragment name) is kept as is.
+pub fn read_assay_list(path: &str) -> Result<AssayList, Box<dyn Error>> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Failed to read assay list {}: {}", path, e))?;
+    let header_line = content.lines().find(|l| !l.trim().is_empty() && !l.starts_with('#')).unwrap_or("");
+    let delimiter = if header_line.contains('\t') { b'\t' } else { b',' };
+    let mut reader = ReaderBuilder::new()
+        .delimiter(delimiter)
+        .comment(Some(b'#'))
+        .trim(csv::Trim::All)
+        .from_reader(content.as_bytes());
+
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|h| h == name);
+    let required = |name: &str| column(name).ok_or_else(|| format!("Assay list {} has no {} column.", path, name));
+    let (id_col, mz_col, charge_col, rt_col, fragment_col) =
+        (required("id")?, required("precursor_mz")?, required("charge")?, required("rt")?, required("fragment_mz")?);
+    let (im_col, intensity_col, label_col, sequence_col) =
+        (column("im"), column("fragment_intensity"), column("fragment_label"), column("sequence"));
+
+    let mut assays = AssayList { records: Vec::new(), ids: Vec::new(), rt_dict: HashMap::new(), im_dict: HashMap::new() };
+    let mut seen = HashSet::new();
+    for (row, result) in reader.records().enumerate() {
+        let fields = result?;
+        let get = |col: Option<usize>| col.and_then(|c| fields.get(c)).unwrap_or("");
+        let context = |msg: String| format!("Assay list {} row {}: {}", path, row + 1, msg);
+
+        let id = get(Some(id_col)).to_string();
+        if id.is_empty() || !seen.insert(id.clone()) {
+            return Err(context(format!("missing or duplicate id '{}'", id)).into());
+        }
+        let precursor_mz: f32 = get(Some(mz_col)).parse().map_err(|_| context(format!("invalid precursor_mz '{}'", get(Some(mz_col)))))?;
+        let charge: u8 = get(Some(charge_col)).parse().map_err(|_| context(format!("invalid charge '{}'", get(Some(charge_col)))))?;
+        let rt: f32 = get(Some(rt_col)).parse().map_err(|_| context(format!("invalid rt '{}'", get(Some(rt_col)))))?;
+        let im: f32 = match get(im_col) {
+            "" => 0.0,
+            value => value.parse().map_err(|_| context(format!("invalid im '{}'", value)))?,
+        };
+
+        let fragment_mzs = parse_list(get(Some(fragment_col)));
+        let intensities = parse_list(get(intensity_col));
+        let labels = parse_list(get(label_col));
+        if fragment_mzs.is_empty() {
+            return Err(context("no fragment_mz values".to_string()).into());
+        }
+        if (!intensities.is_empty() && intensities.len() != fragment_mzs.len()) || (!labels.is_empty() && labels.len() != fragment_mzs.len()) {
+            return Err(context(format!(
+                "{} fragment_mz values but {} intensities and {} labels",
+                fragment_mzs.len(), intensities.len(), labels.len()
+            ))
+            .into());
+        }
+
+        // Peptides keep their sequence (composition isotope model); other targets have none
+        let sequence = get(sequence_col);
+        let stripped = if sequence.is_empty() {
+            String::new()
+        } else {
+            ModifiedPeptide::parse(sequence).map_err(|e| context(format!("invalid sequence '{}': {}", sequence, e)))?.stripped()
+        };
+
+        for (k, mz) in fragment_mzs.iter().enumerate() {
+            mz.parse::<f32>().map_err(|_| context(format!("invalid fragment_mz '{}'", mz)))?;
+            let intensity = intensities.get(k).copied().unwrap_or("1");
+            intensity.parse::<f32>().map_err(|_| context(format!("invalid fragment_intensity '{}'", intensity)))?;
+            let label = labels.get(k).copied().unwrap_or("");
+            let (ion_label, fragment_charge) = split_fragment_charge(label);
+            let annotation = parse_fragment_annotation(&ion_label);
+            let (fragment_type, fragment_number) = match fragment_type_code(&annotation.series) {
+                Some(code) => (code.to_string(), annotation.number.clone().unwrap_or_default()),
+                None => (label.to_string(), String::new()),
+            };
+            assays.records.push(LibraryRecord {
+                transition_group_id: id.clone(),
+                peptide_sequence: stripped.clone(),
+                full_unimod_peptide_name: sequence.to_string(),
+                precursor_charge: charge.to_string(),
+                precursor_mz: precursor_mz.to_string(),
+                tr_recalibrated: rt.to_string(),
+                ion_mobility: im.to_string(),
+                product_mz: mz.to_string(),
+                fragment_type,
+                fragment_charge: fragment_charge.to_string(),
+                fragment_number,
+                fragment_loss_type: annotation.loss.unwrap_or_else(|| "noloss".to_string()),
+                library_intensity: intensity.to_string(),
+                protein_id: String::new(),
+                protein_name: String::new(),
+                gene: String::new(),
+                decoy: "0".to_string(),
+                paired_precursor_id: String::new(),
+                other_columns: HashMap::new(),
+            });
+        }
+        assays.rt_dict.insert(id.clone(), rt);
+        assays.im_dict.insert(id.clone(), im);
+        assays.ids.push(id);
+    }
+    Ok(assays)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(content: &str) -> Result<AssayList, Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("dia_peak_assay_{}_{}.tsv", std::process::id(), content.len()));
+        fs::write(&path, content).unwrap();
+        let assays = read_assay_list(&path.to_string_lossy());
+        fs::remove_file(&path).unwrap();
+        assays
+    }
+
+    #[test]
+    fn reads_peptide_and_metabolite_targets() {
+        let assays = read(
+            "# targets\n\
+             id\tprecursor_mz\tcharge\trt\tim\tfragment_mz\tfragment_intensity\tfragment_label\tsequence\n\
+             pep\t464.7\t2\t12.5\t0.9\t476.2;363.2\t100;50\ty4^2-H2O;b3-H2O^2\tPEPTIDEK\n\
+             met\t180.1\t1\t3.0\t\t163.1\t\tloss_water\t\n",
+        )
+        .unwrap();
+
+        assert_eq!(assays.ids, ["pep", "met"]);
+        assert_eq!(assays.records.len(), 3);
+        assert_eq!(assays.rt_dict["pep"], 12.5);
+        assert_eq!(assays.im_dict["met"], 0.0);
+        // The charge suffix may sit before or after the loss
+        for (record, number) in assays.records[..2].iter().zip(["4", "3"]) {
+            assert_eq!((record.fragment_number.as_str(), record.fragment_charge.as_str(), record.fragment_loss_type.as_str()), (number, "2", "H2O"));
+            assert_eq!(record.peptide_sequence, "PEPTIDEK");
+        }
+        let metabolite = &assays.records[2];
+        assert_eq!((metabolite.fragment_type.as_str(), metabolite.library_intensity.as_str()), ("loss_water", "1"));
+    }
+
+    #[test]
+    fn rejects_duplicate_ids_and_mismatched_lists() {
+        let header = "id,precursor_mz,charge,rt,fragment_mz,fragment_intensity\n";
+        assert!(read(&format!("{}a,400,2,10,300;200,1;2\na,410,2,11,300,1\n", header)).is_err());
+        assert!(read(&format!("{}a,400,2,10,300;200,1\n", header)).is_err());
+        assert!(read("id,precursor_mz,charge,rt\na,400,2,10\n").is_err());
+    }
+}
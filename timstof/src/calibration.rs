@@ -0,0 +1,291 @@
+// File: src/calibration.rs
+use std::collections::HashMap;
+use std::error::Error;
+use rayon::prelude::*;
+
+use crate::config::{Config, ExtractionConfig, LibraryOnlyConfig};
+use crate::processing::{FastChunkFinder, RtSelection, extract_precursor, fragment_coelution_scores};
+use crate::utils::{IndexedTimsTOFData, PrecursorLibData};
+
+/// A precursor found with confidence in the anchor pass.
+#[derive(Debug, Clone)]
+pub struct AnchorHit {
+    pub irt: f32,
+    pub rt: f32,
+    pub score: f32,
+}
+
+/// First and last RT (minutes) covered by the MS1 data.
+pub fn gradient_rt_range(ms1_indexed: &IndexedTimsTOFData) -> (f32, f32) {
+    ms1_indexed.rt_values_min
+        .par_iter()
+        .fold(|| (f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), &rt| (lo.min(rt), hi.max(rt)))
+        .reduce(|| (f32::INFINITY, f32::NEG_INFINITY), |a, b| (a.0.min(b.0), a.1.max(b.1)))
+}
+
+/// Pick up to `n_candidates` precursors spread evenly over the iRT range.
+pub fn select_anchor_candidates(
+    precursor_ids: &[String],
+    irt_dict: &HashMap<String, f32>,
+    n_candidates: usize,
+) -> Vec<String> {
+    let mut by_irt: Vec<(&String, f32)> = precursor_ids
+        .iter()
+        .filter_map(|id| irt_dict.get(id).map(|&irt| (id, irt)))
+        .collect();
+    by_irt.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+
+    if by_irt.len() <= n_candidates {
+        return by_irt.into_iter().map(|(id, _)| id.clone()).collect();
+    }
+
+    let step = by_irt.len() as f64 / n_candidates as f64;
+    (0..n_candidates)
+        .map(|k| by_irt[(k as f64 * step) as usize].0.clone())
+        .collect()
+}
+
+/// Extract each candidate over `rt_range` and keep the best-scoring apexes.
+/// `candidate.rt` is expected to still hold the library iRT.
+pub fn find_anchor_precursors(
+    candidates: &[PrecursorLibData],
+    ms1_indexed: &IndexedTimsTOFData,
+    finder: &FastChunkFinder,
+    extraction: &ExtractionConfig,
+    rt_range: (f32, f32),
+    library_only: &LibraryOnlyConfig,
+    device: &str,
+) -> Vec<AnchorHit> {
+    let mut hits: Vec<AnchorHit> = candidates
+        .par_iter()
+        .filter_map(|precursor_data| {
+            let extracted = extract_precursor(
+                precursor_data,
+                ms1_indexed,
+                finder,
+                extraction,
+                RtSelection::Range(rt_range.0, rt_range.1),
+                device,
+            ).ok()?;
+
+            let (scores, detected) = fragment_coelution_scores(&extracted.rsm_matrix, &extracted.frag_info);
+            let (apex_idx, &apex_score) = scores
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal))?;
+
+            if apex_score <= 0.0 || detected[apex_idx] < library_only.min_fragments {
+                return None;
+            }
+
+            Some(AnchorHit {
+                irt: precursor_data.rt,
+                rt: extracted.all_rt[apex_idx],
+                score: apex_score,
+            })
+        })
+        .collect();
+
+    hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    let n_keep = ((candidates.len() as f32 * library_only.anchor_fraction).ceil() as usize)
+        .max(library_only.min_anchors)
+        .min(hits.len());
+    hits.truncate(n_keep);
+    hits
+}
+
+/// Piecewise-linear iRT -> RT mapping through binned anchor medians.
+#[derive(Debug, Clone)]
+pub struct RtCalibration {
+    irt_knots: Vec<f32>,
+    rt_knots: Vec<f32>,
+}
+
+impl RtCalibration {
+    pub fn fit(anchors: &[AnchorHit], n_bins: usize) -> Result<Self, Box<dyn Error>> {
+        if anchors.len() < 2 {
+            return Err(format!("not enough anchors for RT calibration: {}", anchors.len()).into());
+        }
+
+        // 1. Robust linear fit: drop anchors further than 3 scaled MADs, twice
+        let mut points: Vec<(f32, f32)> = anchors.iter().map(|a| (a.irt, a.rt)).collect();
+        for _ in 0..2 {
+            let (slope, intercept) = linear_fit(&points);
+            let residuals: Vec<f32> = points.iter().map(|&(x, y)| y - (slope * x + intercept)).collect();
+            let mad = median(residuals.iter().map(|r| r.abs()).collect()) * 1.4826;
+            if mad <= 0.0 {
+                break;
+            }
+            let kept: Vec<(f32, f32)> = points
+                .iter()
+                .zip(&residuals)
+                .filter(|(_, r)| r.abs() <= 3.0 * mad)
+                .map(|(&p, _)| p)
+                .collect();
+            if kept.len() < 2 {
+                break;
+            }
+            points = kept;
+        }
+
+        // 2. Equal-count bins -> one (median iRT, median RT) knot per bin
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        let n_bins = n_bins.min(points.len() / 5).max(1);
+        let mut irt_knots = Vec::with_capacity(n_bins);
+        let mut rt_knots = Vec::with_capacity(n_bins);
+        for bin in 0..n_bins {
+            let start = bin * points.len() / n_bins;
+            let end = (bin + 1) * points.len() / n_bins;
+            let chunk = &points[start..end];
+            let irt = median(chunk.iter().map(|p| p.0).collect());
+            let rt = median(chunk.iter().map(|p| p.1).collect());
+            // Keep the mapping monotonic
+            let rt = rt_knots.last().map_or(rt, |&last: &f32| rt.max(last));
+            if irt_knots.last().is_some_and(|&last| irt <= last) {
+                continue;
+            }
+            irt_knots.push(irt);
+            rt_knots.push(rt);
+        }
+
+        // 3. Fall back to the straight line when the bins collapse
+        if irt_knots.len() < 2 {
+            let (slope, intercept) = linear_fit(&points);
+            let lo = points[0].0;
+            let hi = points[points.len() - 1].0.max(lo + 1.0);
+            irt_knots = vec![lo, hi];
+            rt_knots = vec![slope * lo + intercept, slope * hi + intercept];
+        }
+
+        Ok(Self { irt_knots, rt_knots })
+    }
+
+    /// Interpolate between knots; extrapolate with the first/last segment.
+    pub fn predict(&self, irt: f32) -> f32 {
+        let n = self.irt_knots.len();
+        let seg = match self.irt_knots.partition_point(|&x| x < irt) {
+            0 => 0,
+            pos if pos >= n => n - 2,
+            pos => pos - 1,
+        };
+        let (x0, x1) = (self.irt_knots[seg], self.irt_knots[seg + 1]);
+        let (y0, y1) = (self.rt_knots[seg], self.rt_knots[seg + 1]);
+        y0 + (irt - x0) * (y1 - y0) / (x1 - x0)
+    }
+
+    pub fn n_knots(&self) -> usize {
+        self.irt_knots.len()
+    }
+}
+
+/// Anchor pass + calibration: returns calibrated RT (minutes) per precursor.
+/// `candidates` come from `select_anchor_candidates` with `rt` holding iRT.
+pub fn calibrate_library_rt(
+    candidates: &[PrecursorLibData],
+    precursor_ids: &[String],
+    irt_dict: &HashMap<String, f32>,
+    ms1_indexed: &IndexedTimsTOFData,
+    finder: &FastChunkFinder,
+    config: &Config,
+    device: &str,
+) -> Result<HashMap<String, f32>, Box<dyn Error>> {
+    let library_only = &config.library_only;
+    let rt_range = gradient_rt_range(ms1_indexed);
+    println!("  - Gradient RT range: {:.2} - {:.2} min", rt_range.0, rt_range.1);
+
+    let anchors = find_anchor_precursors(
+        candidates, ms1_indexed, finder, &config.extraction, rt_range, library_only, device,
+    );
+    println!("  - Anchors: {} of {} candidates", anchors.len(), candidates.len());
+
+    let calibration = RtCalibration::fit(&anchors, library_only.calibration_bins)?;
+    println!("  - RT calibration knots: {}", calibration.n_knots());
+
+    Ok(precursor_ids
+        .iter()
+        .filter_map(|id| irt_dict.get(id).map(|&irt| (id.clone(), calibration.predict(irt))))
+        .collect())
+}
+
+pub fn linear_fit(points: &[(f32, f32)]) -> (f32, f32) {
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0 as f64).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1 as f64).sum::<f64>() / n;
+    let mut sxx = 0.0;
+    let mut sxy = 0.0;
+    for &(x, y) in points {
+        sxx += (x as f64 - mean_x).powi(2);
+        sxy += (x as f64 - mean_x) * (y as f64 - mean_y);
+    }
+    let slope = if sxx > 0.0 { sxy / sxx } else { 0.0 };
+    (slope as f32, (mean_y - slope * mean_x) as f32)
+}
+
+pub fn median(mut values: Vec<f32>) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn anchors(irts: impl Iterator<Item = f32>, rt: impl Fn(f32) -> f32) -> Vec<AnchorHit> {
+        irts.map(|irt| AnchorHit { irt, rt: rt(irt), score: 1.0 }).collect()
+    }
+
+    #[test]
+    fn anchor_candidates_are_spread_evenly_over_irt() {
+        // IDs in scrambled order, irt = 2 * k; one ID has no iRT
+        let ids: Vec<String> = (0..100).map(|k| format!("p{}", (k * 37) % 100)).chain(["unknown".to_string()]).collect();
+        let irt_dict: HashMap<String, f32> = (0..100).map(|k| (format!("p{}", k), 2.0 * k as f32)).collect();
+
+        let picked = select_anchor_candidates(&ids, &irt_dict, 5);
+        let irts: Vec<f32> = picked.iter().map(|id| irt_dict[id]).collect();
+        assert_eq!(irts, vec![0.0, 40.0, 80.0, 120.0, 160.0]);
+
+        assert_eq!(select_anchor_candidates(&ids[..3], &irt_dict, 5), ["p0", "p37", "p74"]);
+    }
+
+    #[test]
+    fn fit_recovers_a_linear_mapping_despite_outliers() {
+        let mut hits = anchors((-50..=50).map(|k| k as f32), |irt| 0.5 * irt + 30.0);
+        hits[10].rt += 15.0;
+        hits[70].rt -= 20.0;
+        let calibration = RtCalibration::fit(&hits, 10).unwrap();
+
+        assert_eq!(calibration.n_knots(), 10);
+        for (irt, rt) in [(-20.0, 20.0), (40.0, 50.0), (100.0, 80.0), (-80.0, -10.0)] {
+            assert!((calibration.predict(irt) - rt).abs() < 1e-3, "iRT {}: {}", irt, calibration.predict(irt));
+        }
+    }
+
+    #[test]
+    fn fit_follows_a_piecewise_linear_gradient() {
+        let rt = |irt: f32| if irt < 0.0 { 30.0 + 0.4 * irt } else { 30.0 + 0.8 * irt };
+        let calibration = RtCalibration::fit(&anchors((-100..100).map(|k| k as f32 + 0.5), rt), 20).unwrap();
+
+        for irt in [-80.0, -40.0, 40.0, 80.0] {
+            assert!((calibration.predict(irt) - rt(irt)).abs() < 1e-3, "iRT {}: {}", irt, calibration.predict(irt));
+        }
+    }
+
+    #[test]
+    fn fit_falls_back_to_a_line_with_too_few_anchors_per_bin() {
+        // 6 anchors only fill one bin of 5
+        let calibration = RtCalibration::fit(&anchors((0..6).map(|k| 10.0 * k as f32), |irt| 2.0 * irt + 5.0), 10).unwrap();
+        assert_eq!(calibration.n_knots(), 2);
+        assert!((calibration.predict(25.0) - 55.0).abs() < 1e-3);
+        assert!((calibration.predict(-10.0) + 15.0).abs() < 1e-3);
+
+        assert!(RtCalibration::fit(&anchors([1.0].into_iter(), |irt| irt), 10).is_err());
+    }
+}
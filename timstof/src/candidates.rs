@@ -0,0 +1,147 @@
+// File: src/candidates.rs
+use std::sync::Arc;
+use ndarray::{Array4, s};
+
+use crate::config::CandidateConfig;
+use crate::processing::{ExtractedPrecursor, fragment_coelution_scores};
+
+/// One candidate peak group: a fixed-length RT window cut from a broad extraction.
+#[derive(Debug, Clone)]
+pub struct RtCandidate {
+    pub rank: usize,
+    pub start: usize,     // first RT index of the window in the broad extraction
+    pub apex_rt: f32,     // RT of the best-scoring point inside the window
+    pub pre_score: f32,   // summed fragment co-elution score over the window
+}
+
+/// Slide a `window_length` window with `stride` over the broad extraction and
+/// keep the `top_k` best windows whose apexes are at least half a window apart.
+pub fn generate_rt_candidates(extracted: &ExtractedPrecursor, config: &CandidateConfig) -> Vec<RtCandidate> {
+    let n_rt = extracted.all_rt.len();
+    if n_rt == 0 {
+        return Vec::new();
+    }
+
+    let (scores, _) = fragment_coelution_scores(&extracted.rsm_matrix, &extracted.frag_info);
+    let window_length = config.window_length.min(n_rt);
+    let stride = config.stride.max(1);
+
+    // 1. Score every window position (always include the last full window)
+    let mut starts: Vec<usize> = (0..=n_rt - window_length).step_by(stride).collect();
+    if starts.last() != Some(&(n_rt - window_length)) {
+        starts.push(n_rt - window_length);
+    }
+
+    let mut windows: Vec<(usize, usize, f32)> = starts
+        .into_iter()
+        .map(|start| {
+            let window = &scores[start..start + window_length];
+            let (apex_offset, _) = window
+                .iter()
+                .enumerate()
+                .fold((0, f32::NEG_INFINITY), |best, (k, &v)| if v > best.1 { (k, v) } else { best });
+            (start, start + apex_offset, window.iter().sum::<f32>())
+        })
+        .collect();
+
+    windows.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(std::cmp::Ordering::Equal));
+
+    // 2. Greedy non-maximum suppression on the apex position
+    let min_separation = window_length / 2;
+    let mut picked: Vec<(usize, usize, f32)> = Vec::with_capacity(config.top_k);
+    for window in windows {
+        if picked.len() >= config.top_k {
+            break;
+        }
+        if picked.iter().any(|p| p.1.abs_diff(window.1) < min_separation) {
+            continue;
+        }
+        picked.push(window);
+    }
+
+    picked
+        .into_iter()
+        .enumerate()
+        .map(|(rank, (start, apex_idx, pre_score))| RtCandidate {
+            rank,
+            start,
+            apex_rt: extracted.all_rt[apex_idx],
+            pre_score,
+        })
+        .collect()
+}
+
+/// Cut the candidate window out of the broad extraction, zero-padding the RT
+/// axis up to `window_length` like `get_rt_list` does for sparse data.
+pub fn slice_candidate(extracted: &ExtractedPrecursor, candidate: &RtCandidate, window_length: usize) -> ExtractedPrecursor {
+    let n_rt = extracted.all_rt.len();
+    let end = (candidate.start + window_length).min(n_rt);
+    let shape = extracted.rsm_matrix.shape();
+
+    let mut rsm_matrix = Array4::<f32>::zeros((shape[0], shape[1], shape[2], window_length));
+    rsm_matrix
+        .slice_mut(s![.., .., .., ..end - candidate.start])
+        .assign(&extracted.rsm_matrix.slice(s![.., .., .., candidate.start..end]));
+
+    let mut all_rt = extracted.all_rt[candidate.start..end].to_vec();
+    all_rt.resize(window_length, 0.0);
+    let mut ms1_columns = extracted.ms1_columns[candidate.start..end].to_vec();
+    ms1_columns.resize(window_length, false);
+    let mut ms2_columns = extracted.ms2_columns[candidate.start..end].to_vec();
+    ms2_columns.resize(window_length, false);
+
+    ExtractedPrecursor {
+        rsm_matrix,
+        frag_info: extracted.frag_info.clone(),
+        all_rt,
+        ms1_columns,
+        ms2_columns,
+        ms1_peaks: Arc::clone(&extracted.ms1_peaks),
+        ms2_peaks: Arc::clone(&extracted.ms2_peaks),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array3;
+    use crate::utils::VARIANT_ORIGINAL;
+
+    // One fragment (row 6) with single-point signals of 10, 8 and 5 at RT
+    // indices 10, 12 and 30 of a 40-point axis
+    fn extraction() -> ExtractedPrecursor {
+        let mut frag_info = Array3::<f32>::zeros((1, 72, 4));
+        frag_info[[0, 6, 0]] = 500.0;
+        frag_info[[0, 6, 2]] = VARIANT_ORIGINAL;
+        let mut rsm = Array4::<f32>::zeros((1, 1, 72, 40));
+        for (rt, intensity) in [(10, 10.0), (12, 8.0), (30, 5.0)] {
+            rsm[[0, 0, 6, rt]] = intensity;
+        }
+        ExtractedPrecursor::synthetic(rsm, frag_info, (0..40).map(|k| 20.0 + 0.1 * k as f32).collect())
+    }
+
+    #[test]
+    fn suppression_keeps_the_best_of_overlapping_windows() {
+        let config = CandidateConfig { enabled: true, rt_tolerance: 0.0, window_length: 8, stride: 1, top_k: 2 };
+        let candidates = generate_rt_candidates(&extraction(), &config);
+
+        // Windows holding the apex at 12 overlap the best one (apex 10) and are dropped
+        let apexes: Vec<(usize, f32, f32)> = candidates.iter().map(|c| (c.rank, c.apex_rt, c.pre_score)).collect();
+        assert_eq!(apexes, vec![(0, 21.0, 18.0), (1, 23.0, 5.0)]);
+        assert!(candidates[0].start <= 10 && candidates[0].start + 8 > 12);
+    }
+
+    #[test]
+    fn slice_zero_pads_past_the_end_of_the_extraction() {
+        let extracted = extraction();
+        let candidate = RtCandidate { rank: 0, start: 28, apex_rt: 23.0, pre_score: 5.0 };
+        let view = slice_candidate(&extracted, &candidate, 16);
+
+        assert_eq!(view.rsm_matrix.shape(), &[1, 1, 72, 16]);
+        assert_eq!(view.rsm_matrix[[0, 0, 6, 2]], 5.0);
+        assert_eq!(view.all_rt[11], extracted.all_rt[39]);
+        assert!(view.all_rt[12..].iter().all(|&rt| rt == 0.0));
+        assert_eq!(view.ms2_columns.iter().filter(|&&c| c).count(), 12);
+        assert!(view.rsm_matrix.slice(s![.., .., .., 12..]).iter().all(|&v| v == 0.0));
+    }
+}
@@ -0,0 +1,645 @@
+// File: src/chemistry.rs
+use std::error::Error;
+
+// 单同位素质量 (monoisotopic masses, Da)
+pub const PROTON_MASS: f64 = 1.007_276_466_812;
+pub const H_MASS: f64 = 1.007_825_032;
+pub const H2O_MASS: f64 = 18.010_564_684;
+pub const NH3_MASS: f64 = 17.026_549_101;
+pub const CO_MASS: f64 = 27.994_914_620;
+pub const H3PO4_MASS: f64 = 97.976_895_573;
+pub const NEUTRON_SPACING: f64 = 1.003_354_835; // 13C - 12C
+
+/// Fragment ion series. N-terminal: a/b/c, C-terminal: x/y/z (z is z-dot).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IonType {
+    A,
+    B,
+    C,
+    X,
+    Y,
+    Z,
+}
+
+impl IonType {
+    pub fn from_char(c: char) -> Option<Self> {
+        match c {
+            'a' => Some(IonType::A),
+            'b' => Some(IonType::B),
+            'c' => Some(IonType::C),
+            'x' => Some(IonType::X),
+            'y' => Some(IonType::Y),
+            'z' => Some(IonType::Z),
+            _ => None,
+        }
+    }
+
+    pub fn is_n_terminal(self) -> bool {
+        matches!(self, IonType::A | IonType::B | IonType::C)
+    }
+
+    /// Mass added to the summed residues (plus N-term mods for a/b/c).
+    fn terminal_offset(self) -> f64 {
+        match self {
+            IonType::A => -CO_MASS,
+            IonType::B => 0.0,
+            IonType::C => NH3_MASS,
+            IonType::X => H2O_MASS + CO_MASS - 2.0 * H_MASS,
+            IonType::Y => H2O_MASS,
+            IonType::Z => H2O_MASS - NH3_MASS + H_MASS,
+        }
+    }
+}
+
+/// Neutral losses found in fragment annotations such as `y5-H2O`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NeutralLoss {
+    H2O,
+    NH3,
+    H3PO4,
+    CO,
+}
+
+impl NeutralLoss {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim() {
+            // nominal masses as used in OpenSWATH annotations (y5-18)
+            "H2O" | "h2o" | "18" => Some(NeutralLoss::H2O),
+            "NH3" | "nh3" | "17" => Some(NeutralLoss::NH3),
+            "H3PO4" | "h3po4" | "98" => Some(NeutralLoss::H3PO4),
+            "CO" | "co" | "28" => Some(NeutralLoss::CO),
+            _ => None,
+        }
+    }
+
+    pub fn mass(self) -> f64 {
+        match self {
+            NeutralLoss::H2O => H2O_MASS,
+            NeutralLoss::NH3 => NH3_MASS,
+            NeutralLoss::H3PO4 => H3PO4_MASS,
+            NeutralLoss::CO => CO_MASS,
+        }
+    }
+}
+
+/// Monoisotopic residue mass of a standard amino acid.
+pub fn residue_mass(aa: char) -> Option<f64> {
+    let mass = match aa {
+        'G' => 57.021_463_72,
+        'A' => 71.037_113_79,
+        'S' => 87.032_028_41,
+        'P' => 97.052_763_85,
+        'V' => 99.068_413_91,
+        'T' => 101.047_678_5,
+        'C' => 103.009_184_5,
+        'L' => 113.084_064_0,
+        'I' => 113.084_064_0,
+        'N' => 114.042_927_4,
+        'D' => 115.026_943_1,
+        'Q' => 128.058_577_5,
+        'K' => 128.094_963_1,
+        'E' => 129.042_593_1,
+        'M' => 131.040_484_6,
+        'H' => 137.058_911_9,
+        'F' => 147.068_414_0,
+        'R' => 156.101_111_1,
+        'Y' => 163.063_328_6,
+        'W' => 186.079_313_0,
+        'U' => 150.953_633_4,
+        _ => return None,
+    };
+    Some(mass)
+}
+
+/// Monoisotopic mass delta of the UniMod accessions seen in DIA libraries.
+pub fn unimod_mass(unimod_id: u32) -> Option<f64> {
+    let mass = match unimod_id {
+        1 => 42.010_565,     // Acetyl
+        4 => 57.021_464,     // Carbamidomethyl
+        5 => 43.005_814,     // Carbamyl
+        7 => 0.984_016,      // Deamidated
+        21 => 79.966_331,    // Phospho
+        26 => 39.994_915,    // Pyro-carbamidomethyl
+        27 => -18.010_565,   // Glu->pyro-Glu
+        28 => -17.026_549,   // Gln->pyro-Glu
+        34 => 14.015_650,    // Methyl
+        35 => 15.994_915,    // Oxidation
+        36 => 28.031_300,    // Dimethyl
+        37 => 42.046_950,    // Trimethyl
+        121 => 114.042_927,  // GG
+        214 => 144.102_063,  // iTRAQ4plex
+        259 => 8.014_199,    // Label:13C(6)15N(2)
+        267 => 10.008_269,   // Label:13C(6)15N(4)
+        737 => 229.162_932,  // TMT6plex
+        _ => return None,
+    };
+    Some(mass)
+}
+
+/// One residue of a modified peptide with the UniMod IDs attached to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Residue {
+    pub aa: char,
+    pub mods: Vec<u32>,
+}
+
+/// Modified peptide parsed from `FullUniModPeptideName`, e.g.
+/// `_(UniMod:1)AC(UniMod:4)DEM(UniMod:35)K_`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModifiedPeptide {
+    pub nterm_mods: Vec<u32>,
+    pub residues: Vec<Residue>,
+}
+
+impl ModifiedPeptide {
+    pub fn parse(sequence: &str) -> Result<Self, Box<dyn Error>> {
+        let mut nterm_mods = Vec::new();
+        let mut residues: Vec<Residue> = Vec::new();
+        // Generated decoys carry a DECOY_ prefix on the modified sequence
+        let body = sequence.strip_prefix("DECOY_").unwrap_or(sequence);
+        let mut chars = body.trim_matches(|c| c == '_' || c == '.').chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '(' | '[' => {
+                    let close = if c == '(' { ')' } else { ']' };
+                    let token: String = chars.by_ref().take_while(|&ch| ch != close).collect();
+                    let id = token
+                        .trim()
+                        .strip_prefix("UniMod:")
+                        .or_else(|| token.trim().strip_prefix("UNIMOD:"))
+                        .and_then(|v| v.parse::<u32>().ok())
+                        .ok_or_else(|| format!("unsupported modification '{}' in {}", token, sequence))?;
+                    match residues.last_mut() {
+                        Some(residue) => residue.mods.push(id),
+                        None => nterm_mods.push(id),
+                    }
+                }
+                '.' | '_' | '-' => {}
+                aa if aa.is_ascii_uppercase() => residues.push(Residue { aa, mods: Vec::new() }),
+                other => return Err(format!("unexpected character '{}' in {}", other, sequence).into()),
+            }
+        }
+
+        if residues.is_empty() {
+            return Err(format!("empty peptide sequence: {}", sequence).into());
+        }
+        Ok(Self { nterm_mods, residues })
+    }
+
+    /// Stripped sequence without modifications.
+    pub fn stripped(&self) -> String {
+        self.residues.iter().map(|r| r.aa).collect()
+    }
+
+    /// Rebuild the DIA-NN style modified sequence, e.g. `(UniMod:1)AC(UniMod:4)K`.
+    pub fn to_unimod_string(&self) -> String {
+        let mut out = String::new();
+        for id in &self.nterm_mods {
+            out.push_str(&format!("(UniMod:{})", id));
+        }
+        for residue in &self.residues {
+            out.push(residue.aa);
+            for id in &residue.mods {
+                out.push_str(&format!("(UniMod:{})", id));
+            }
+        }
+        out
+    }
+
+    /// Mass of residue `idx` including its modifications.
+    pub fn residue_total_mass(&self, idx: usize) -> Result<f64, Box<dyn Error>> {
+        let residue = &self.residues[idx];
+        let mut mass = residue_mass(residue.aa)
+            .ok_or_else(|| format!("unknown residue '{}'", residue.aa))?;
+        for &id in &residue.mods {
+            mass += unimod_mass(id).ok_or_else(|| format!("unknown UniMod:{}", id))?;
+        }
+        Ok(mass)
+    }
+
+    fn nterm_mass(&self) -> Result<f64, Box<dyn Error>> {
+        self.nterm_mods.iter().try_fold(0.0, |acc, &id| {
+            Ok(acc + unimod_mass(id).ok_or_else(|| format!("unknown UniMod:{}", id))?)
+        })
+    }
+
+    /// Neutral monoisotopic mass of the peptide.
+    pub fn monoisotopic_mass(&self) -> Result<f64, Box<dyn Error>> {
+        let mut mass = H2O_MASS + self.nterm_mass()?;
+        for idx in 0..self.residues.len() {
+            mass += self.residue_total_mass(idx)?;
+        }
+        Ok(mass)
+    }
+
+    pub fn precursor_mz(&self, charge: u32) -> Result<f64, Box<dyn Error>> {
+        Ok(mz_from_mass(self.monoisotopic_mass()?, charge))
+    }
+
+    /// m/z of an `ion_type` ion with `number` residues at the given charge,
+    /// optionally minus a neutral loss.
+    pub fn fragment_mz(
+        &self,
+        ion_type: IonType,
+        number: usize,
+        charge: u32,
+        loss: Option<NeutralLoss>,
+    ) -> Result<f64, Box<dyn Error>> {
+        let n = self.residues.len();
+        if number == 0 || number >= n || charge == 0 {
+            return Err(format!("fragment {}{}+{} out of range for length {}", ion_type_name(ion_type), number, charge, n).into());
+        }
+        let residues = if ion_type.is_n_terminal() { 0..number } else { (n - number)..n };
+        let mut neutral = ion_type.terminal_offset();
+        if ion_type.is_n_terminal() {
+            neutral += self.nterm_mass()?;
+        }
+        for idx in residues {
+            neutral += self.residue_total_mass(idx)?;
+        }
+        if let Some(loss) = loss {
+            neutral -= loss.mass();
+        }
+        Ok(mz_from_mass(neutral, charge))
+    }
+
+    /// Monoisotopic precursor m/z followed by `n_isotopes - 1` heavier isotopes.
+    pub fn precursor_isotope_mzs(&self, charge: u32, n_isotopes: usize) -> Result<Vec<f64>, Box<dyn Error>> {
+        let mono = self.precursor_mz(charge)?;
+        Ok((0..n_isotopes)
+            .map(|k| mono + k as f64 * NEUTRON_SPACING / charge as f64)
+            .collect())
+    }
+}
+
+/// Numeric `FragmentType` code written by `process_library_fast` for an ion
+/// series. b/y/p keep their original codes 1/2/3; internal ions (`int`, `m`) are 8.
+pub fn fragment_type_code(series: &str) -> Option<&'static str> {
+    match series {
+        "b" => Some("1"),
+        "y" => Some("2"),
+        "p" => Some("3"),
+        "a" => Some("4"),
+        "c" => Some("5"),
+        "x" => Some("6"),
+        "z" => Some("7"),
+        "int" | "Int" | "internal" | "m" => Some("8"),
+        _ => None,
+    }
+}
+
+pub const INTERNAL_FRAGMENT_CODE: &str = "8";
+
+/// Series name of a numeric `FragmentType` code (inverse of
+/// `fragment_type_code`); anything else is returned unchanged.
+pub fn fragment_series_name(fragment_type: &str) -> &str {
+    match fragment_type {
+        "1" => "b",
+        "2" => "y",
+        "3" => "p",
+        "4" => "a",
+        "5" => "c",
+        "6" => "x",
+        "7" => "z",
+        "8" => "int",
+        other => other,
+    }
+}
+
+/// Ion series of a library `FragmentType` value, either a numeric code from
+/// `fragment_type_code` or the raw series letter.
+pub fn fragment_ion_type(fragment_type: &str) -> Option<IonType> {
+    match fragment_type {
+        "1" => Some(IonType::B),
+        "2" => Some(IonType::Y),
+        "4" => Some(IonType::A),
+        "5" => Some(IonType::C),
+        "6" => Some(IonType::X),
+        "7" => Some(IonType::Z),
+        other => other.chars().next().and_then(IonType::from_char),
+    }
+}
+
+/// A `FragmentType` annotation split into its parts, e.g. `y5^2-H2O` gives
+/// series `y`, number `5` and loss `H2O`. Plain series letters leave
+/// number and loss empty.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FragmentAnnotation {
+    pub series: String,
+    pub number: Option<String>,
+    pub loss: Option<String>,
+}
+
+pub fn parse_fragment_annotation(raw: &str) -> FragmentAnnotation {
+    let raw = raw.trim();
+    let (ion, loss) = match raw.split_once('-') {
+        Some((ion, loss)) => (ion, Some(loss.trim())),
+        None => (raw, None),
+    };
+    // charge suffix (y5^2) is carried by FragmentCharge
+    let ion = ion.split('^').next().unwrap_or(ion);
+
+    let split = ion.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(ion.len());
+    let (series, rest) = ion.split_at(split);
+    let number = (!rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit())).then(|| rest.to_string());
+
+    let loss = loss
+        .filter(|l| !l.is_empty())
+        .map(|l| match NeutralLoss::parse(l) {
+            Some(parsed) => format!("{:?}", parsed),
+            None => l.to_string(),
+        });
+
+    FragmentAnnotation { series: series.to_string(), number, loss }
+}
+
+/// Neutral loss of a library fragment; `noloss` and empty mean none.
+pub fn fragment_neutral_loss(loss_type: &str) -> Option<NeutralLoss> {
+    match loss_type {
+        "" | "noloss" => None,
+        other => NeutralLoss::parse(other),
+    }
+}
+
+pub fn ion_type_name(ion_type: IonType) -> char {
+    match ion_type {
+        IonType::A => 'a',
+        IonType::B => 'b',
+        IonType::C => 'c',
+        IonType::X => 'x',
+        IonType::Y => 'y',
+        IonType::Z => 'z',
+    }
+}
+
+#[inline]
+pub fn mz_from_mass(neutral_mass: f64, charge: u32) -> f64 {
+    (neutral_mass + charge as f64 * PROTON_MASS) / charge as f64
+}
+
+// ============================================================================
+// 同位素分布 (isotope envelopes)
+// ============================================================================
+
+/// Elemental composition (C, H, N, O, S, P).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Composition {
+    pub c: i32,
+    pub h: i32,
+    pub n: i32,
+    pub o: i32,
+    pub s: i32,
+    pub p: i32,
+}
+
+impl Composition {
+    const fn new(c: i32, h: i32, n: i32, o: i32, s: i32, p: i32) -> Self {
+        Self { c, h, n, o, s, p }
+    }
+
+    fn add(&mut self, other: Composition) {
+        self.c += other.c;
+        self.h += other.h;
+        self.n += other.n;
+        self.o += other.o;
+        self.s += other.s;
+        self.p += other.p;
+    }
+
+    /// Averagine composition (Senko et al.) scaled to `mass`.
+    pub fn averagine(mass: f64) -> Self {
+        let units = (mass / 111.1254).max(0.0);
+        Self {
+            c: (4.9384 * units).round() as i32,
+            h: (7.7583 * units).round() as i32,
+            n: (1.3577 * units).round() as i32,
+            o: (1.4773 * units).round() as i32,
+            s: (0.0417 * units).round() as i32,
+            p: 0,
+        }
+    }
+}
+
+fn residue_composition(aa: char) -> Option<Composition> {
+    let comp = match aa {
+        'G' => Composition::new(2, 3, 1, 1, 0, 0),
+        'A' => Composition::new(3, 5, 1, 1, 0, 0),
+        'S' => Composition::new(3, 5, 1, 2, 0, 0),
+        'P' => Composition::new(5, 7, 1, 1, 0, 0),
+        'V' => Composition::new(5, 9, 1, 1, 0, 0),
+        'T' => Composition::new(4, 7, 1, 2, 0, 0),
+        'C' => Composition::new(3, 5, 1, 1, 1, 0),
+        'L' | 'I' => Composition::new(6, 11, 1, 1, 0, 0),
+        'N' => Composition::new(4, 6, 2, 2, 0, 0),
+        'D' => Composition::new(4, 5, 1, 3, 0, 0),
+        'Q' => Composition::new(5, 8, 2, 2, 0, 0),
+        'K' => Composition::new(6, 12, 2, 1, 0, 0),
+        'E' => Composition::new(5, 7, 1, 3, 0, 0),
+        'M' => Composition::new(5, 9, 1, 1, 1, 0),
+        'H' => Composition::new(6, 7, 3, 1, 0, 0),
+        'F' => Composition::new(9, 9, 1, 1, 0, 0),
+        'R' => Composition::new(6, 12, 4, 1, 0, 0),
+        'Y' => Composition::new(9, 9, 1, 2, 0, 0),
+        'W' => Composition::new(11, 10, 2, 1, 0, 0),
+        _ => return None,
+    };
+    Some(comp)
+}
+
+/// Composition delta of a UniMod modification; unknown entries fall back to
+/// averagine for their mass delta.
+fn unimod_composition(unimod_id: u32) -> Option<Composition> {
+    let comp = match unimod_id {
+        1 => Composition::new(2, 2, 0, 1, 0, 0),
+        4 => Composition::new(2, 3, 1, 1, 0, 0),
+        5 => Composition::new(1, 1, 1, 1, 0, 0),
+        7 => Composition::new(0, -1, -1, 1, 0, 0),
+        21 => Composition::new(0, 1, 0, 3, 0, 1),
+        27 => Composition::new(0, -2, 0, -1, 0, 0),
+        28 => Composition::new(0, -3, -1, 0, 0, 0),
+        34 => Composition::new(1, 2, 0, 0, 0, 0),
+        35 => Composition::new(0, 0, 0, 1, 0, 0),
+        36 => Composition::new(2, 4, 0, 0, 0, 0),
+        37 => Composition::new(3, 6, 0, 0, 0, 0),
+        121 => Composition::new(4, 6, 2, 2, 0, 0),
+        _ => return None,
+    };
+    Some(comp)
+}
+
+impl ModifiedPeptide {
+    /// Elemental composition of the neutral peptide.
+    pub fn composition(&self) -> Result<Composition, Box<dyn Error>> {
+        let mut total = Composition::new(0, 2, 0, 1, 0, 0); // H2O
+        let mods = self.nterm_mods.iter().chain(self.residues.iter().flat_map(|r| r.mods.iter()));
+        for residue in &self.residues {
+            total.add(residue_composition(residue.aa).ok_or_else(|| format!("unknown residue '{}'", residue.aa))?);
+        }
+        for &id in mods {
+            match unimod_composition(id) {
+                Some(comp) => total.add(comp),
+                None => total.add(Composition::averagine(
+                    unimod_mass(id).ok_or_else(|| format!("unknown UniMod:{}", id))?,
+                )),
+            }
+        }
+        Ok(total)
+    }
+}
+
+/// Relative abundances of the natural isotopes, indexed by nominal mass shift.
+fn element_isotopes(element: char) -> &'static [f64] {
+    match element {
+        'C' => &[0.9893, 0.0107],
+        'H' => &[0.999_885, 0.000_115],
+        'N' => &[0.996_36, 0.003_64],
+        'O' => &[0.997_57, 0.000_38, 0.002_05],
+        'S' => &[0.9499, 0.0075, 0.0425, 0.0, 0.0001],
+        _ => &[1.0],
+    }
+}
+
+fn convolve(a: &[f64], b: &[f64], n_peaks: usize) -> Vec<f64> {
+    let mut out = vec![0.0; (a.len() + b.len() - 1).min(n_peaks)];
+    for (i, &x) in a.iter().enumerate() {
+        for (j, &y) in b.iter().enumerate() {
+            if i + j < out.len() {
+                out[i + j] += x * y;
+            }
+        }
+    }
+    out
+}
+
+/// `dist` convolved with itself `count` times (exponentiation by squaring).
+fn convolve_power(dist: &[f64], count: i32, n_peaks: usize) -> Vec<f64> {
+    let mut result = vec![1.0];
+    let mut base = dist.to_vec();
+    let mut count = count.max(0) as u32;
+    while count > 0 {
+        if count & 1 == 1 {
+            result = convolve(&result, &base, n_peaks);
+        }
+        base = convolve(&base, &base, n_peaks);
+        count >>= 1;
+    }
+    result
+}
+
+/// Isotope envelope (M, M+1, ...) normalised to the most abundant peak.
+pub fn isotope_distribution(composition: &Composition, n_peaks: usize) -> Vec<f64> {
+    let mut dist = vec![1.0];
+    for (element, count) in [
+        ('C', composition.c),
+        ('H', composition.h),
+        ('N', composition.n),
+        ('O', composition.o),
+        ('S', composition.s),
+    ] {
+        dist = convolve(&dist, &convolve_power(element_isotopes(element), count, n_peaks), n_peaks);
+    }
+    dist.resize(n_peaks, 0.0);
+
+    let max = dist.iter().cloned().fold(0.0, f64::max);
+    if max > 0.0 {
+        dist.iter_mut().for_each(|v| *v /= max);
+    }
+    dist
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!((actual - expected).abs() < tolerance, "{} vs {}", actual, expected);
+    }
+
+    #[test]
+    fn peptide_mass_and_precursor_mz() {
+        let peptide = ModifiedPeptide::parse("_PEPTIDE_").unwrap();
+        assert_close(peptide.monoisotopic_mass().unwrap(), 799.3600, 1e-4);
+        assert_close(peptide.precursor_mz(2).unwrap(), 400.6873, 1e-4);
+        let isotopes = peptide.precursor_isotope_mzs(2, 3).unwrap();
+        assert_close(isotopes[2] - isotopes[0], NEUTRON_SPACING, 1e-9);
+    }
+
+    #[test]
+    fn ion_series_of_an_unmodified_peptide() {
+        let peptide = ModifiedPeptide::parse("PEPTIDE").unwrap();
+        for (ion_type, number, expected) in [
+            (IonType::B, 2, 227.1026),
+            (IonType::A, 2, 199.1077),
+            (IonType::C, 2, 244.1292),
+            (IonType::Y, 3, 376.1714),
+            (IonType::X, 2, 289.0666),
+            (IonType::Z, 2, 247.0687),
+        ] {
+            assert_close(peptide.fragment_mz(ion_type, number, 1, None).unwrap(), expected, 1e-3);
+        }
+        assert!(peptide.fragment_mz(IonType::Y, 7, 1, None).is_err());
+        assert!(peptide.fragment_mz(IonType::B, 2, 0, None).is_err());
+    }
+
+    #[test]
+    fn modifications_and_neutral_losses_shift_fragments() {
+        let peptide = ModifiedPeptide::parse("_AC(UniMod:4)DEM(UniMod:35)K_").unwrap();
+        assert_eq!(peptide.to_unimod_string(), "AC(UniMod:4)DEM(UniMod:35)K");
+        assert_close(peptide.monoisotopic_mass().unwrap(), 768.2782, 1e-3);
+        assert_close(peptide.fragment_mz(IonType::B, 2, 1, None).unwrap(), 232.0750, 1e-3);
+        assert_close(peptide.fragment_mz(IonType::Y, 2, 1, None).unwrap(), 294.1482, 1e-3);
+        assert_close(peptide.fragment_mz(IonType::Y, 2, 1, Some(NeutralLoss::H2O)).unwrap(), 276.1376, 1e-3);
+        assert_close(peptide.fragment_mz(IonType::Y, 2, 2, None).unwrap(), 147.5777, 1e-3);
+
+        assert!(ModifiedPeptide::parse("PEPT(UniMod:9999)IDE").unwrap().monoisotopic_mass().is_err());
+        assert!(ModifiedPeptide::parse("PEPT(Phospho)IDE").is_err());
+    }
+
+    #[test]
+    fn fragment_type_codes_round_trip() {
+        for series in ["b", "y", "p", "a", "c", "x", "z", "int"] {
+            let code = fragment_type_code(series).unwrap();
+            assert_eq!(fragment_series_name(code), series);
+            assert_eq!(fragment_ion_type(code), series.chars().next().and_then(IonType::from_char).filter(|_| series != "int"));
+        }
+        assert_eq!(fragment_type_code("w"), None);
+        assert_eq!(fragment_series_name("y"), "y");
+    }
+
+    #[test]
+    fn isotope_distribution_of_a_known_composition() {
+        // PEPTIDE, C34H53N7O15
+        let composition = ModifiedPeptide::parse("PEPTIDE").unwrap().composition().unwrap();
+        assert_eq!(composition, Composition::new(34, 53, 7, 15, 0, 0));
+
+        let wide = isotope_distribution(&composition, 12);
+        let total: f64 = wide.iter().sum();
+        let probabilities: Vec<f64> = wide.iter().map(|v| v / total).collect();
+        for (p, expected) in probabilities.iter().zip([0.647_992, 0.262_513, 0.071_829, 0.014_740, 0.002_507, 0.000_366]) {
+            assert_close(*p, expected, 1e-5);
+        }
+        // The six MS1 slots hold essentially the whole envelope
+        assert!(probabilities[..6].iter().sum::<f64>() > 0.9999);
+
+        let envelope = isotope_distribution(&composition, 6);
+        assert_eq!(envelope[0], 1.0);
+        assert_close(envelope[1], 0.405_117, 1e-5);
+    }
+
+    #[test]
+    fn fragment_annotations_split_series_number_and_loss() {
+        let annotation = |series: &str, number: Option<&str>, loss: Option<&str>| FragmentAnnotation {
+            series: series.to_string(),
+            number: number.map(str::to_string),
+            loss: loss.map(str::to_string),
+        };
+        assert_eq!(parse_fragment_annotation("y5^2-H2O"), annotation("y", Some("5"), Some("H2O")));
+        assert_eq!(parse_fragment_annotation(" b3-NH3 "), annotation("b", Some("3"), Some("NH3")));
+        assert_eq!(parse_fragment_annotation("y7-18"), annotation("y", Some("7"), Some("H2O")));
+        assert_eq!(parse_fragment_annotation("z4-CH4S"), annotation("z", Some("4"), Some("CH4S")));
+        assert_eq!(parse_fragment_annotation("y"), annotation("y", None, None));
+        assert_eq!(fragment_neutral_loss("noloss"), None);
+        assert_eq!(fragment_neutral_loss("NH3"), Some(NeutralLoss::NH3));
+    }
+}
@@ -0,0 +1,699 @@
+// File: src/config.rs
+use serde::Deserialize;
+use std::{collections::HashMap, error::Error, fs, path::Path};
+
+/// Run configuration, loaded from `config.toml` in the working directory.
+/// Every section and field is optional; missing values fall back to the
+/// defaults below, which reproduce the original hard-coded settings.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub processing: ProcessingConfig,
+    pub extraction: ExtractionConfig,
+    pub library: LibraryConfig,
+    pub library_only: LibraryOnlyConfig,
+    pub assay_list: AssayListConfig,
+    pub selection: SelectionConfig,
+    pub candidates: CandidateConfig,
+    pub resampling: ResamplingConfig,
+    pub rt_window: RtWindowConfig,
+    pub peaks: PeakConfig,
+    pub quant: QuantConfig,
+    pub features: FeatureConfig,
+    pub rescoring: RescoringConfig,
+    pub mass_calibration: MassCalibrationConfig,
+    pub im_calibration: ImCalibrationConfig,
+    pub mobilogram: MobilogramConfig,
+    pub spectra: SpectrumConfig,
+    pub empirical_library: EmpiricalLibraryConfig,
+    pub interference: InterferenceConfig,
+    pub training: TrainingConfig,
+    pub inference: InferenceConfig,
+    pub decoys: DecoyConfig,
+    pub validation: ValidationConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ProcessingConfig {
+    pub parallel_threads: usize, // 1 for sequential, 2+ for parallel processing
+    pub max_precursors: usize,
+    pub output_dir: String,
+    pub save_dataframes: bool,        // write one CSV per precursor (or candidate) to output_dir
+    pub repeat_reduction: String,     // `sum`, `max`, `centre` or `full` over the frag_repeat_num sub-windows
+    pub metadata_file: String,        // run settings written to output_dir
+    pub library_path: Option<String>, // overrides the OS-specific default path
+    pub report_path: Option<String>,  // overrides the OS-specific default path
+}
+
+impl Default for ProcessingConfig {
+    fn default() -> Self {
+        Self {
+            parallel_threads: 16,
+            max_precursors: 8000,
+            output_dir: "output_precursors".to_string(),
+            save_dataframes: false,
+            repeat_reduction: "sum".to_string(),
+            metadata_file: "run_metadata.json".to_string(),
+            library_path: None,
+            report_path: None,
+        }
+    }
+}
+
+/// Which report precursors are extracted (report mode; library-only mode
+/// extracts every precursor with an iRT). `strategy` is one of `all`,
+/// `first`, `random`, `stratified`, `top_quantity` or `id_list`; all but
+/// `all` and `id_list` take `processing.max_precursors` precursors.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SelectionConfig {
+    pub strategy: String,
+    pub seed: u64,
+    pub stratify_by: Vec<String>, // `rt`, `mz` (quantile bins) and/or `charge`
+    pub n_bins: usize,            // quantile bins per continuous dimension
+    pub quantity_column: String,  // report column ranked by `top_quantity`
+    pub id_list_file: Option<String>,
+}
+
+impl Default for SelectionConfig {
+    fn default() -> Self {
+        Self {
+            strategy: "first".to_string(),
+            seed: 42,
+            stratify_by: vec!["rt".to_string(), "mz".to_string(), "charge".to_string()],
+            n_bins: 4,
+            quantity_column: "Precursor.Quantity".to_string(),
+            id_list_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ExtractionConfig {
+    pub frag_repeat_num: usize,
+    pub mz_unit: String,
+    pub ms1_tolerance: f32,
+    pub ms2_tolerance: f32,
+    pub im_tolerance: f32,
+    pub im_tolerance_mode: String,        // `fixed`, `relative` (fraction of 1/K0) or `charge`
+    pub im_tolerance_relative: f32,
+    pub im_tolerance_by_charge: HashMap<String, f32>, // charge -> tolerance, `im_tolerance` otherwise
+    pub rt_axis: String, // `frame` (every MS1/MS2 frame RT) or `cycle` (one column per DIA cycle)
+}
+
+impl Default for ExtractionConfig {
+    fn default() -> Self {
+        Self {
+            frag_repeat_num: 5,
+            mz_unit: "ppm".to_string(),
+            ms1_tolerance: 20.0,
+            ms2_tolerance: 50.0,
+            im_tolerance: 0.05,
+            im_tolerance_mode: "fixed".to_string(),
+            im_tolerance_relative: 0.05,
+            im_tolerance_by_charge: HashMap::new(),
+            rt_axis: "frame".to_string(),
+        }
+    }
+}
+
+/// How library entries are turned into MS1/MS2 extraction rows.
+/// `isotope_model`: `none` (legacy 1/z spacing, no abundances), `averagine`
+/// or `composition` (from the modified sequence, averagine as fallback).
+/// Fragment rules are applied per precursor before the fragment slots are
+/// filled; the defaults keep every fragment in library order.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LibraryConfig {
+    pub isotope_model: String,
+    pub min_isotope_abundance: f32, // relative to the most abundant isotope
+    pub max_fragments: usize,       // top-N, capped at the 20 fragment slots
+    pub rank_by_intensity: bool,    // pick top-N by LibraryIntensity instead of library order
+    pub min_fragment_mz: f32,
+    pub min_fragment_number: usize, // e.g. 3 drops b1/b2/y1/y2
+    pub isolation_exclusion: f32,   // Da around the precursor m/z; 0 disables
+    pub include_neutral_losses: bool,
+    pub include_internal_ions: bool,
+}
+
+impl Default for LibraryConfig {
+    fn default() -> Self {
+        Self {
+            isotope_model: "none".to_string(),
+            min_isotope_abundance: 0.01,
+            max_fragments: 20,
+            rank_by_intensity: false,
+            min_fragment_mz: 0.0,
+            min_fragment_number: 0,
+            isolation_exclusion: 0.0,
+            include_neutral_losses: true,
+            include_internal_ions: true,
+        }
+    }
+}
+
+/// Standalone mode: RT/IM come from the library instead of a DIA-NN report.
+/// A first pass extracts `anchor_candidates` precursors over the whole
+/// gradient, the best-scoring ones calibrate library iRT onto run RT, and
+/// every library precursor is then extracted at its calibrated RT.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LibraryOnlyConfig {
+    pub enabled: bool,
+    pub anchor_candidates: usize,
+    pub anchor_fraction: f32,  // top fraction of candidates (by score) kept as anchors
+    pub min_anchors: usize,
+    pub min_fragments: usize,  // co-detected fragments required at the anchor apex
+    pub calibration_bins: usize,
+}
+
+impl Default for LibraryOnlyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            anchor_candidates: 2000,
+            anchor_fraction: 0.2,
+            min_anchors: 20,
+            min_fragments: 4,
+            calibration_bins: 20,
+        }
+    }
+}
+
+/// Targeted mode: extract the targets of a small assay list (see
+/// `read_assay_list`) at their listed RT/IM, without library or report.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AssayListConfig {
+    pub enabled: bool,
+    pub path: String,
+}
+
+impl Default for AssayListConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "assays.tsv".to_string(),
+        }
+    }
+}
+
+/// Discovery mode: extract each precursor over `rt_tolerance` minutes (or the
+/// whole gradient when 0) and emit the `top_k` best sliding windows instead of
+/// a single window centred on the report RT.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CandidateConfig {
+    pub enabled: bool,
+    pub rt_tolerance: f32,
+    pub window_length: usize, // RT points per candidate
+    pub stride: usize,        // RT points between window starts
+    pub top_k: usize,
+}
+
+impl Default for CandidateConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rt_tolerance: 0.0,
+            window_length: 48,
+            stride: 8,
+            top_k: 5,
+        }
+    }
+}
+
+/// Optional resampling of every trace onto `n_points` RTs spaced `spacing`
+/// apart (`unit`: `minutes` or `seconds`) around the target RT, or around the
+/// apex in candidate mode. `kernel` is `nearest`, `linear` or `gaussian`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ResamplingConfig {
+    pub enabled: bool,
+    pub n_points: usize,
+    pub spacing: f32,
+    pub unit: String,
+    pub kernel: String,
+}
+
+impl Default for ResamplingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            n_points: 48,
+            spacing: 0.05,
+            unit: "minutes".to_string(),
+            kernel: "linear".to_string(),
+        }
+    }
+}
+
+/// Optional per-precursor RT window (report mode, outside candidate mode):
+/// the reported peak width (`stop_column` - `start_column`) times
+/// `multiplier`, clamped to `[min_width, max_width]` minutes and centred on
+/// the report RT. `fit` brings the window to `n_points` RTs: `resample`
+/// (`kernel` as in `[resampling]`) or `pad` (frames cropped or zero-padded
+/// like `get_rt_list`; a window spanning more than `n_points` frames is cut
+/// back to the frames nearest the RT). Precursors without boundaries keep
+/// the default window.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RtWindowConfig {
+    pub enabled: bool,
+    pub multiplier: f32,
+    pub min_width: f32,
+    pub max_width: f32,
+    pub n_points: usize,
+    pub fit: String,
+    pub kernel: String,
+    pub start_column: String,
+    pub stop_column: String,
+}
+
+impl Default for RtWindowConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            multiplier: 3.0,
+            min_width: 0.5,
+            max_width: 3.0,
+            n_points: 48,
+            fit: "resample".to_string(),
+            kernel: "linear".to_string(),
+            start_column: "RT.Start".to_string(),
+            stop_column: "RT.Stop".to_string(),
+        }
+    }
+}
+
+/// Peak picking on the summed fragment trace. `smoothing` is `none`,
+/// `savitzky_golay` (window in points) or `gaussian` (sigma in points).
+/// Boundaries stop where the smoothed trace falls below `boundary_fraction`
+/// of the apex or starts rising again.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PeakConfig {
+    pub enabled: bool,
+    pub smoothing: String,
+    pub smoothing_window: usize,
+    pub gaussian_sigma: f32,
+    pub min_apex_fraction: f32, // local maxima below this fraction of the highest point are ignored
+    pub boundary_fraction: f32,
+    pub output_file: String,    // written to output_dir
+}
+
+impl Default for PeakConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            smoothing: "savitzky_golay".to_string(),
+            smoothing_window: 5,
+            gaussian_sigma: 1.0,
+            min_apex_fraction: 0.1,
+            boundary_fraction: 0.05,
+            output_file: "peaks.tsv".to_string(),
+        }
+    }
+}
+
+/// Fragment areas between the picked peak boundaries (`integration`:
+/// `trapezoid` or `sum`, `background`: `none`, `min` or `linear`) and the
+/// precursor quantity from them (`method`: `top_n`, `all` or `robust`).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct QuantConfig {
+    pub enabled: bool,
+    pub integration: String,
+    pub background: String,
+    pub method: String,
+    pub top_n: usize,
+    pub min_correlation: f32, // `robust`: fragments below this shape correlation are skipped
+    pub output_file: String,  // precursor table, written to output_dir
+    pub fragment_output_file: String,
+}
+
+impl Default for QuantConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            integration: "trapezoid".to_string(),
+            background: "none".to_string(),
+            method: "top_n".to_string(),
+            top_n: 3,
+            min_correlation: 0.7,
+            output_file: "quant.tsv".to_string(),
+            fragment_output_file: "fragment_quant.tsv".to_string(),
+        }
+    }
+}
+
+/// OpenSWATH-style subscores per peak group, written as a feature table
+/// for rescoring.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FeatureConfig {
+    pub enabled: bool,
+    pub output_file: String, // written to output_dir
+}
+
+impl Default for FeatureConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            output_file: "features.tsv".to_string(),
+        }
+    }
+}
+
+/// Semi-supervised target-decoy rescoring of the peak-group subscores
+/// (`classifier`: `lda` or `svm`). Training starts from `initial_score`
+/// (a feature table column), keeps targets below `initial_fdr` (then
+/// `iteration_fdr`) as positives and runs `folds`-fold cross-validation.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RescoringConfig {
+    pub enabled: bool,
+    pub classifier: String,
+    pub initial_score: String,
+    pub folds: usize,
+    pub iterations: usize,
+    pub initial_fdr: f64,
+    pub iteration_fdr: f64,
+    pub pi0_lambda: f64,
+    pub svm_lambda: f64,
+    pub seed: u64,
+    pub output_file: String, // written to output_dir
+}
+
+impl Default for RescoringConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            classifier: "lda".to_string(),
+            initial_score: "library_dotprod".to_string(),
+            folds: 3,
+            iterations: 3,
+            initial_fdr: 0.15,
+            iteration_fdr: 0.05,
+            pi0_lambda: 0.4,
+            svm_lambda: 0.01,
+            seed: 42,
+            output_file: "rescored.tsv".to_string(),
+        }
+    }
+}
+
+/// Fragment mass errors and two-pass m/z recalibration. With `recalibrate`,
+/// the first `anchor_precursors` targets are extracted, the best
+/// `anchor_fraction` of their peak groups (by library dot product) serve as
+/// anchors for an m/z error model over RT and m/z, the index is corrected in
+/// place and the ppm tolerances are tightened to `tolerance_sd_multiplier`
+/// residual SDs (never below `min_tolerance_ppm`, never wider than before).
+/// An MS level with fewer than `min_anchors` matched peaks keeps its
+/// tolerance and is not corrected.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MassCalibrationConfig {
+    pub report_fragment_errors: bool,
+    pub output_file: String, // written to output_dir
+    pub recalibrate: bool,
+    pub anchor_precursors: usize,
+    pub anchor_fraction: f32,
+    pub min_anchors: usize,
+    pub min_fragments: usize,
+    pub tolerance_sd_multiplier: f32,
+    pub min_tolerance_ppm: f32,
+}
+
+impl Default for MassCalibrationConfig {
+    fn default() -> Self {
+        Self {
+            report_fragment_errors: false,
+            output_file: "mass_errors.tsv".to_string(),
+            recalibrate: false,
+            anchor_precursors: 2000,
+            anchor_fraction: 0.3,
+            min_anchors: 20,
+            min_fragments: 4,
+            tolerance_sd_multiplier: 3.0,
+            min_tolerance_ppm: 5.0,
+        }
+    }
+}
+
+/// Run-level ion mobility calibration. The best `anchor_fraction` of the
+/// first `anchor_precursors` targets (extracted with `anchor_im_tolerance`)
+/// give the observed IM; `model` is `offset` (median shift) or `linear`.
+/// With `adaptive_tolerance` the IM window per charge state becomes
+/// `width_multiplier` x the observed IM peak width.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ImCalibrationConfig {
+    pub enabled: bool,
+    pub model: String,
+    pub anchor_precursors: usize,
+    pub anchor_fraction: f32,
+    pub min_anchors: usize,
+    pub min_fragments: usize,
+    pub anchor_im_tolerance: f32,
+    pub adaptive_tolerance: bool,
+    pub width_multiplier: f32,
+    pub min_im_tolerance: f32,
+    pub output_file: String, // anchor table, written to output_dir
+}
+
+impl Default for ImCalibrationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            model: "offset".to_string(),
+            anchor_precursors: 2000,
+            anchor_fraction: 0.3,
+            min_anchors: 20,
+            min_fragments: 4,
+            anchor_im_tolerance: 0.1,
+            adaptive_tolerance: false,
+            width_multiplier: 3.0,
+            min_im_tolerance: 0.01,
+            output_file: "im_calibration.tsv".to_string(),
+        }
+    }
+}
+
+/// Fragment x IM-bin mobilograms (binned on scan indices) at the apex,
+/// summed over `apex_half_width` RT points on each side; `full_tensor` also
+/// keeps the fragment x RT x IM tensor. Written with the per-precursor
+/// dataframes (`save_dataframes`).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MobilogramConfig {
+    pub enabled: bool,
+    pub n_bins: usize,
+    pub apex_half_width: usize,
+    pub full_tensor: bool,
+}
+
+impl Default for MobilogramConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            n_bins: 16,
+            apex_half_width: 1,
+            full_tensor: false,
+        }
+    }
+}
+
+/// Full MS2 spectrum of each precursor's isolation window at the apex,
+/// summed over `frames_each_side` MS2 frames around it and over the
+/// precursor IM range, centroided and annotated with library fragments.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SpectrumConfig {
+    pub enabled: bool,
+    pub frames_each_side: usize,
+    pub merge_tolerance_ppm: f32,
+    pub min_intensity: f32,
+    pub output_file: String, // written to output_dir
+}
+
+impl Default for SpectrumConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            frames_each_side: 2,
+            merge_tolerance_ppm: 10.0,
+            min_intensity: 0.0,
+            output_file: "apex_spectra.mgf".to_string(),
+        }
+    }
+}
+
+/// Empirical library from confident target peak groups (q-value cut when
+/// rescoring is enabled, else `min_dotprod`): apex fragment intensities,
+/// run RT and measured IM. `library consensus` merges several run libraries,
+/// keeping entries seen in at least `min_run_fraction` of the runs.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct EmpiricalLibraryConfig {
+    pub enabled: bool,
+    pub q_value: f64,
+    pub min_dotprod: f32,
+    pub min_fragments: usize,
+    pub min_run_fraction: f32,
+    pub output_file: String, // written to output_dir
+}
+
+impl Default for EmpiricalLibraryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            q_value: 0.01,
+            min_dotprod: 0.8,
+            min_fragments: 4,
+            min_run_fraction: 0.5,
+            output_file: "empirical_library.tsv".to_string(),
+        }
+    }
+}
+
+/// Shared-transition detection between precursors routed to the same MS2
+/// window: fragments within the MS2 tolerance of another precursor's
+/// fragment whose RT (`rt_window`, minutes) and IM (`im_window`) overlap.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct InterferenceConfig {
+    pub enabled: bool,
+    pub rt_window: f32,
+    pub im_window: f32,
+    pub output_file: String, // written to output_dir
+}
+
+impl Default for InterferenceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rt_window: 1.0,
+            im_window: 0.05,
+            output_file: "interference.tsv".to_string(),
+        }
+    }
+}
+
+/// Labelled tensor export for DIA-BERT training: report targets below
+/// `q_value` are positives, extracted decoys and `rt_shift_negatives`
+/// windows moved by multiples of `rt_shift` (minutes) per positive are
+/// negatives. Splits are assigned per peptide sequence.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TrainingConfig {
+    pub enabled: bool,
+    pub q_value: f32,
+    pub q_value_column: String, // report column holding the precursor q-value
+    pub rt_shift_negatives: usize,
+    pub rt_shift: f32,
+    pub validation_fraction: f32,
+    pub test_fraction: f32,
+    pub seed: u64,
+    pub output_dir: String, // subdirectory of processing.output_dir
+}
+
+impl Default for TrainingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            q_value: 0.01,
+            q_value_column: "Q.Value".to_string(),
+            rt_shift_negatives: 0,
+            rt_shift: 2.0,
+            validation_fraction: 0.1,
+            test_fraction: 0.1,
+            seed: 42,
+            output_dir: "training".to_string(),
+        }
+    }
+}
+
+/// In-process scoring of every peak group with an exported ONNX model
+/// (inputs `rsm` and `precursor_feat`, one score per batch row). Needs a
+/// build with the `inference` feature.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct InferenceConfig {
+    pub enabled: bool,
+    pub model_path: String,
+    pub batch_size: usize,
+    pub output_file: String, // written to output_dir
+}
+
+impl Default for InferenceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            model_path: "models/toy_scorer.onnx".to_string(),
+            batch_size: 256,
+            output_file: "model_scores.tsv".to_string(),
+        }
+    }
+}
+
+/// Decoy generation for libraries without decoys. `method` is one of
+/// `pseudo_reverse`, `shuffle` or `mutate`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DecoyConfig {
+    pub generate: bool,
+    pub method: String,
+    pub seed: u64,
+    pub only_if_missing: bool,
+    pub extract: bool,   // extract library decoys at the RT/IM of their paired target
+    pub pairing: String, // `auto`, `column` (PairedPrecursorId) or `sequence`
+}
+
+impl Default for DecoyConfig {
+    fn default() -> Self {
+        Self {
+            generate: false,
+            method: "pseudo_reverse".to_string(),
+            seed: 42,
+            only_if_missing: true,
+            extract: false,
+            pairing: "auto".to_string(),
+        }
+    }
+}
+
+/// `library validate`: entries deviating more than `ppm_threshold` from the
+/// recomputed m/z are written to `output_path`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ValidationConfig {
+    pub ppm_threshold: f64,
+    pub output_path: String,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            ppm_threshold: 10.0,
+            output_path: "library_validation.tsv".to_string(),
+        }
+    }
+}
+
+pub fn load_config() -> Result<Config, Box<dyn Error>> {
+    // Try to load config.toml from current directory
+    let config_path = "config.toml";
+
+    if Path::new(config_path).exists() {
+        let config_str = fs::read_to_string(config_path)?;
+        let config: Config = toml::from_str(&config_str)?;
+        println!("Loaded configuration from {}", config_path);
+        Ok(config)
+    } else {
+        println!("No config.toml found, using default configuration");
+        Ok(Config::default())
+    }
+}
@@ -0,0 +1,166 @@
+// File: src/cycles.rs
+use rayon::prelude::*;
+
+use crate::utils::IndexedTimsTOFData;
+
+const NO_CYCLE: u32 = u32::MAX;
+
+/// DIA cycle model: every MS1 frame opens a new cycle and the MS2 frames that
+/// follow it belong to the same cycle. MS2 frames acquired before the first
+/// MS1 frame are assigned to cycle 0.
+#[derive(Debug, Clone)]
+pub struct CycleModel {
+    frame_to_cycle: Vec<u32>, // indexed by frame index, NO_CYCLE for unseen frames
+    cycle_rt: Vec<f32>,       // apex time (midpoint of first and last frame) per cycle, minutes
+}
+
+impl CycleModel {
+    /// Build the model from a frame table of `(frame_index, rt_min, is_ms1)`.
+    pub fn from_frames(frames: &[(u32, f32, bool)]) -> Self {
+        let mut frames = frames.to_vec();
+        frames.sort_by_key(|f| f.0);
+
+        let max_frame = frames.last().map(|f| f.0 as usize + 1).unwrap_or(0);
+        let mut frame_to_cycle = vec![NO_CYCLE; max_frame];
+        let mut cycle_bounds: Vec<(f32, f32)> = Vec::new();
+        let mut seen_ms1 = false;
+
+        for &(frame_idx, rt, is_ms1) in &frames {
+            if cycle_bounds.is_empty() || (is_ms1 && seen_ms1) {
+                cycle_bounds.push((rt, rt));
+            }
+            seen_ms1 |= is_ms1;
+
+            let cycle = cycle_bounds.len() - 1;
+            let bounds = &mut cycle_bounds[cycle];
+            bounds.0 = bounds.0.min(rt);
+            bounds.1 = bounds.1.max(rt);
+            frame_to_cycle[frame_idx as usize] = cycle as u32;
+        }
+
+        let cycle_rt = cycle_bounds.iter().map(|&(first, last)| 0.5 * (first + last)).collect();
+        Self { frame_to_cycle, cycle_rt }
+    }
+
+    /// Reconstruct the frame table from the indexed peaks (so it also works
+    /// when the data comes from the cache) and build the model.
+    pub fn from_indexed(
+        ms1_indexed: &IndexedTimsTOFData,
+        ms2_indexed_pairs: &[((f32, f32), IndexedTimsTOFData)],
+    ) -> Self {
+        let mut frame_rt: Vec<Option<(f32, bool)>> = Vec::new();
+        let mut add = |data: &IndexedTimsTOFData, is_ms1: bool| {
+            for (&frame_idx, &rt) in data.frame_indices.iter().zip(&data.rt_values_min) {
+                let idx = frame_idx as usize;
+                if idx >= frame_rt.len() {
+                    frame_rt.resize(idx + 1, None);
+                }
+                frame_rt[idx].get_or_insert((rt, is_ms1));
+            }
+        };
+
+        add(ms1_indexed, true);
+        for (_, data) in ms2_indexed_pairs {
+            add(data, false);
+        }
+
+        let frames: Vec<(u32, f32, bool)> = frame_rt
+            .into_iter()
+            .enumerate()
+            .filter_map(|(idx, info)| info.map(|(rt, is_ms1)| (idx as u32, rt, is_ms1)))
+            .collect();
+        Self::from_frames(&frames)
+    }
+
+    pub fn n_cycles(&self) -> usize {
+        self.cycle_rt.len()
+    }
+
+    /// Median time between consecutive cycle apexes, in seconds.
+    pub fn median_cycle_time(&self) -> f32 {
+        let mut deltas: Vec<f32> = self.cycle_rt.windows(2).map(|w| (w[1] - w[0]) * 60.0).collect();
+        if deltas.is_empty() {
+            return 0.0;
+        }
+        deltas.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        deltas[deltas.len() / 2]
+    }
+
+    pub fn cycle_of(&self, frame_idx: u32) -> Option<usize> {
+        match self.frame_to_cycle.get(frame_idx as usize) {
+            Some(&cycle) if cycle != NO_CYCLE => Some(cycle as usize),
+            _ => None,
+        }
+    }
+
+    /// Replace every peak's frame RT with the apex RT of its cycle, so MS1 and
+    /// MS2 peaks of one cycle land in the same RT column during extraction.
+    pub fn align(&self, data: &mut IndexedTimsTOFData) {
+        data.rt_values_min
+            .par_iter_mut()
+            .zip(data.frame_indices.par_iter())
+            .for_each(|(rt, &frame_idx)| {
+                if let Some(cycle) = self.cycle_of(frame_idx) {
+                    *rt = self.cycle_rt[cycle];
+                }
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peaks(frames: &[(u32, f32)]) -> IndexedTimsTOFData {
+        IndexedTimsTOFData {
+            rt_values_min: frames.iter().map(|f| f.1).collect(),
+            mobility_values: vec![1.0; frames.len()],
+            mz_values: vec![500.0; frames.len()],
+            intensity_values: vec![100; frames.len()],
+            frame_indices: frames.iter().map(|f| f.0).collect(),
+            scan_indices: vec![0; frames.len()],
+        }
+    }
+
+    // Frame 1 is an MS2 frame before the first MS1 frame; frame 7 was never seen
+    fn model() -> CycleModel {
+        CycleModel::from_frames(&[
+            (5, 1.3, true),
+            (1, 0.9, false),
+            (2, 1.0, true),
+            (3, 1.1, false),
+            (4, 1.2, false),
+            (6, 1.4, false),
+            (8, 1.6, true),
+        ])
+    }
+
+    #[test]
+    fn every_ms1_frame_opens_a_cycle() {
+        let model = model();
+        assert_eq!(model.n_cycles(), 3);
+        let cycles: Vec<Option<usize>> = (0..10).map(|frame| model.cycle_of(frame)).collect();
+        assert_eq!(cycles, [None, Some(0), Some(0), Some(0), Some(0), Some(1), Some(1), None, Some(2), None]);
+        assert!((model.median_cycle_time() - 18.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn align_moves_peaks_to_their_cycle_apex() {
+        let model = model();
+        let mut data = peaks(&[(1, 0.9), (3, 1.1), (6, 1.4), (7, 1.5)]);
+        model.align(&mut data);
+        let expected = [1.05, 1.05, 1.35, 1.5];
+        for (rt, expected) in data.rt_values_min.iter().zip(expected) {
+            assert!((rt - expected).abs() < 1e-6, "{:?}", data.rt_values_min);
+        }
+    }
+
+    #[test]
+    fn model_from_indexed_peaks_matches_the_frame_table() {
+        let ms1 = peaks(&[(2, 1.0), (2, 1.0), (5, 1.3)]);
+        let ms2 = vec![((400.0, 425.0), peaks(&[(3, 1.1), (6, 1.4)])), ((425.0, 450.0), peaks(&[(4, 1.2)]))];
+        let model = CycleModel::from_indexed(&ms1, &ms2);
+        assert_eq!(model.n_cycles(), 2);
+        assert_eq!((model.cycle_of(4), model.cycle_of(6)), (Some(0), Some(1)));
+    }
+}
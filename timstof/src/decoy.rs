@@ -0,0 +1,385 @@
+// File: src/decoy.rs
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use rayon::prelude::*;
+
+use crate::chemistry::{ModifiedPeptide, NeutralLoss, fragment_ion_type, fragment_neutral_loss, fragment_series_name, mz_from_mass};
+use crate::config::DecoyConfig;
+use crate::utils::{LibraryRecord, SeededRng, group_library_records};
+
+pub const DECOY_PREFIX: &str = "DECOY_";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecoyMethod {
+    /// Reverse everything but the C-terminal residue.
+    PseudoReverse,
+    /// Shuffle everything but the C-terminal residue.
+    Shuffle,
+    /// DIA-NN style: mutate the second and second-to-last residues.
+    Mutate,
+}
+
+impl DecoyMethod {
+    pub fn from_name(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name {
+            "pseudo_reverse" | "reverse" => Ok(DecoyMethod::PseudoReverse),
+            "shuffle" => Ok(DecoyMethod::Shuffle),
+            "mutate" => Ok(DecoyMethod::Mutate),
+            _ => Err(format!("Invalid decoy method: {}. Use pseudo_reverse, shuffle or mutate.", name).into()),
+        }
+    }
+}
+
+/// Residue substitution used by DIA-NN's terminal mutation decoys.
+fn mutate_residue(aa: char) -> char {
+    match aa {
+        'G' | 'A' | 'V' | 'F' | 'M' | 'P' | 'W' | 'K' | 'R' => 'L',
+        // I and L are isobaric: both go to V so the decoy mass changes
+        'L' | 'I' => 'V',
+        'S' => 'T',
+        'C' | 'T' | 'Y' | 'H' => 'S',
+        'Q' => 'N',
+        'E' => 'D',
+        'N' => 'Q',
+        'D' => 'E',
+        other => other,
+    }
+}
+
+fn mutate_termini(peptide: &mut ModifiedPeptide) {
+    let n = peptide.residues.len();
+    if n < 3 {
+        return;
+    }
+    for idx in [1, n - 2] {
+        let residue = &mut peptide.residues[idx];
+        residue.aa = mutate_residue(residue.aa);
+    }
+}
+
+/// Build the decoy peptide; modifications travel with their residues.
+/// Falls back to terminal mutation when reversing/shuffling reproduces the target.
+pub fn make_decoy_peptide(target: &ModifiedPeptide, method: DecoyMethod, rng: &mut SeededRng) -> ModifiedPeptide {
+    let mut decoy = target.clone();
+    let n = decoy.residues.len();
+
+    match method {
+        DecoyMethod::PseudoReverse => decoy.residues[..n.saturating_sub(1)].reverse(),
+        DecoyMethod::Shuffle => {
+            for _ in 0..10 {
+                rng.shuffle(&mut decoy.residues[..n.saturating_sub(1)]);
+                if decoy.stripped() != target.stripped() {
+                    break;
+                }
+            }
+        }
+        DecoyMethod::Mutate => mutate_termini(&mut decoy),
+    }
+
+    if method != DecoyMethod::Mutate && decoy.stripped() == target.stripped() {
+        mutate_termini(&mut decoy);
+    }
+    decoy
+}
+
+/// m/z of a target fragment on the decoy sequence; precursor ions follow the
+/// decoy precursor. `None` for fragments that cannot be recomputed (e.g.
+/// internal ions or unparseable numbers).
+fn decoy_fragment_mz(decoy: &ModifiedPeptide, decoy_mass: f64, record: &LibraryRecord) -> Option<f64> {
+    let charge = record.fragment_charge.parse::<u32>().ok().filter(|&c| c > 0)?;
+    let loss = fragment_neutral_loss(&record.fragment_loss_type);
+    if fragment_series_name(&record.fragment_type) == "p" {
+        return Some(mz_from_mass(decoy_mass - loss.map_or(0.0, NeutralLoss::mass), charge));
+    }
+    let ion_type = fragment_ion_type(&record.fragment_type)?;
+    let number = record.fragment_number.parse::<usize>().ok()?;
+    decoy.fragment_mz(ion_type, number, charge, loss).ok()
+}
+
+/// Decoy records for one target precursor, with m/z recomputed from the decoy
+/// sequence and RT/IM copied from the target, plus the number of fragments
+/// that could not be recomputed and were dropped.
+pub fn build_decoy_records(
+    target_records: &[LibraryRecord],
+    method: DecoyMethod,
+    rng: &mut SeededRng,
+) -> Result<(Vec<LibraryRecord>, usize), Box<dyn Error>> {
+    let first = &target_records[0];
+    let target = ModifiedPeptide::parse(&first.full_unimod_peptide_name)?;
+    let decoy = make_decoy_peptide(&target, method, rng);
+
+    let precursor_charge: u32 = first.precursor_charge.parse()?;
+    let decoy_name = decoy.to_unimod_string();
+    let decoy_id = format!("{}{}{}", DECOY_PREFIX, decoy_name, first.precursor_charge);
+    let precursor_mz = decoy.precursor_mz(precursor_charge)?;
+    let decoy_mass = decoy.monoisotopic_mass()?;
+
+    let mut records = Vec::with_capacity(target_records.len());
+    let mut n_dropped = 0;
+    for record in target_records {
+        let Some(product_mz) = decoy_fragment_mz(&decoy, decoy_mass, record) else {
+            n_dropped += 1;
+            continue;
+        };
+
+        let mut decoy_record = record.clone();
+        decoy_record.transition_group_id = decoy_id.clone();
+        decoy_record.peptide_sequence = decoy.stripped();
+        decoy_record.full_unimod_peptide_name = format!("{}{}", DECOY_PREFIX, decoy_name);
+        decoy_record.precursor_mz = format!("{:.6}", precursor_mz);
+        decoy_record.product_mz = format!("{:.6}", product_mz);
+        decoy_record.decoy = "1".to_string();
+        decoy_record.paired_precursor_id = first.transition_group_id.clone();
+        records.push(decoy_record);
+    }
+
+    Ok((records, n_dropped))
+}
+
+/// Append one decoy per target precursor and tag both sides of each pair.
+/// Does nothing if the library already has decoys and `only_if_missing` is set.
+pub fn append_decoys(
+    mut library_records: Vec<LibraryRecord>,
+    decoy_cfg: &DecoyConfig,
+) -> Result<Vec<LibraryRecord>, Box<dyn Error>> {
+    let has_decoys = library_records.iter().any(|r| r.decoy == "1");
+    if has_decoys && decoy_cfg.only_if_missing {
+        println!("  - Library already contains decoys, skipping decoy generation");
+        return Ok(library_records);
+    }
+
+    let method = DecoyMethod::from_name(&decoy_cfg.method)?;
+    let record_index = group_library_records(&library_records);
+    let mut target_ids: Vec<&str> = record_index
+        .iter()
+        .filter(|(_, indices)| library_records[indices[0]].decoy != "1")
+        .map(|(&id, _)| id)
+        .collect();
+    target_ids.sort_unstable();
+
+    let decoy_groups: Vec<Option<(Vec<LibraryRecord>, usize)>> = target_ids
+        .par_iter()
+        .enumerate()
+        .map(|(k, id)| {
+            let group: Vec<LibraryRecord> = record_index[id]
+                .iter()
+                .map(|&idx| library_records[idx].clone())
+                .collect();
+            // Per-precursor seed keeps results independent of thread scheduling
+            let mut rng = SeededRng::new(decoy_cfg.seed ^ (k as u64).wrapping_mul(0x2545_F491_4F6C_DD1D));
+            build_decoy_records(&group, method, &mut rng).ok().filter(|(g, _)| !g.is_empty())
+        })
+        .collect();
+    let n_failed = decoy_groups.iter().filter(|g| g.is_none()).count();
+    let n_dropped_fragments: usize = decoy_groups.iter().flatten().map(|(_, n_dropped)| n_dropped).sum();
+
+    // Drop decoys that collide with an existing precursor
+    let existing: HashSet<String> = record_index.keys().map(|id| id.to_string()).collect();
+    let mut paired_targets = HashMap::new();
+    let mut n_decoys = 0;
+    let mut decoy_records = Vec::new();
+    for (group, _) in decoy_groups.into_iter().flatten() {
+        let decoy_id = &group[0].transition_group_id;
+        if existing.contains(decoy_id) {
+            continue;
+        }
+        paired_targets.insert(group[0].paired_precursor_id.clone(), decoy_id.clone());
+        n_decoys += 1;
+        decoy_records.extend(group);
+    }
+
+    for record in library_records.iter_mut() {
+        if let Some(decoy_id) = paired_targets.get(&record.transition_group_id) {
+            record.paired_precursor_id = decoy_id.clone();
+        }
+    }
+    library_records.extend(decoy_records);
+
+    println!("  - Generated {} decoy precursors ({:?})", n_decoys, method);
+    if n_failed > 0 || n_dropped_fragments > 0 {
+        println!(
+            "  - Warning: {} targets got no decoy (unparseable sequence or no fragment left), {} decoy fragments could not be recomputed and were dropped",
+            n_failed, n_dropped_fragments
+        );
+    }
+    Ok(library_records)
+}
+
+/// Decoy precursor -> paired target precursor. `column` uses the pairing
+/// column, `sequence` matches the de-reversed decoy sequence (or the id
+/// without the decoy prefix) against targets of the same charge, `auto`
+/// tries the column first.
+pub fn pair_decoys_with_targets(
+    library_records: &[LibraryRecord],
+    pairing: &str,
+) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let (use_column, use_sequence) = match pairing {
+        "auto" => (true, true),
+        "column" => (true, false),
+        "sequence" => (false, true),
+        other => return Err(format!("Invalid decoy pairing: {}. Use auto, column or sequence.", other).into()),
+    };
+
+    let mut target_ids: HashSet<&str> = HashSet::new();
+    let mut target_by_sequence: HashMap<(&str, &str), &str> = HashMap::new();
+    for record in library_records.iter().filter(|r| r.decoy != "1") {
+        target_ids.insert(&record.transition_group_id);
+        target_by_sequence
+            .entry((record.peptide_sequence.as_str(), record.precursor_charge.as_str()))
+            .or_insert(&record.transition_group_id);
+    }
+
+    let mut pairs = HashMap::new();
+    for record in library_records.iter().filter(|r| r.decoy == "1") {
+        if pairs.contains_key(&record.transition_group_id) {
+            continue;
+        }
+        let by_column = use_column
+            .then_some(record.paired_precursor_id.as_str())
+            .filter(|id| target_ids.contains(id));
+        let by_id = || {
+            let stripped_id = record.transition_group_id.strip_prefix(DECOY_PREFIX)?;
+            target_ids.get(stripped_id).copied()
+        };
+        let by_reversal = || {
+            let residues: Vec<char> = record.peptide_sequence.chars().collect();
+            let n = residues.len();
+            let pseudo: String = residues[..n.saturating_sub(1)].iter().rev().chain(&residues[n.saturating_sub(1)..]).collect();
+            let full: String = residues.iter().rev().collect();
+            [pseudo, full].iter().find_map(|sequence| {
+                target_by_sequence.get(&(sequence.as_str(), record.precursor_charge.as_str())).copied()
+            })
+        };
+        let target = by_column.or_else(|| if use_sequence { by_id().or_else(by_reversal) } else { None });
+        if let Some(target) = target {
+            pairs.insert(record.transition_group_id.clone(), target.to_string());
+        }
+    }
+    Ok(pairs)
+}
+
+/// Give every paired decoy the RT/IM of its target and add it to the
+/// extraction plan right after the target, so `max_precursors` keeps pairs
+/// together. Decoys whose target has no RT are left out.
+pub fn add_paired_decoys(
+    precursor_ids: &[String],
+    pairs: &HashMap<String, String>,
+    rt_dict: &mut HashMap<String, f32>,
+    im_dict: &mut HashMap<String, f32>,
+) -> Vec<String> {
+    let mut decoys_of: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (decoy, target) in pairs {
+        decoys_of.entry(target.as_str()).or_default().push(decoy.as_str());
+    }
+    decoys_of.values_mut().for_each(|decoys| decoys.sort_unstable());
+
+    let paired: HashSet<&str> = pairs.keys().map(|id| id.as_str()).collect();
+    let mut plan = Vec::with_capacity(precursor_ids.len() + pairs.len());
+    for id in precursor_ids.iter().filter(|id| !paired.contains(id.as_str())) {
+        plan.push(id.clone());
+        let Some(&rt) = rt_dict.get(id) else { continue };
+        let im = im_dict.get(id).copied();
+        for &decoy in decoys_of.get(id.as_str()).into_iter().flatten() {
+            rt_dict.insert(decoy.to_string(), rt);
+            match im {
+                Some(im) => im_dict.insert(decoy.to_string(), im),
+                None => im_dict.remove(decoy),
+            };
+            plan.push(decoy.to_string());
+        }
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chemistry::IonType;
+
+    fn record(id: &str, sequence: &str, charge: &str, decoy: bool, paired: &str) -> LibraryRecord {
+        LibraryRecord {
+            decoy: if decoy { "1" } else { "0" }.to_string(),
+            paired_precursor_id: paired.to_string(),
+            ..LibraryRecord::test_record(id, sequence, charge)
+        }
+    }
+
+    #[test]
+    fn mutation_changes_isobaric_leucine_and_isoleucine() {
+        assert_eq!(mutate_residue('I'), 'V');
+        assert_eq!(mutate_residue('L'), 'V');
+        assert_eq!(mutate_residue('K'), 'L');
+    }
+
+    #[test]
+    fn pseudo_reverse_keeps_the_c_terminal_residue() {
+        let target = ModifiedPeptide::parse("PEPTIDEK").unwrap();
+        let decoy = make_decoy_peptide(&target, DecoyMethod::PseudoReverse, &mut SeededRng::new(1));
+        assert_eq!(decoy.stripped(), "EDITPEPK");
+
+        // A palindrome reverses onto itself and falls back to terminal mutation
+        let palindrome = ModifiedPeptide::parse("AKLKAK").unwrap();
+        let decoy = make_decoy_peptide(&palindrome, DecoyMethod::PseudoReverse, &mut SeededRng::new(1));
+        assert_eq!(decoy.stripped(), "ALLKLK");
+    }
+
+    #[test]
+    fn decoy_records_recompute_fragments_and_count_dropped_ones() {
+        let y3 = LibraryRecord::test_record("PEPTIDEK2", "PEPTIDEK", "2");
+        let precursor_ion = LibraryRecord { fragment_type: "3".to_string(), fragment_charge: "2".to_string(), ..y3.clone() };
+        let internal = LibraryRecord { fragment_type: "8".to_string(), ..y3.clone() };
+
+        let (records, n_dropped) =
+            build_decoy_records(&[y3, precursor_ion, internal], DecoyMethod::PseudoReverse, &mut SeededRng::new(1)).unwrap();
+        assert_eq!(n_dropped, 1);
+        assert_eq!(records.len(), 2);
+
+        let decoy = ModifiedPeptide::parse("EDITPEPK").unwrap();
+        let y3_mz = decoy.fragment_mz(IonType::Y, 3, 1, None).unwrap();
+        assert_eq!(records[0].product_mz, format!("{:.6}", y3_mz));
+        assert_eq!(records[1].product_mz, records[1].precursor_mz);
+        assert!(records.iter().all(|r| r.decoy == "1" && r.paired_precursor_id == "PEPTIDEK2"));
+        assert_eq!(records[0].transition_group_id, "DECOY_EDITPEPK2");
+    }
+
+    #[test]
+    fn decoys_pair_by_column_prefix_or_reversed_sequence() {
+        let records = vec![
+            record("PEPTIDEK2", "PEPTIDEK", "2", false, ""),
+            record("AAAK2", "AAAK", "2", false, ""),
+            record("d1", "KEDITPEP", "2", true, "PEPTIDEK2"),
+            record("DECOY_AAAK2", "AAAK", "2", true, ""),
+            record("d3", "EDITPEPK", "2", true, ""),
+            record("d4", "EDITPEPK", "3", true, ""),
+        ];
+        let pairs = pair_decoys_with_targets(&records, "auto").unwrap();
+        assert_eq!(pairs.get("d1").map(String::as_str), Some("PEPTIDEK2"));
+        assert_eq!(pairs.get("DECOY_AAAK2").map(String::as_str), Some("AAAK2"));
+        assert_eq!(pairs.get("d3").map(String::as_str), Some("PEPTIDEK2"));
+        assert!(!pairs.contains_key("d4"), "charge must match");
+
+        let by_column = pair_decoys_with_targets(&records, "column").unwrap();
+        assert_eq!(by_column.len(), 1);
+        assert!(pair_decoys_with_targets(&records, "nearest").is_err());
+    }
+
+    #[test]
+    fn paired_decoys_follow_their_target_with_its_rt_and_im() {
+        let ids: Vec<String> = ["t1", "d1", "t2", "t3"].iter().map(|id| id.to_string()).collect();
+        let pairs = HashMap::from([
+            ("d1".to_string(), "t1".to_string()),
+            ("d3".to_string(), "t3".to_string()),
+            ("d4".to_string(), "t4".to_string()),
+        ]);
+        let mut rt_dict = HashMap::from([("t1".to_string(), 12.0), ("t3".to_string(), 30.0)]);
+        let mut im_dict = HashMap::from([("t1".to_string(), 0.9), ("d3".to_string(), 1.4)]);
+
+        let plan = add_paired_decoys(&ids, &pairs, &mut rt_dict, &mut im_dict);
+        assert_eq!(plan, ["t1", "d1", "t2", "t3", "d3"]);
+        assert_eq!(rt_dict.get("d1"), Some(&12.0));
+        assert_eq!(im_dict.get("d1"), Some(&0.9));
+        assert_eq!(rt_dict.get("d3"), Some(&30.0));
+        assert_eq!(im_dict.get("d3"), None, "target without IM");
+        assert!(!rt_dict.contains_key("d4"));
+    }
+}
@@ -0,0 +1,260 @@
+// File: src/empirical_library.rs
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use ndarray::{Axis, s};
+
+use crate::calibration::median;
+use crate::chemistry::fragment_series_name;
+use crate::config::{EmpiricalLibraryConfig, ExtractionConfig};
+use crate::im_calibration::measure_im;
+use crate::peaks::{ChromPeak, PeakGroupResult};
+use crate::processing::ExtractedPrecursor;
+use crate::rescore::RescoringSummary;
+use crate::utils::{LibraryRecord, PrecursorLibData, VARIANT_ORIGINAL};
+
+/// Columns written by `write_library_tsv`, all understood by `process_library_fast`.
+const LIBRARY_COLUMNS: [&str; 18] = [
+    "transition_group_id",
+    "PeptideSequence",
+    "FullUniModPeptideName",
+    "PrecursorCharge",
+    "PrecursorMz",
+    "Tr_recalibrated",
+    "IonMobility",
+    "ProductMz",
+    "FragmentType",
+    "FragmentCharge",
+    "FragmentNumber",
+    "FragmentLossType",
+    "LibraryIntensity",
+    "ProteinID",
+    "ProteinName",
+    "Gene",
+    "decoy",
+    "PairedPrecursorId",
+];
+
+/// Library records of one peak group with run RT, measured IM and the
+/// observed apex intensities (relative to the most intense fragment).
+/// Fragments without signal at the apex are dropped.
+pub fn empirical_records(
+    precursor_data: &PrecursorLibData,
+    view: &ExtractedPrecursor,
+    peak: &ChromPeak,
+    extraction: &ExtractionConfig,
+) -> Vec<LibraryRecord> {
+    let summed = view.rsm_matrix.sum_axis(Axis(1));
+    let precursor_rows = summed.slice(s![0, .., ..]);
+    let frag_info = &view.frag_info;
+    let apex: Vec<(f32, f32)> = (0..frag_info.shape()[1])
+        .filter(|&row| frag_info[[0, row, 2]] == VARIANT_ORIGINAL && frag_info[[0, row, 0]] > 0.0)
+        .map(|row| (frag_info[[0, row, 0]], precursor_rows[[row, peak.apex_idx]]))
+        .collect();
+
+    let observed: Vec<(&LibraryRecord, f32)> = precursor_data
+        .lib_records
+        .iter()
+        .filter_map(|record| {
+            let product_mz = record.product_mz.parse::<f32>().ok()?;
+            let &(_, intensity) = apex.iter().find(|(mz, _)| (mz - product_mz).abs() < 1e-3)?;
+            (intensity > 0.0).then_some((record, intensity))
+        })
+        .collect();
+    let max_intensity = observed.iter().map(|o| o.1).fold(0.0f32, f32::max);
+    if max_intensity <= 0.0 {
+        return Vec::new();
+    }
+
+    let im = measure_im(view, peak, extraction.ms2_tolerance, &extraction.mz_unit)
+        .map(|(im, _)| im)
+        .unwrap_or(precursor_data.im);
+    observed
+        .into_iter()
+        .map(|(record, intensity)| {
+            let mut record = record.clone();
+            record.tr_recalibrated = format!("{:.4}", peak.apex_rt);
+            if im > 0.0 {
+                record.ion_mobility = format!("{:.5}", im);
+            }
+            record.library_intensity = format!("{:.5}", intensity / max_intensity);
+            record
+        })
+        .collect()
+}
+
+/// Confident target peak groups of a run: q-value cut when rescoring ran,
+/// otherwise the best candidate per precursor above the library dot product
+/// threshold. Each contributes its empirical records.
+pub fn select_confident_records(
+    results: &[PeakGroupResult],
+    summary: Option<&RescoringSummary>,
+    config: &EmpiricalLibraryConfig,
+) -> Vec<LibraryRecord> {
+    let confident: Vec<usize> = match summary {
+        Some(summary) => summary
+            .precursors
+            .iter()
+            .filter(|p| p.q_value <= config.q_value)
+            .map(|p| p.result_index)
+            .collect(),
+        None => {
+            let mut best: HashMap<&str, usize> = HashMap::new();
+            for (index, result) in results.iter().enumerate() {
+                let Some(scores) = &result.scores else { continue };
+                if scores.library_dotprod < config.min_dotprod {
+                    continue;
+                }
+                let entry = best.entry(result.precursor_id.as_str()).or_insert(index);
+                let current = results[*entry].scores.as_ref().map(|s| s.library_dotprod).unwrap_or(0.0);
+                if scores.library_dotprod > current {
+                    *entry = index;
+                }
+            }
+            let mut indices: Vec<usize> = best.into_values().collect();
+            indices.sort_unstable();
+            indices
+        }
+    };
+    confident
+        .into_iter()
+        .map(|index| &results[index])
+        .filter(|result| !result.decoy && result.library_entry.len() >= config.min_fragments)
+        .flat_map(|result| result.library_entry.iter().cloned())
+        .collect()
+}
+
+/// Consensus of several empirical libraries: median RT and IM, mean relative
+/// fragment intensity (0 in runs where the fragment is missing), keeping
+/// precursors and fragments seen in at least `min_run_fraction` of the runs.
+pub fn consensus_library(runs: &[Vec<LibraryRecord>], min_run_fraction: f32) -> Vec<LibraryRecord> {
+    let n_runs = runs.len() as f32;
+    // precursor -> (per-run RT, per-run IM, fragment key -> (template, per-run intensity))
+    type FragmentObservations = HashMap<String, (LibraryRecord, Vec<f32>)>;
+    let mut precursors: HashMap<String, (Vec<f32>, Vec<f32>, FragmentObservations)> = HashMap::new();
+    let mut order: Vec<String> = Vec::new();
+
+    for records in runs {
+        let mut seen_in_run: HashSet<&str> = HashSet::new();
+        for record in records {
+            let entry = precursors.entry(record.transition_group_id.clone()).or_insert_with(|| {
+                order.push(record.transition_group_id.clone());
+                (Vec::new(), Vec::new(), HashMap::new())
+            });
+            if seen_in_run.insert(&record.transition_group_id) {
+                if let Ok(rt) = record.tr_recalibrated.parse::<f32>() {
+                    entry.0.push(rt);
+                }
+                if let Ok(im) = record.ion_mobility.parse::<f32>() {
+                    entry.1.push(im);
+                }
+            }
+            let key = format!(
+                "{}_{}_{}_{}",
+                record.fragment_type, record.fragment_number, record.fragment_charge, record.fragment_loss_type
+            );
+            let intensity = record.library_intensity.parse::<f32>().unwrap_or(0.0);
+            entry.2.entry(key).or_insert_with(|| (record.clone(), Vec::new())).1.push(intensity);
+        }
+    }
+
+    let mut consensus = Vec::new();
+    for precursor_id in order {
+        let (rts, ims, fragments) = &precursors[&precursor_id];
+        if (rts.len() as f32) < min_run_fraction * n_runs {
+            continue;
+        }
+        let (rt, im) = (median(rts.clone()), median(ims.clone()));
+        let mut kept: Vec<(LibraryRecord, f32)> = fragments
+            .values()
+            .filter(|(_, intensities)| intensities.len() as f32 >= min_run_fraction * n_runs)
+            .map(|(template, intensities)| (template.clone(), intensities.iter().sum::<f32>() / n_runs))
+            .collect();
+        kept.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let max_intensity = kept.first().map(|k| k.1).unwrap_or(0.0);
+        if max_intensity <= 0.0 {
+            continue;
+        }
+        for (mut record, intensity) in kept {
+            record.tr_recalibrated = format!("{:.4}", rt);
+            if !ims.is_empty() {
+                record.ion_mobility = format!("{:.5}", im);
+            }
+            record.library_intensity = format!("{:.5}", intensity / max_intensity);
+            consensus.push(record);
+        }
+    }
+    consensus
+}
+
+/// Write records in the library TSV schema read by `process_library_fast`.
+pub fn write_library_tsv(records: &[LibraryRecord], path: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "{}", LIBRARY_COLUMNS.join("\t"))?;
+    for r in records {
+        let fields = [
+            r.transition_group_id.as_str(),
+            &r.peptide_sequence,
+            &r.full_unimod_peptide_name,
+            &r.precursor_charge,
+            &r.precursor_mz,
+            &r.tr_recalibrated,
+            &r.ion_mobility,
+            &r.product_mz,
+            fragment_series_name(&r.fragment_type),
+            &r.fragment_charge,
+            &r.fragment_number,
+            &r.fragment_loss_type,
+            &r.library_intensity,
+            &r.protein_id,
+            &r.protein_name,
+            &r.gene,
+            &r.decoy,
+            &r.paired_precursor_id,
+        ];
+        writeln!(writer, "{}", fields.join("\t"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(id: &str, rt: &str, number: &str, intensity: &str) -> LibraryRecord {
+        LibraryRecord {
+            tr_recalibrated: rt.to_string(),
+            fragment_number: number.to_string(),
+            library_intensity: intensity.to_string(),
+            ..LibraryRecord::test_record(id, "PEPTIDEK", "2")
+        }
+    }
+
+    fn runs() -> Vec<Vec<LibraryRecord>> {
+        vec![
+            vec![fragment("a", "10", "3", "100"), fragment("a", "10", "4", "40"), fragment("b", "20", "3", "10")],
+            vec![fragment("a", "12", "3", "50")],
+            vec![fragment("a", "11", "3", "150"), fragment("a", "11", "4", "20")],
+        ]
+    }
+
+    #[test]
+    fn consensus_keeps_fragments_seen_in_enough_runs() {
+        let consensus = consensus_library(&runs(), 1.0);
+        assert_eq!(consensus.len(), 1);
+        assert_eq!(consensus[0].transition_group_id, "a");
+        assert_eq!(consensus[0].fragment_number, "3");
+        assert_eq!(consensus[0].tr_recalibrated, "11.0000");
+        assert_eq!(consensus[0].library_intensity, "1.00000");
+    }
+
+    #[test]
+    fn consensus_averages_intensities_over_all_runs() {
+        let consensus = consensus_library(&runs(), 0.5);
+        let ids: Vec<(&str, &str)> = consensus.iter().map(|r| (r.transition_group_id.as_str(), r.fragment_number.as_str())).collect();
+        assert_eq!(ids, [("a", "3"), ("a", "4")]);
+        // y4: (40 + 0 + 20) / 3 = 20 against y3: (100 + 50 + 150) / 3 = 100
+        assert_eq!(consensus[1].library_intensity, "0.20000");
+    }
+}
@@ -0,0 +1,251 @@
+// File: src/im_calibration.rs
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::calibration::{linear_fit, median};
+use crate::config::{Config, ImCalibrationConfig};
+use crate::mass_calibration::{AnchorSelection, collect_anchors};
+use crate::processing::{ExtractedPrecursor, FastChunkFinder};
+use crate::peaks::ChromPeak;
+use crate::scores::mz_tolerance;
+use crate::utils::{IndexedTimsTOFData, PrecursorLibData, VARIANT_ORIGINAL};
+
+/// Observed ion mobility of one anchor peak group.
+#[derive(Debug, Clone)]
+pub struct ImAnchor {
+    pub precursor_id: String,
+    pub charge: u8,
+    pub expected_im: f32,
+    pub observed_im: f32, // intensity-weighted over the matched fragment peaks
+    pub width: f32,       // intensity-weighted SD of the matched peak mobilities
+}
+
+/// Intensity-weighted mean and SD of the mobility of all original-variant
+/// MS2 fragment peaks inside the peak boundaries.
+pub fn measure_im(extracted: &ExtractedPrecursor, peak: &ChromPeak, ms2_tolerance: f32, mz_unit: &str) -> Option<(f32, f32)> {
+    let frag_info = &extracted.frag_info;
+    let peaks = &extracted.ms2_peaks;
+    let targets: Vec<(f32, f32)> = (0..frag_info.shape()[1])
+        .filter(|&row| frag_info[[0, row, 2]] == VARIANT_ORIGINAL && frag_info[[0, row, 0]] > 0.0)
+        .map(|row| {
+            let mz = frag_info[[0, row, 0]];
+            (mz, mz_tolerance(mz, ms2_tolerance, mz_unit))
+        })
+        .collect();
+
+    let (mut weight, mut sum, mut sum_sq) = (0.0f64, 0.0f64, 0.0f64);
+    for k in 0..peaks.mz_values.len() {
+        let rt = peaks.rt_values_min[k];
+        if rt < peak.left_rt || rt > peak.right_rt {
+            continue;
+        }
+        let mz = peaks.mz_values[k];
+        if !targets.iter().any(|&(target, tolerance)| (mz - target).abs() <= tolerance) {
+            continue;
+        }
+        let (w, im) = (peaks.intensity_values[k] as f64, peaks.mobility_values[k] as f64);
+        weight += w;
+        sum += w * im;
+        sum_sq += w * im * im;
+    }
+    if weight <= 0.0 {
+        return None;
+    }
+    let mean = sum / weight;
+    let sd = (sum_sq / weight - mean * mean).max(0.0).sqrt();
+    Some((mean as f32, sd as f32))
+}
+
+/// Run-level IM correction (observed = slope * expected + intercept) and the
+/// per-charge IM tolerances learned from the anchor peak widths.
+#[derive(Debug, Clone)]
+pub struct ImCalibration {
+    pub slope: f32,
+    pub intercept: f32,
+    pub residual_sd: f32,
+    pub tolerance_by_charge: HashMap<u8, f32>,
+    pub anchors: Vec<ImAnchor>,
+}
+
+impl ImCalibration {
+    pub fn predict(&self, expected_im: f32) -> f32 {
+        self.slope * expected_im + self.intercept
+    }
+}
+
+/// First pass: measure the observed IM of the anchor peak groups (extracted
+/// with the wider `anchor_im_tolerance`) and fit the run-level IM model.
+pub fn learn_im_calibration(
+    precursors: &[PrecursorLibData],
+    ms1_indexed: &IndexedTimsTOFData,
+    finder: &FastChunkFinder,
+    config: &Config,
+    device: &str,
+) -> Result<ImCalibration, Box<dyn Error>> {
+    let im_cfg = &config.im_calibration;
+    let mut anchor_config = config.clone();
+    anchor_config.extraction.im_tolerance_mode = "fixed".to_string();
+    anchor_config.extraction.im_tolerance = im_cfg.anchor_im_tolerance;
+
+    let selection = AnchorSelection {
+        n_precursors: im_cfg.anchor_precursors,
+        fraction: im_cfg.anchor_fraction,
+        min_fragments: im_cfg.min_fragments,
+        require_im: true,
+    };
+    let extraction = &anchor_config.extraction;
+    let anchors = collect_anchors(precursors, ms1_indexed, finder, &anchor_config, &selection, device, |precursor, extracted, peak| {
+        let (observed_im, width) = measure_im(extracted, peak, extraction.ms2_tolerance, &extraction.mz_unit)?;
+        Some(ImAnchor {
+            precursor_id: precursor.precursor_id.clone(),
+            charge: precursor.precursor_info.get(2).copied().unwrap_or(0.0) as u8,
+            expected_im: precursor.im,
+            observed_im,
+            width,
+        })
+    });
+    if anchors.len() < im_cfg.min_anchors {
+        return Err(format!("only {} IM calibration anchors found (need {})", anchors.len(), im_cfg.min_anchors).into());
+    }
+    fit_im_calibration(anchors, im_cfg)
+}
+
+/// Fit the IM model and the per-charge tolerances on the measured anchors.
+pub fn fit_im_calibration(anchors: Vec<ImAnchor>, im_cfg: &ImCalibrationConfig) -> Result<ImCalibration, Box<dyn Error>> {
+    // 1. Offset (median shift) or linear calibration curve
+    let deltas: Vec<f32> = anchors.iter().map(|a| a.observed_im - a.expected_im).collect();
+    let (slope, intercept) = match im_cfg.model.as_str() {
+        "offset" => (1.0, median(deltas)),
+        "linear" => {
+            let points: Vec<(f32, f32)> = anchors.iter().map(|a| (a.expected_im, a.observed_im)).collect();
+            linear_fit(&points)
+        }
+        other => return Err(format!("Invalid IM calibration model: {}. Use offset or linear.", other).into()),
+    };
+    let residuals: Vec<f32> = anchors
+        .iter()
+        .map(|a| (a.observed_im - (slope * a.expected_im + intercept)).abs())
+        .collect();
+    let residual_sd = 1.4826 * median(residuals);
+
+    // 2. Tolerance per charge state: multiple of the median peak width,
+    //    widened by the calibration residual
+    let mut widths: HashMap<u8, Vec<f32>> = HashMap::new();
+    for anchor in &anchors {
+        widths.entry(anchor.charge).or_default().push(anchor.width);
+    }
+    let tolerance_by_charge = widths
+        .into_iter()
+        .map(|(charge, w)| {
+            let tolerance = im_cfg.width_multiplier * median(w).hypot(residual_sd);
+            (charge, tolerance.max(im_cfg.min_im_tolerance))
+        })
+        .collect();
+
+    Ok(ImCalibration { slope, intercept, residual_sd, tolerance_by_charge, anchors })
+}
+
+/// Replace the expected IM of every precursor with a known IM by the calibrated value.
+pub fn apply_im_calibration(calibration: &ImCalibration, precursors: &mut [PrecursorLibData]) {
+    for precursor in precursors.iter_mut().filter(|p| p.im > 0.0) {
+        precursor.im = calibration.predict(precursor.im);
+    }
+}
+
+/// One line per anchor: expected vs. observed IM and the IM peak width.
+pub fn write_im_anchor_table(calibration: &ImCalibration, run: &str, path: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "run\tprecursor_id\tcharge\texpected_im\tobserved_im\tdelta_im\tcalibrated_im\twidth")?;
+    for a in &calibration.anchors {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{:.5}\t{:.5}\t{:.5}\t{:.5}\t{:.5}",
+            run, a.precursor_id, a.charge, a.expected_im, a.observed_im,
+            a.observed_im - a.expected_im, calibration.predict(a.expected_im), a.width
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use ndarray::{Array3, Array4};
+    use crate::utils::TimsTOFData;
+
+    // observed = 1.1 * expected - 0.08, widths 0.01 (charge 2) and 0.02 (charge 3)
+    fn anchors() -> Vec<ImAnchor> {
+        (0..10)
+            .map(|k| {
+                let expected_im = 0.7 + 0.06 * k as f32;
+                let charge = if k < 5 { 2 } else { 3 };
+                ImAnchor {
+                    precursor_id: format!("p{}", k),
+                    charge,
+                    expected_im,
+                    observed_im: 1.1 * expected_im - 0.08,
+                    width: 0.01 * (charge - 1) as f32,
+                }
+            })
+            .collect()
+    }
+
+    fn im_cfg(model: &str) -> ImCalibrationConfig {
+        ImCalibrationConfig { model: model.to_string(), ..ImCalibrationConfig::default() }
+    }
+
+    #[test]
+    fn linear_model_recovers_slope_and_intercept() {
+        let calibration = fit_im_calibration(anchors(), &im_cfg("linear")).unwrap();
+        assert!((calibration.slope - 1.1).abs() < 1e-4 && (calibration.intercept + 0.08).abs() < 1e-4);
+        assert!(calibration.residual_sd < 1e-4);
+        assert!((calibration.predict(1.0) - 1.02).abs() < 1e-4);
+        // Tolerance = multiplier x median width (the residual adds nothing)
+        assert!((calibration.tolerance_by_charge[&2] - 0.03).abs() < 1e-4);
+        assert!((calibration.tolerance_by_charge[&3] - 0.06).abs() < 1e-4);
+    }
+
+    #[test]
+    fn offset_model_shifts_by_the_median_delta() {
+        let calibration = fit_im_calibration(anchors(), &im_cfg("offset")).unwrap();
+        // deltas 0.1 * expected - 0.08 = -0.01 .. 0.044, median between 0.014 and 0.02
+        assert_eq!(calibration.slope, 1.0);
+        assert!((calibration.intercept - 0.017).abs() < 1e-4, "{}", calibration.intercept);
+        assert!(calibration.residual_sd > 0.0);
+        assert!(calibration.tolerance_by_charge[&2] > 0.03);
+
+        let floor = ImCalibrationConfig { min_im_tolerance: 0.5, ..im_cfg("offset") };
+        assert_eq!(fit_im_calibration(anchors(), &floor).unwrap().tolerance_by_charge[&3], 0.5);
+        assert!(fit_im_calibration(anchors(), &im_cfg("spline")).is_err());
+    }
+
+    #[test]
+    fn measured_im_is_weighted_over_matching_fragment_peaks() {
+        let frag_info = Array3::from_shape_fn((1, 2, 4), |(_, row, c)| match c {
+            0 => 400.0 + 200.0 * row as f32,
+            2 => VARIANT_ORIGINAL,
+            _ => 1.0,
+        });
+        let mut extracted = ExtractedPrecursor::synthetic(Array4::zeros((1, 1, 2, 3)), frag_info, vec![10.0, 10.1, 10.2]);
+        let mut peaks = TimsTOFData::new();
+        // (mz, rt, im, intensity): two matches, one outside the peak, one off-target
+        for (mz, rt, im, intensity) in [(400.01, 10.1, 0.9, 100), (600.0, 10.1, 1.1, 100), (600.0, 10.5, 2.0, 1000), (500.0, 10.1, 2.0, 1000)] {
+            peaks.mz_values.push(mz);
+            peaks.rt_values_min.push(rt);
+            peaks.mobility_values.push(im);
+            peaks.intensity_values.push(intensity);
+            peaks.frame_indices.push(0);
+            peaks.scan_indices.push(0);
+        }
+        extracted.ms2_peaks = Arc::new(peaks);
+        let peak = ChromPeak { apex_idx: 1, left_idx: 0, right_idx: 2, apex_rt: 10.1, left_rt: 10.0, right_rt: 10.2, fwhm: 0.1, apex_intensity: 1.0 };
+
+        let (im, width) = measure_im(&extracted, &peak, 50.0, "ppm").unwrap();
+        assert!((im - 1.0).abs() < 1e-5 && (width - 0.1).abs() < 1e-4, "{} {}", im, width);
+        assert!(measure_im(&extracted, &peak, 5.0, "ppm").is_some_and(|(im, _)| (im - 1.1).abs() < 1e-5));
+        assert!(measure_im(&extracted, &peak, 0.001, "Da").is_some_and(|(im, _)| (im - 1.1).abs() < 1e-5));
+    }
+}
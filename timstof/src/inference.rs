@@ -0,0 +1,186 @@
+// File: src/inference.rs
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use ndarray::{Array1, Array2, Array3, Array4, Axis, stack};
+use rayon::prelude::*;
+use tract_onnx::prelude::*;
+
+use crate::peaks::PeakGroupResult;
+use crate::processing::{ExtractedPrecursor, prepare_precursor_features};
+use crate::utils::PrecursorLibData;
+
+/// Model inputs of one peak group: the view's rsm tensor (repeats,
+/// fragments, rt) and its `prepare_precursor_features` row.
+#[derive(Debug, Clone)]
+pub struct ModelInput {
+    pub rsm: Array3<f32>,
+    pub precursor_feat: Array1<f32>,
+}
+
+/// Inputs of a view, with the precursor features taken at `expected_rt`.
+pub fn model_input(
+    precursor_data: &PrecursorLibData,
+    view: &ExtractedPrecursor,
+    expected_rt: f32,
+) -> Result<ModelInput, Box<dyn Error>> {
+    let id = &precursor_data.precursor_id;
+    let rt_dict = HashMap::from([(id.clone(), expected_rt)]);
+    let im_dict = HashMap::from([(id.clone(), precursor_data.im)]);
+    let features = prepare_precursor_features(
+        &[vec![id.clone()]],
+        std::slice::from_ref(&precursor_data.precursor_info),
+        &rt_dict,
+        &im_dict,
+    )?;
+    Ok(ModelInput {
+        rsm: view.rsm_matrix.index_axis(Axis(0), 0).to_owned(),
+        precursor_feat: features.row(0).to_owned(),
+    })
+}
+
+/// ONNX scoring model: inputs `rsm` (batch, repeats, fragments, rt) and
+/// `precursor_feat` (batch, 8), in that order; the first output holds one
+/// score per batch row.
+pub struct ScoringModel {
+    plan: TypedRunnableModel<TypedModel>,
+}
+
+impl ScoringModel {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let plan = tract_onnx::onnx()
+            .model_for_path(path)
+            .map_err(|e| format!("Failed to load ONNX model {}: {}", path, e))?
+            .into_optimized()?
+            .into_runnable()?;
+        Ok(Self { plan })
+    }
+
+    pub fn score(&self, rsm: Array4<f32>, precursor_feat: Array2<f32>) -> Result<Vec<f32>, Box<dyn Error>> {
+        let batch = rsm.shape()[0];
+        // tract bundles its own ndarray version: hand the data over as shape + slice
+        let rsm = Tensor::from_shape(rsm.shape(), rsm.as_standard_layout().as_slice().unwrap_or_default())?;
+        let precursor_feat = Tensor::from_shape(precursor_feat.shape(), precursor_feat.as_standard_layout().as_slice().unwrap_or_default())?;
+        let outputs = self.plan.run(tvec!(rsm.into(), precursor_feat.into()))?;
+        let scores: Vec<f32> = outputs[0].to_array_view::<f32>()?.iter().copied().collect();
+        if scores.len() != batch {
+            return Err(format!("Model returned {} scores for a batch of {}.", scores.len(), batch).into());
+        }
+        Ok(scores)
+    }
+}
+
+/// Score every peak group carrying model inputs, in batches of up to
+/// `batch_size` groups with the same tensor shape. The inputs are dropped
+/// and the score kept in `model_score`; returns the number of scored groups.
+pub fn score_peak_groups(
+    results: &mut [PeakGroupResult],
+    model: &ScoringModel,
+    batch_size: usize,
+) -> Result<usize, Box<dyn Error>> {
+    let inputs: Vec<Option<ModelInput>> = results.iter_mut().map(|r| r.model_input.take()).collect();
+    let mut by_shape: HashMap<&[usize], Vec<usize>> = HashMap::new();
+    for (index, input) in inputs.iter().enumerate() {
+        if let Some(input) = input {
+            by_shape.entry(input.rsm.shape()).or_default().push(index);
+        }
+    }
+    let batches: Vec<&[usize]> = by_shape.values().flat_map(|indices| indices.chunks(batch_size.max(1))).collect();
+
+    let batch_scores: Vec<Vec<f32>> = batches
+        .par_iter()
+        .map(|batch| {
+            let batch_inputs: Vec<&ModelInput> = batch.iter().filter_map(|&index| inputs[index].as_ref()).collect();
+            let rsm = stack(Axis(0), &batch_inputs.iter().map(|input| input.rsm.view()).collect::<Vec<_>>()).map_err(|e| e.to_string())?;
+            let features = stack(Axis(0), &batch_inputs.iter().map(|input| input.precursor_feat.view()).collect::<Vec<_>>())
+                .map_err(|e| e.to_string())?;
+            model.score(rsm, features).map_err(|e| e.to_string())
+        })
+        .collect::<Result<_, String>>()?;
+
+    let mut n_scored = 0;
+    for (batch, batch_scores) in batches.iter().zip(batch_scores) {
+        for (&index, score) in batch.iter().zip(batch_scores) {
+            results[index].model_score = Some(score);
+            n_scored += 1;
+        }
+    }
+    Ok(n_scored)
+}
+
+/// One line per scored peak group.
+pub fn write_model_score_table(results: &[PeakGroupResult], run: &str, path: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "run\tprecursor_id\tprotein_id\tdecoy\tcandidate\tapex_rt\tmodel_score")?;
+    for result in results {
+        let Some(score) = result.model_score else { continue };
+        let candidate = result.candidate_rank.map(|r| r.to_string()).unwrap_or_default();
+        let apex_rt = result.peak.as_ref().map(|p| format!("{:.4}", p.apex_rt)).unwrap_or_else(|| "NA".to_string());
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{:.6}",
+            run, result.precursor_id, result.protein_id, result.decoy as u8, candidate, apex_rt, score
+        )?;
+    }
+    Ok(())
+}
+
+/// Run a synthetic batch through the model, e.g. to check a freshly
+/// exported model before a full run.
+pub fn check_model(path: &str, repeats: usize) -> Result<Vec<f32>, Box<dyn Error>> {
+    let model = ScoringModel::load(path)?;
+    let rsm = Array4::from_shape_fn((2, repeats, 72, 48), |(b, _, fragment, rt)| (b * 1000 + fragment * 10 + rt) as f32);
+    let features = Array2::from_shape_fn((2, 8), |(b, k)| (b + k) as f32);
+    model.score(rsm, features)
+}
+
+#[cfg(all(test, feature = "inference"))]
+mod tests {
+    use super::*;
+
+    fn toy_model() -> ScoringModel {
+        ScoringModel::load(concat!(env!("CARGO_MANIFEST_DIR"), "/models/toy_scorer.onnx")).unwrap()
+    }
+
+    // score = sigmoid(ln(1 + mean(rsm)) - 0.001 * mean(precursor_feat) - 5), see models/make_toy_scorer.py
+    fn toy_score(rsm: f32, feature: f32) -> f32 {
+        1.0 / (1.0 + (-((1.0 + rsm).ln() - 0.001 * feature - 5.0)).exp())
+    }
+
+    fn group(id: &str, input: Option<(usize, f32, f32)>) -> PeakGroupResult {
+        PeakGroupResult {
+            model_input: input.map(|(n_rt, rsm, feature)| ModelInput {
+                rsm: Array3::from_elem((1, 72, n_rt), rsm),
+                precursor_feat: Array1::from_elem(8, feature),
+            }),
+            ..PeakGroupResult::empty(id, false)
+        }
+    }
+
+    #[test]
+    fn scores_groups_of_mixed_shapes_in_order() {
+        let mut results = vec![
+            group("a", Some((48, 150.0, 10.0))),
+            group("b", None),
+            group("c", Some((16, 20.0, 500.0))),
+            group("d", Some((48, 0.0, 0.0))),
+        ];
+        let n_scored = score_peak_groups(&mut results, &toy_model(), 1).unwrap();
+
+        assert_eq!(n_scored, 3);
+        assert!(results.iter().all(|r| r.model_input.is_none()));
+        assert_eq!(results[1].model_score, None);
+        for (result, expected) in [(&results[0], toy_score(150.0, 10.0)), (&results[2], toy_score(20.0, 500.0)), (&results[3], toy_score(0.0, 0.0))] {
+            let score = result.model_score.unwrap();
+            assert!((score - expected).abs() < 1e-5, "{}: {} vs {}", result.precursor_id, score, expected);
+        }
+    }
+
+    #[test]
+    fn check_model_returns_one_score_per_row() {
+        let scores = check_model(concat!(env!("CARGO_MANIFEST_DIR"), "/models/toy_scorer.onnx"), 3).unwrap();
+        assert_eq!(scores.len(), 2);
+        assert!(scores.iter().all(|s| (0.0..=1.0).contains(s)));
+    }
+}
@@ -0,0 +1,192 @@
+// File: src/interference.rs
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use ndarray::{Array3, s};
+use rayon::prelude::*;
+
+use crate::config::{ExtractionConfig, InterferenceConfig};
+use crate::processing::FastChunkFinder;
+use crate::scores::mz_tolerance;
+use crate::utils::{PrecursorLibData, VARIANT_ORIGINAL};
+
+/// Library fragment of a precursor that other precursors of the same MS2
+/// window share within the MS2 tolerance while overlapping in RT and IM.
+#[derive(Debug, Clone)]
+pub struct FragmentInterference {
+    pub product_mz: f32,
+    pub interferers: Vec<String>, // precursor ids
+}
+
+struct IndexedFragment {
+    mz: f32,
+    precursor: usize,
+}
+
+/// Original-variant MS2 fragment m/z of a precursor (`ms2_data` rows:
+/// m/z in column 0, variant in column 8).
+fn fragment_mzs(precursor: &PrecursorLibData) -> Vec<f32> {
+    precursor
+        .ms2_data
+        .iter()
+        .filter(|row| row.len() > 8 && row[8] == VARIANT_ORIGINAL && row[0] > 0.0)
+        .map(|row| row[0])
+        .collect()
+}
+
+/// Build the per-window fragment m/z index over all `precursors` and store
+/// the shared transitions in each precursor's `interference`. Precursors
+/// overlap when their RTs differ by at most `rt_window` and their IMs by at
+/// most `im_window` (an unknown IM always overlaps).
+pub fn annotate_interference(
+    precursors: &mut [PrecursorLibData],
+    finder: &FastChunkFinder,
+    extraction: &ExtractionConfig,
+    config: &InterferenceConfig,
+) -> usize {
+    // 1. Fragment m/z index per MS2 window, sorted by m/z
+    let mut windows: Vec<Vec<IndexedFragment>> = (0..finder.n_windows()).map(|_| Vec::new()).collect();
+    let mut precursor_window = vec![None; precursors.len()];
+    for (index, precursor) in precursors.iter().enumerate() {
+        let Some(window) = precursor.precursor_info.get(1).and_then(|&mz| finder.window_of(mz)) else { continue };
+        precursor_window[index] = Some(window);
+        windows[window].extend(fragment_mzs(precursor).into_iter().map(|mz| IndexedFragment { mz, precursor: index }));
+    }
+    windows.par_iter_mut().for_each(|fragments| fragments.sort_by(|a, b| a.mz.total_cmp(&b.mz)));
+
+    // 2. Shared transitions with RT/IM overlap
+    let overlaps = |a: &PrecursorLibData, b: &PrecursorLibData| {
+        let rt_ok = (a.rt - b.rt).abs() <= config.rt_window;
+        let im_ok = a.im <= 0.0 || b.im <= 0.0 || (a.im - b.im).abs() <= config.im_window;
+        rt_ok && im_ok
+    };
+    let shared: Vec<Vec<FragmentInterference>> = (0..precursors.len())
+        .into_par_iter()
+        .map(|index| {
+            let Some(window) = precursor_window[index] else { return Vec::new() };
+            let fragments = &windows[window];
+            let precursor = &precursors[index];
+            fragment_mzs(precursor)
+                .into_iter()
+                .filter_map(|mz| {
+                    let tolerance = mz_tolerance(mz, extraction.ms2_tolerance, &extraction.mz_unit);
+                    let start = fragments.partition_point(|f| f.mz < mz - tolerance);
+                    let end = fragments.partition_point(|f| f.mz <= mz + tolerance);
+                    let mut interferers: Vec<usize> = fragments[start..end]
+                        .iter()
+                        .map(|f| f.precursor)
+                        .filter(|&other| other != index && overlaps(precursor, &precursors[other]))
+                        .collect();
+                    interferers.sort_unstable();
+                    interferers.dedup();
+                    (!interferers.is_empty()).then(|| FragmentInterference {
+                        product_mz: mz,
+                        interferers: interferers.iter().map(|&other| precursors[other].precursor_id.clone()).collect(),
+                    })
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut n_flagged = 0;
+    for (precursor, interference) in precursors.iter_mut().zip(shared) {
+        n_flagged += interference.len();
+        precursor.interference = interference;
+    }
+    n_flagged
+}
+
+/// `frag_info` with a fifth `Interference` column: 1 for original-variant
+/// rows whose m/z matches an interfered fragment of the precursor, else 0.
+pub fn with_interference_mask(frag_info: &Array3<f32>, interference: &[FragmentInterference]) -> Array3<f32> {
+    let (batch, rows, cols) = frag_info.dim();
+    let mut masked = Array3::<f32>::zeros((batch, rows, cols + 1));
+    masked.slice_mut(s![.., .., ..cols]).assign(frag_info);
+    for b in 0..batch {
+        for row in 0..rows {
+            let mz = frag_info[[b, row, 0]];
+            if frag_info[[b, row, 2]] == VARIANT_ORIGINAL && interference.iter().any(|f| (f.product_mz - mz).abs() < 1e-4) {
+                masked[[b, row, cols]] = 1.0;
+            }
+        }
+    }
+    masked
+}
+
+/// One line per interfered fragment with the precursors sharing it.
+pub fn write_interference_table(precursors: &[PrecursorLibData], run: &str, path: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "run\tprecursor_id\tdecoy\tproduct_mz\tn_interferers\tinterferers")?;
+    for precursor in precursors {
+        let decoy = precursor.lib_records.first().is_some_and(|r| r.decoy == "1");
+        for fragment in &precursor.interference {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{:.4}\t{}\t{}",
+                run, precursor.precursor_id, decoy as u8, fragment.product_mz, fragment.interferers.len(), fragment.interferers.join(";")
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{IndexedTimsTOFData, TimsTOFData, VARIANT_LIGHT};
+
+    fn finder() -> FastChunkFinder {
+        let empty = || IndexedTimsTOFData::from_timstof_data(TimsTOFData::new());
+        FastChunkFinder::new(vec![((400.0, 425.0), empty()), ((500.0, 525.0), empty())]).unwrap()
+    }
+
+    fn interferers(precursor: &PrecursorLibData) -> Vec<(f32, Vec<&str>)> {
+        precursor
+            .interference
+            .iter()
+            .map(|f| (f.product_mz, f.interferers.iter().map(String::as_str).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn shared_fragments_interfere_only_with_rt_and_im_overlap() {
+        let mut precursors = vec![
+            PrecursorLibData::test_precursor("A", 505.0, 10.0, 1.0, &[300.0, 400.0, 650.0]),
+            PrecursorLibData::test_precursor("B", 510.0, 10.5, 1.02, &[300.005, 700.0]), // 16.7 ppm from A's 300
+            PrecursorLibData::test_precursor("C", 512.0, 15.0, 1.0, &[400.0]),           // too late
+            PrecursorLibData::test_precursor("D", 515.0, 10.0, 1.5, &[650.0]),           // too far in IM
+            PrecursorLibData::test_precursor("E", 410.0, 10.0, 1.0, &[300.0]),           // other window
+            PrecursorLibData::test_precursor("F", 520.0, 10.0, 0.0, &[700.0]),           // unknown IM
+            PrecursorLibData::test_precursor("G", 900.0, 10.0, 1.0, &[300.0]),           // no window
+        ];
+        let n_flagged = annotate_interference(&mut precursors, &finder(), &ExtractionConfig::default(), &InterferenceConfig::default());
+
+        assert_eq!(n_flagged, 4);
+        assert_eq!(interferers(&precursors[0]), [(300.0, vec!["B"])]);
+        assert_eq!(interferers(&precursors[1]), [(300.005, vec!["A"]), (700.0, vec!["F"])]);
+        assert_eq!(interferers(&precursors[5]), [(700.0, vec!["B"])]);
+        assert!([2, 3, 4, 6].iter().all(|&k| precursors[k].interference.is_empty()));
+
+        // Wider windows let C and D overlap with A
+        let wide = InterferenceConfig { rt_window: 10.0, im_window: 1.0, ..InterferenceConfig::default() };
+        annotate_interference(&mut precursors, &finder(), &ExtractionConfig::default(), &wide);
+        assert_eq!(interferers(&precursors[0]), [(300.0, vec!["B"]), (400.0, vec!["C"]), (650.0, vec!["D"])]);
+    }
+
+    #[test]
+    fn mask_flags_original_rows_of_interfered_fragments() {
+        let rows = [(300.0, VARIANT_ORIGINAL), (400.0, VARIANT_ORIGINAL), (300.0, VARIANT_LIGHT)];
+        let frag_info = Array3::from_shape_fn((1, 3, 4), |(_, row, c)| match c {
+            0 => rows[row].0,
+            2 => rows[row].1,
+            _ => 1.0,
+        });
+        let interference = [FragmentInterference { product_mz: 300.0, interferers: vec!["B".to_string()] }];
+        let masked = with_interference_mask(&frag_info, &interference);
+
+        assert_eq!(masked.dim(), (1, 3, 5));
+        assert_eq!(masked.slice(s![.., .., ..4]), frag_info);
+        assert_eq!(masked.slice(s![0, .., 4]).to_vec(), [1.0, 0.0, 0.0]);
+        assert_eq!(with_interference_mask(&frag_info, &[]).slice(s![0, .., 4]).sum(), 0.0);
+    }
+}
@@ -1,29 +1,79 @@
 mod utils;
 mod cache;
 mod processing;
+mod config;
+mod calibration;
+mod candidates;
+mod chemistry;
+mod decoy;
+mod validation;
+mod cycles;
+mod resample;
+mod peaks;
+mod quant;
+mod scores;
+mod rescore;
+mod mass_calibration;
+mod im_calibration;
+mod mobilogram;
+mod spectra;
+mod empirical_library;
+mod interference;
+mod training;
+mod selection;
+mod rt_window;
+mod assay;
+#[cfg(feature = "inference")]
+mod inference;
 
 use cache::CacheManager;
+use config::load_config;
+use cycles::CycleModel;
+use peaks::{PeakGroupResult, write_peak_table};
+use quant::write_quant_tables;
+use scores::write_feature_table;
+use rescore::{rescore_peak_groups, write_rescored_table};
+use im_calibration::{apply_im_calibration, learn_im_calibration, write_im_anchor_table};
+use spectra::write_spectrum_mgf;
+use interference::{annotate_interference, write_interference_table};
+use empirical_library::{consensus_library, select_confident_records, write_library_tsv};
+use mass_calibration::{apply_mz_correction, learn_mz_calibration, write_mass_error_table};
+use calibration::{calibrate_library_rt, select_anchor_candidates};
+use training::{SPLITS, export_training_set, training_dir};
+#[cfg(feature = "inference")]
+use inference::{ScoringModel, check_model, score_peak_groups, write_model_score_table};
+use selection::{precursor_mz_charge, select_precursors};
+use rt_window::{WindowFit, assign_peak_widths, report_peak_widths};
+use assay::read_assay_list;
+use decoy::{add_paired_decoys, append_decoys, pair_decoys_with_targets};
+use validation::{validate_library, report_library_validation};
 use utils::{
     read_timstof_data, build_indexed_data, read_parquet_with_polars,
     library_records_to_dataframe, merge_library_and_report, get_unique_precursor_ids, 
-    process_library_fast, create_rt_im_dicts, build_lib_matrix, build_precursors_matrix_step1, 
+    process_library_fast, create_rt_im_dicts, create_report_value_dict, build_lib_matrix, build_precursors_matrix_step1, 
     build_precursors_matrix_step2, build_range_matrix_step3, build_precursors_matrix_step3, 
-    build_frag_info, LibCols, PrecursorLibData, prepare_precursor_lib_data
+    build_frag_info, LibCols, PrecursorLibData, prepare_precursor_lib_data,
+    get_library_precursor_ids, create_library_rt_im_dicts
 };
 use processing::{
     FastChunkFinder, build_intensity_matrix_optimized, prepare_precursor_features,
     calculate_mz_range, extract_ms2_data, build_mask_matrices, extract_aligned_rt_values,
-    reshape_and_combine_matrices, create_final_dataframe, process_single_precursor
+    reshape_and_combine_matrices, create_final_dataframe, process_single_precursor, RepeatReduction,
 };
 
 use rayon::prelude::*;
-use std::{error::Error, path::Path, time::Instant, env, fs::File};
+use std::{collections::{HashMap, HashSet}, error::Error, path::Path, time::Instant, env, fs::File};
 use ndarray::{Array2, Array3, Array4, s, Axis};
 use polars::prelude::*;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    // Configurable parallel processing parameter
-    let parallel_threads = 16; // Set to 1 for sequential, 2+ for parallel processing
+    // Load configuration from file or use defaults
+    let mut config = load_config()?;
+    #[cfg(not(feature = "inference"))]
+    if config.inference.enabled {
+        return Err("inference.enabled needs a build with the `inference` feature (cargo build --release --features inference).".into());
+    }
+    let parallel_threads = config.processing.parallel_threads; // Set to 1 for sequential, 2+ for parallel processing
     
     // Initialize global thread pool based on parallel_threads setting
     if parallel_threads > 1 {
@@ -63,6 +113,42 @@ fn main() -> Result<(), Box<dyn Error>> {
                 }
                 return Ok(());
             }
+            "library" if args.get(2).map(String::as_str) == Some("validate") => {
+                // library validate [library.tsv]: recompute m/z from sequences
+                let lib_path = args.get(3).cloned()
+                    .or_else(|| config.processing.library_path.clone())
+                    .ok_or("usage: read_bruker_data library validate <library.tsv>")?;
+                let library_records = process_library_fast(&lib_path)?;
+                let validation = validate_library(&library_records);
+                report_library_validation(&validation, &config.validation)?;
+                return Ok(());
+            }
+            "library" if args.get(2).map(String::as_str) == Some("consensus") => {
+                // library consensus <out.tsv> <run1.tsv> <run2.tsv> ...: merge empirical libraries
+                let (Some(out_path), run_paths) = (args.get(3), args.get(4..).unwrap_or_default()) else {
+                    return Err("usage: read_bruker_data library consensus <out.tsv> <run1.tsv> <run2.tsv> ...".into());
+                };
+                if run_paths.is_empty() {
+                    return Err("usage: read_bruker_data library consensus <out.tsv> <run1.tsv> <run2.tsv> ...".into());
+                }
+                let runs = run_paths.iter().map(|path| process_library_fast(path)).collect::<Result<Vec<_>, _>>()?;
+                let consensus = consensus_library(&runs, config.empirical_library.min_run_fraction);
+                write_library_tsv(&consensus, out_path)?;
+                println!("Consensus library of {} runs: {} fragments written to {}", runs.len(), consensus.len(), out_path);
+                return Ok(());
+            }
+            #[cfg(feature = "inference")]
+            "inference" if args.get(2).map(String::as_str) == Some("check") => {
+                // inference check [model.onnx]: score a synthetic batch
+                let model_path = args.get(3).cloned().unwrap_or_else(|| config.inference.model_path.clone());
+                let scores = check_model(&model_path, config.extraction.frag_repeat_num)?;
+                println!("Model {} OK, synthetic batch scores: {:?}", model_path, scores);
+                return Ok(());
+            }
+            #[cfg(not(feature = "inference"))]
+            "inference" => {
+                return Err("inference commands need a build with the `inference` feature (cargo build --release --features inference).".into());
+            }
             _ => {}
         }
     }
@@ -73,7 +159,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     println!("Using macOS paths: {}", is_macos);
     
     // Set file paths based on OS detection
-    let (default_data_folder, lib_file_path, report_file_path) = if is_macos {
+    let (default_data_folder, default_lib_file_path, default_report_file_path) = if is_macos {
         // macOS paths
         (
             "/Users/augustsirius/Desktop/raw_data/CAD20220207yuel_TPHP_DIA_pool1_Slot2-54_1_4382.d".to_string(),
@@ -89,6 +175,10 @@ fn main() -> Result<(), Box<dyn Error>> {
         )
     };
     
+    // Library and report paths can be overridden in config.toml
+    let lib_file_path = config.processing.library_path.as_deref().unwrap_or(default_lib_file_path);
+    let report_file_path = config.processing.report_path.as_deref().unwrap_or(default_report_file_path);
+    
     // Set data folder path (can still be overridden by command line argument)
     let d_folder = args.get(1).cloned().unwrap_or(default_data_folder);
     
@@ -98,8 +188,22 @@ fn main() -> Result<(), Box<dyn Error>> {
     }
     
     println!("Using data folder: {}", d_folder);
-    println!("Using library file: {}", lib_file_path);
-    println!("Using report file: {}", report_file_path);
+    if config.assay_list.enabled {
+        if config.library_only.enabled {
+            return Err("assay_list and library_only are exclusive; enable only one.".into());
+        }
+        if config.decoys.generate || config.decoys.extract {
+            return Err("Assay lists carry no decoys; disable decoys.generate and decoys.extract.".into());
+        }
+        println!("Using assay list: {} (no library or report file used)", config.assay_list.path);
+    } else {
+        println!("Using library file: {}", lib_file_path);
+        if config.library_only.enabled {
+            println!("Library-only mode: no report file used");
+        } else {
+            println!("Using report file: {}", report_file_path);
+        }
+    }
     
     // ================================ DATA LOADING AND INDEXING ================================
     let cache_manager = CacheManager::new();
@@ -107,7 +211,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     println!("\n========== DATA PREPARATION PHASE ==========");
     let total_start = Instant::now();
     
-    let (ms1_indexed, ms2_indexed_pairs) = if cache_manager.is_cache_valid(d_path) {
+    let (mut ms1_indexed, mut ms2_indexed_pairs) = if cache_manager.is_cache_valid(d_path) {
         println!("Found valid cache, loading indexed data directly...");
         let cache_load_start = Instant::now();
         let result = cache_manager.load_indexed_data(d_path)?;
@@ -139,27 +243,145 @@ fn main() -> Result<(), Box<dyn Error>> {
     
     println!("Total data preparation time: {:.5} seconds", total_start.elapsed().as_secs_f32());
     
+    // Cycle-aligned RT axis: snap MS1 and MS2 peaks onto their DIA cycle apex time
+    // (done after caching so the cache keeps the original frame RTs)
+    match config.extraction.rt_axis.as_str() {
+        "frame" => {}
+        "cycle" => {
+            let cycle_model = CycleModel::from_indexed(&ms1_indexed, &ms2_indexed_pairs);
+            cycle_model.align(&mut ms1_indexed);
+            ms2_indexed_pairs.par_iter_mut().for_each(|(_, data)| cycle_model.align(data));
+            println!("Cycle-aligned RT axis: {} cycles, median cycle time {:.3} s",
+                     cycle_model.n_cycles(), cycle_model.median_cycle_time());
+        }
+        other => return Err(format!("Invalid rt_axis: {}. Use frame or cycle.", other).into()),
+    }
+    
     // Create MS2 finder for fast chunk lookup
-    let finder = FastChunkFinder::new(ms2_indexed_pairs)?;
+    let mut finder = FastChunkFinder::new(ms2_indexed_pairs)?;
     
     // ================================ LIBRARY AND REPORT LOADING ================================
     println!("\n========== LIBRARY AND REPORT PROCESSING ==========");
     let lib_processing_start = Instant::now();
     
-    let library_records = process_library_fast(lib_file_path)?;
-    let library_df = library_records_to_dataframe(library_records.clone())?;
+    let (mut library_records, assay_targets) = if config.assay_list.enabled {
+        let assays = read_assay_list(&config.assay_list.path)?;
+        println!("Assay list: {} targets, {} fragments", assays.ids.len(), assays.records.len());
+        (assays.records, Some((assays.ids, assays.rt_dict, assays.im_dict)))
+    } else {
+        (process_library_fast(lib_file_path)?, None)
+    };
+    if config.decoys.generate {
+        library_records = append_decoys(library_records, &config.decoys)?;
+    }
+    let lib_cols = LibCols::default();
     
-    let report_df = read_parquet_with_polars(report_file_path)?;
+    // Set processing parameters
+    let device = "cpu";
     
-    let diann_result = merge_library_and_report(library_df, report_df)?;
-    let diann_precursor_id_all = get_unique_precursor_ids(&diann_result)?;
-    let (assay_rt_kept_dict, assay_im_kept_dict) = create_rt_im_dicts(&diann_precursor_id_all)?;
+    let mut selection_summary = "all (library-only)".to_string();
+    let (mut unique_precursor_ids, mut assay_rt_kept_dict, mut assay_im_kept_dict, mut max_precursors, report_q_values, mut peak_widths) = if let Some((assay_ids, rt_dict, im_dict)) = assay_targets {
+        // Every listed target at its listed RT/IM
+        let n_targets = assay_ids.len();
+        selection_summary = format!("assay list ({}): {} targets", config.assay_list.path, n_targets);
+        (assay_ids, rt_dict, im_dict, n_targets, HashMap::new(), HashMap::new())
+    } else if config.library_only.enabled {
+        // RT/IM come from the library: calibrate iRT on anchors found in a first pass
+        println!("\n[Library-only] Calibrating library iRT against the run");
+        let library_precursor_ids = get_library_precursor_ids(&library_records);
+        let (irt_dict, im_dict) = create_library_rt_im_dicts(&library_records);
+        println!("  - Library precursors: {} ({} with iRT, {} with IM)", 
+                 library_precursor_ids.len(), irt_dict.len(), im_dict.len());
+        
+        let candidate_ids = select_anchor_candidates(
+            &library_precursor_ids, &irt_dict, config.library_only.anchor_candidates,
+        );
+        let candidates = prepare_precursor_lib_data(
+            &library_records, &candidate_ids, &irt_dict, &im_dict, &lib_cols, &config.library, candidate_ids.len(),
+        )?;
+        let rt_dict = calibrate_library_rt(
+            &candidates, &library_precursor_ids, &irt_dict, &ms1_indexed, &finder, &config, device,
+        )?;
+        
+        // 只保留有iRT的precursor，全部提取
+        let precursor_ids: Vec<String> = library_precursor_ids
+            .into_iter()
+            .filter(|id| rt_dict.contains_key(id))
+            .collect();
+        let n_precursors = precursor_ids.len();
+        (precursor_ids, rt_dict, im_dict, n_precursors, HashMap::new(), HashMap::new())
+    } else {
+        let library_df = library_records_to_dataframe(library_records.clone())?;
+        
+        let report_df = read_parquet_with_polars(report_file_path)?;
+        let report_q_values = if config.training.enabled {
+            create_report_value_dict(&report_df, &config.training.q_value_column, f32::min)?
+        } else {
+            HashMap::new()
+        };
+        let report_quantities = if config.selection.strategy == "top_quantity" {
+            create_report_value_dict(&report_df, &config.selection.quantity_column, f32::max)?
+        } else {
+            HashMap::new()
+        };
+        let peak_widths = if config.rt_window.enabled {
+            report_peak_widths(&report_df, &config.rt_window)?
+        } else {
+            HashMap::new()
+        };
+        
+        let diann_result = merge_library_and_report(library_df, report_df)?;
+        let diann_precursor_id_all = get_unique_precursor_ids(&diann_result)?;
+        let (assay_rt_kept_dict, assay_im_kept_dict) = create_rt_im_dicts(&diann_precursor_id_all)?;
+        
+        // 获取unique precursor IDs
+        let unique_precursor_ids: Vec<String> = diann_precursor_id_all
+            .column("transition_group_id")?
+            .str()?
+            .into_iter()
+            .filter_map(|opt| opt.map(|s| s.to_string()))
+            .collect();
+        
+        let selection = select_precursors(
+            &unique_precursor_ids,
+            &assay_rt_kept_dict,
+            &precursor_mz_charge(&library_records),
+            &report_quantities,
+            &config.selection,
+            config.processing.max_precursors,
+        )?;
+        println!("  - Precursor selection: {}", selection.summary);
+        selection_summary = selection.summary;
+        let n_selected = selection.ids.len();
+        
+        (selection.ids, assay_rt_kept_dict, assay_im_kept_dict, n_selected, report_q_values, peak_widths)
+    };
     
-    println!("Library and report processing time: {:.5} seconds", lib_processing_start.elapsed().as_secs_f32());
+    // Library decoys are never reported: extract them at the RT/IM of their paired target
+    // decoy ID -> target ID, also used to keep decoys in their target's training split
+    let decoy_pairs = if config.decoys.extract {
+        let pairs = pair_decoys_with_targets(&library_records, &config.decoys.pairing)?;
+        let n_library_decoys = library_records
+            .iter()
+            .filter(|r| r.decoy == "1")
+            .map(|r| r.transition_group_id.as_str())
+            .collect::<HashSet<_>>()
+            .len();
+        // The selection counts targets; their decoys come on top
+        unique_precursor_ids = add_paired_decoys(&unique_precursor_ids, &pairs, &mut assay_rt_kept_dict, &mut assay_im_kept_dict);
+        for (decoy_id, target_id) in &pairs {
+            if let Some(&width) = peak_widths.get(target_id) {
+                peak_widths.insert(decoy_id.clone(), width);
+            }
+        }
+        max_precursors = unique_precursor_ids.len();
+        println!("  - Decoys paired with targets: {} of {} ({} pairing)", pairs.len(), n_library_decoys, config.decoys.pairing);
+        pairs
+    } else {
+        HashMap::new()
+    };
     
-    // Set processing parameters
-    let device = "cpu";
-    let frag_repeat_num = 5;
+    println!("Library and report processing time: {:.5} seconds", lib_processing_start.elapsed().as_secs_f32());
     
     // ================================ BATCH PRECURSOR PROCESSING ================================
     println!("\n========== BATCH PRECURSOR PROCESSING ==========");
@@ -168,107 +390,330 @@ fn main() -> Result<(), Box<dyn Error>> {
     println!("\n[Step 1] Preparing library data for batch processing");
     let prep_start = Instant::now();
     
-    // 获取unique precursor IDs
-    let unique_precursor_ids: Vec<String> = diann_precursor_id_all
-        .column("transition_group_id")?
-        .str()?
-        .into_iter()
-        .filter_map(|opt| opt.map(|s| s.to_string()))
-        .collect();
-    
-    let lib_cols = LibCols::default();
-    let max_precursors = 8000; // 可以根据需要调整
-    
     // 预先构建所有precursor的library data
-    let precursor_lib_data_list = prepare_precursor_lib_data(
+    let mut precursor_lib_data_list = prepare_precursor_lib_data(
         &library_records,
         &unique_precursor_ids,
         &assay_rt_kept_dict,
         &assay_im_kept_dict,
         &lib_cols,
+        &config.library,
         max_precursors,
     )?;
     
     println!("  - Prepared data for {} precursors", precursor_lib_data_list.len());
+    if config.rt_window.enabled {
+        if WindowFit::from_name(&config.rt_window.fit)? == WindowFit::Pad {
+            println!("  - Warning: fit = \"pad\" keeps at most {} frames per window; wider windows are cropped around the RT",
+                     config.rt_window.n_points);
+        }
+        let n_windows = assign_peak_widths(&mut precursor_lib_data_list, &peak_widths);
+        println!("  - Adaptive RT windows: {} of {} precursors ({} x peak width, {}-{} min, {} to {} points)",
+                 n_windows, precursor_lib_data_list.len(), config.rt_window.multiplier,
+                 config.rt_window.min_width, config.rt_window.max_width, config.rt_window.fit, config.rt_window.n_points);
+    }
     println!("  - Preparation time: {:.5} seconds", prep_start.elapsed().as_secs_f32());
     
     // 释放library_records的内存，因为我们已经不需要它了
     drop(library_records);
     println!("  - Released library_records from memory");
     
+    // Run name = .d folder name, so tables of several runs can be concatenated
+    let run_name = d_path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_else(|| d_folder.clone());
+    let output_dir = config.processing.output_dir.clone();
+    std::fs::create_dir_all(&output_dir)?;
+    
+    // Optional IM calibration pass: shift the expected IMs onto the run and
+    // optionally replace the fixed IM window by per-charge learned tolerances
+    if config.im_calibration.enabled {
+        println!("\n[Step 1a] Learning IM calibration from anchor precursors");
+        let calibration_start = Instant::now();
+        let calibration = learn_im_calibration(&precursor_lib_data_list, &ms1_indexed, &finder, &config, device)?;
+        apply_im_calibration(&calibration, &mut precursor_lib_data_list);
+        println!("  - Anchors: {}", calibration.anchors.len());
+        println!("  - IM model: observed = {:.4} x expected {:+.4}, residual SD {:.4}",
+                 calibration.slope, calibration.intercept, calibration.residual_sd);
+        if config.im_calibration.adaptive_tolerance {
+            let mut charges: Vec<_> = calibration.tolerance_by_charge.iter().collect();
+            charges.sort_by_key(|(charge, _)| **charge);
+            for (charge, tolerance) in charges {
+                println!("  - IM tolerance charge {}: {:.4}", charge, tolerance);
+                config.extraction.im_tolerance_by_charge.insert(charge.to_string(), *tolerance);
+            }
+            config.extraction.im_tolerance_mode = "charge".to_string();
+        }
+        let anchor_path = Path::new(&output_dir).join(&config.im_calibration.output_file);
+        write_im_anchor_table(&calibration, &run_name, &anchor_path.to_string_lossy())?;
+        println!("  - IM calibration time: {:.5} seconds", calibration_start.elapsed().as_secs_f32());
+    }
+    
+    // Shared fragment transitions between co-isolated precursors (after IM calibration,
+    // so the IM overlap uses the calibrated values)
+    if config.interference.enabled {
+        let n_flagged = annotate_interference(&mut precursor_lib_data_list, &finder, &config.extraction, &config.interference);
+        let interference_path = Path::new(&output_dir).join(&config.interference.output_file);
+        write_interference_table(&precursor_lib_data_list, &run_name, &interference_path.to_string_lossy())?;
+        let n_precursors = precursor_lib_data_list.iter().filter(|p| !p.interference.is_empty()).count();
+        println!("\n[Step 1c] Interference: {} fragments of {} precursors shared with co-isolated precursors, written to: {}",
+                 n_flagged, n_precursors, interference_path.display());
+    }
+    
+    // Optional first pass: learn the m/z error from anchors, correct the index
+    // in place and re-extract everything with the tightened tolerances
+    if config.mass_calibration.recalibrate {
+        println!("\n[Step 1b] Learning m/z recalibration from anchor precursors");
+        let calibration_start = Instant::now();
+        let calibration = learn_mz_calibration(&precursor_lib_data_list, &ms1_indexed, &finder, &config, device)?;
+        apply_mz_correction(&calibration.ms1, &mut ms1_indexed);
+        finder.chunks_mut().par_iter_mut().for_each(|chunk| apply_mz_correction(&calibration.ms2, chunk));
+        println!("  - Anchors: {}", calibration.n_anchors);
+        println!("  - MS1 residual SD: {:.2} ppm, tolerance {:.1} -> {:.1} ppm",
+                 calibration.ms1_sd, config.extraction.ms1_tolerance, calibration.ms1_tolerance);
+        println!("  - MS2 residual SD: {:.2} ppm, tolerance {:.1} -> {:.1} ppm",
+                 calibration.ms2_sd, config.extraction.ms2_tolerance, calibration.ms2_tolerance);
+        config.extraction.ms1_tolerance = calibration.ms1_tolerance;
+        config.extraction.ms2_tolerance = calibration.ms2_tolerance;
+        println!("  - Recalibration time: {:.5} seconds", calibration_start.elapsed().as_secs_f32());
+    }
+    
+    // Labelled tensors for DIA-BERT training (report q-values as ground truth)
+    if config.training.enabled {
+        if config.library_only.enabled || config.assay_list.enabled {
+            return Err("Training export needs report q-values; disable library_only and assay_list.".into());
+        }
+        println!("\n[Step 1d] Exporting labelled training set");
+        let training_start = Instant::now();
+        let training_dir = training_dir(&config);
+        let summary = export_training_set(
+            &precursor_lib_data_list, &report_q_values, &decoy_pairs, &ms1_indexed, &finder, &config, device,
+        )?;
+        if summary.n_decoys == 0 {
+            println!("  - Warning: no decoy negatives (set decoys.extract = true)");
+        }
+        println!("  - Positives: {}, decoys: {}, RT-shifted negatives: {}",
+                 summary.n_positives, summary.n_decoys, summary.n_rt_shifted);
+        for (name, n) in SPLITS.iter().zip(summary.n_per_split) {
+            println!("  - {}: {} samples", name, n);
+        }
+        println!("  - Written to: {}", training_dir.display());
+        println!("  - Export time: {:.5} seconds", training_start.elapsed().as_secs_f32());
+    }
+    
     // Step 2: Process each precursor sequentially (可以后续改为并行)
     println!("\n[Step 2] Processing individual precursors");
     
-    // 创建输出目录
-    let output_dir = "output_precursors";
-    std::fs::create_dir_all(output_dir)?;
-    
-    let device = "cpu";
-    let frag_repeat_num = 5;
+    let output_dir = output_dir.as_str();
+    
+    // Settings needed to read the tables and dataframes of this run (after
+    // calibration, so the final tolerances are recorded)
+    let repeat_reduction = RepeatReduction::from_name(&config.processing.repeat_reduction)?;
+    let metadata = serde_json::json!({
+        "run": run_name,
+        "frag_repeat_num": config.extraction.frag_repeat_num,
+        "selection": selection_summary,
+        "repeat_reduction": repeat_reduction.name(),
+        "dataframe_rows": if repeat_reduction == RepeatReduction::Full { "fragment x repeat" } else { "fragment" },
+        "rt_axis": config.extraction.rt_axis,
+        "mz_unit": config.extraction.mz_unit,
+        "ms1_tolerance": config.extraction.ms1_tolerance,
+        "ms2_tolerance": config.extraction.ms2_tolerance,
+        "im_tolerance_mode": config.extraction.im_tolerance_mode,
+        "im_tolerance": config.extraction.im_tolerance,
+        "rt_window": if config.rt_window.enabled && !config.candidates.enabled {
+            serde_json::json!({
+                "multiplier": config.rt_window.multiplier,
+                "min_width": config.rt_window.min_width,
+                "max_width": config.rt_window.max_width,
+                "n_points": config.rt_window.n_points,
+                "fit": config.rt_window.fit,
+            })
+        } else {
+            serde_json::Value::Null
+        },
+    });
+    let metadata_path = Path::new(output_dir).join(&config.processing.metadata_file);
+    std::fs::write(&metadata_path, serde_json::to_string_pretty(&metadata)?)?;
+    println!("  - Run metadata (repeat reduction: {}) written to: {}", repeat_reduction.name(), metadata_path.display());
+    
+    // Load the scoring model before extraction so a bad model fails fast
+    #[cfg(feature = "inference")]
+    let scoring_model = if config.inference.enabled {
+        println!("Loading scoring model: {}", config.inference.model_path);
+        Some(ScoringModel::load(&config.inference.model_path)?)
+    } else {
+        None
+    };
 
     let batch_start = Instant::now();
     
-    // Process precursors based on parallel_threads setting
+    // With a scoring model, precursors are extracted and scored chunk by chunk
+    // so only one chunk of model inputs is held in memory
+    #[cfg(feature = "inference")]
+    let chunk_size = if scoring_model.is_some() { config.inference.batch_size.max(1) } else { usize::MAX };
+    #[cfg(not(feature = "inference"))]
+    let chunk_size = usize::MAX;
+    #[cfg(feature = "inference")]
+    let mut n_model_scored = 0;
+    
+    // Use atomic counter for progress tracking in parallel mode
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let processed_count = AtomicUsize::new(0);
+    let total_count = precursor_lib_data_list.len();
     if parallel_threads == 1 {
-        // Sequential processing
         println!("Processing precursors sequentially...");
-        for (idx, precursor_data) in precursor_lib_data_list.iter().enumerate() {
-            println!("\n--- Processing precursor {}/{} ---", idx + 1, precursor_lib_data_list.len());
-            
-            match process_single_precursor(
-                precursor_data,
-                &ms1_indexed,
-                &finder,
-                frag_repeat_num,
-                device,
-                output_dir,
-            ) {
-                Ok(_) => {
-                    println!("✓ Successfully processed: {}", precursor_data.precursor_id);
-                },
-                Err(e) => {
-                    eprintln!("✗ Error processing {}: {}", precursor_data.precursor_id, e);
-                }
-            }
-        }
     } else {
-        // Parallel processing
         println!("Processing precursors in parallel with {} threads...", parallel_threads);
-        
-        // Use atomic counter for progress tracking in parallel mode
-        use std::sync::atomic::{AtomicUsize, Ordering};
-        let processed_count = AtomicUsize::new(0);
-        let total_count = precursor_lib_data_list.len();
-        
-        // Process in parallel using rayon
-        precursor_lib_data_list.par_iter().for_each(|precursor_data| {
-            let result = process_single_precursor(
-                precursor_data,
-                &ms1_indexed,
-                &finder,
-                frag_repeat_num,
-                device,
-                output_dir,
-            );
-            
-            // Update progress counter
-            let current = processed_count.fetch_add(1, Ordering::SeqCst) + 1;
-            
-            match result {
-                Ok(_) => {
-                    println!("[{}/{}] ✓ Successfully processed: {}", 
-                             current, total_count, precursor_data.precursor_id);
-                },
-                Err(e) => {
-                    eprintln!("[{}/{}] ✗ Error processing {}: {}", 
-                              current, total_count, precursor_data.precursor_id, e);
+    }
+    
+    let mut peak_groups: Vec<PeakGroupResult> = Vec::new();
+    for chunk in precursor_lib_data_list.chunks(chunk_size) {
+        // Process precursors based on parallel_threads setting
+        let chunk_groups: Vec<PeakGroupResult> = if parallel_threads == 1 {
+            // Sequential processing
+            let mut chunk_groups = Vec::new();
+            for precursor_data in chunk {
+                let current = processed_count.fetch_add(1, Ordering::SeqCst) + 1;
+                println!("\n--- Processing precursor {}/{} ---", current, total_count);
+                
+                match process_single_precursor(
+                    precursor_data,
+                    &ms1_indexed,
+                    &finder,
+                    &config,
+                    repeat_reduction,
+                    device,
+                    output_dir,
+                ) {
+                    Ok(groups) => {
+                        println!("✓ Successfully processed: {}", precursor_data.precursor_id);
+                        chunk_groups.extend(groups);
+                    },
+                    Err(e) => {
+                        eprintln!("✗ Error processing {}: {}", precursor_data.precursor_id, e);
+                    }
                 }
             }
-        });
+            chunk_groups
+        } else {
+            // Parallel processing using rayon
+            chunk.par_iter().flat_map_iter(|precursor_data| {
+                let result = process_single_precursor(
+                    precursor_data,
+                    &ms1_indexed,
+                    &finder,
+                    &config,
+                    repeat_reduction,
+                    device,
+                    output_dir,
+                );
+                
+                // Update progress counter
+                let current = processed_count.fetch_add(1, Ordering::SeqCst) + 1;
+                
+                match result {
+                    Ok(groups) => {
+                        println!("[{}/{}] ✓ Successfully processed: {}", 
+                                 current, total_count, precursor_data.precursor_id);
+                        groups
+                    },
+                    Err(e) => {
+                        eprintln!("[{}/{}] ✗ Error processing {}: {}", 
+                                  current, total_count, precursor_data.precursor_id, e);
+                        Vec::new()
+                    }
+                }
+            }).collect()
+        };
+        
+        #[cfg(feature = "inference")]
+        let mut chunk_groups = chunk_groups;
+        #[cfg(feature = "inference")]
+        if let Some(model) = &scoring_model {
+            n_model_scored += score_peak_groups(&mut chunk_groups, model, config.inference.batch_size)?;
+        }
+        peak_groups.extend(chunk_groups);
+    }
+    
+    if config.peaks.enabled {
+        let peak_path = Path::new(output_dir).join(&config.peaks.output_file);
+        write_peak_table(&peak_groups, &peak_path.to_string_lossy())?;
+        let n_picked = peak_groups.iter().filter(|g| g.peak.is_some()).count();
+        println!("Peaks picked for {}/{} peak groups, written to: {}", n_picked, peak_groups.len(), peak_path.display());
+    }
+    
+    if config.quant.enabled {
+        let quant_path = Path::new(output_dir).join(&config.quant.output_file);
+        let fragment_quant_path = Path::new(output_dir).join(&config.quant.fragment_output_file);
+        write_quant_tables(&peak_groups, &run_name, &quant_path.to_string_lossy(), &fragment_quant_path.to_string_lossy())?;
+        let n_quantified = peak_groups.iter().filter(|g| g.quant.is_some()).count();
+        println!("Quantified {}/{} peak groups, written to: {}", n_quantified, peak_groups.len(), quant_path.display());
+    }
+    
+    if config.features.enabled {
+        let feature_path = Path::new(output_dir).join(&config.features.output_file);
+        write_feature_table(&peak_groups, &run_name, &feature_path.to_string_lossy())?;
+        let n_scored = peak_groups.iter().filter(|g| g.scores.is_some()).count();
+        println!("Scored {}/{} peak groups, written to: {}", n_scored, peak_groups.len(), feature_path.display());
+    }
+    
+    if config.mass_calibration.report_fragment_errors {
+        let mass_error_path = Path::new(output_dir).join(&config.mass_calibration.output_file);
+        write_mass_error_table(&peak_groups, &run_name, &mass_error_path.to_string_lossy())?;
+        let n_fragments: usize = peak_groups.iter().map(|g| g.mass_errors.len()).sum();
+        println!("Mass errors of {} fragments written to: {}", n_fragments, mass_error_path.display());
+    }
+    
+    if config.spectra.enabled {
+        let spectrum_path = Path::new(output_dir).join(&config.spectra.output_file);
+        write_spectrum_mgf(&peak_groups, &run_name, &spectrum_path.to_string_lossy())?;
+        let n_spectra = peak_groups.iter().filter(|g| g.spectrum.is_some()).count();
+        println!("Apex spectra of {} peak groups written to: {}", n_spectra, spectrum_path.display());
+    }
+    
+    #[cfg(feature = "inference")]
+    if scoring_model.is_some() {
+        let score_path = Path::new(output_dir).join(&config.inference.output_file);
+        write_model_score_table(&peak_groups, &run_name, &score_path.to_string_lossy())?;
+        println!("Model scores of {}/{} peak groups written to: {}", n_model_scored, peak_groups.len(), score_path.display());
+    }
+    
+    let rescoring_summary = if config.rescoring.enabled {
+        println!("\n========== RESCORING ==========");
+        let summary = rescore_peak_groups(&peak_groups, &config.rescoring)?;
+        let rescored_path = Path::new(output_dir).join(&config.rescoring.output_file);
+        write_rescored_table(&peak_groups, &summary, &run_name, &rescored_path.to_string_lossy())?;
+        
+        let is_target = |p: &&rescore::RescoredPrecursor| !peak_groups[p.result_index].decoy;
+        let n_precursors = summary.precursors.iter().filter(is_target).filter(|p| p.q_value <= 0.01).count();
+        let mut proteins: Vec<&str> = summary.precursors.iter()
+            .filter(is_target)
+            .filter(|p| p.protein_q_value <= 0.01)
+            .map(|p| peak_groups[p.result_index].protein_id.as_str())
+            .collect();
+        proteins.sort_unstable();
+        proteins.dedup();
+        println!("  - pi0: {:.3} (precursor), {:.3} (protein)", summary.pi0, summary.protein_pi0);
+        println!("  - Precursors at 1% FDR: {}", n_precursors);
+        println!("  - Proteins at 1% FDR: {}", proteins.len());
+        println!("Rescored precursors written to: {}", rescored_path.display());
+        Some(summary)
+    } else {
+        None
+    };
+    
+    if config.empirical_library.enabled {
+        let records = select_confident_records(&peak_groups, rescoring_summary.as_ref(), &config.empirical_library);
+        let library_path = Path::new(output_dir).join(&config.empirical_library.output_file);
+        write_library_tsv(&records, &library_path.to_string_lossy())?;
+        let mut precursor_ids: Vec<&str> = records.iter().map(|r| r.transition_group_id.as_str()).collect();
+        precursor_ids.dedup();
+        println!("Empirical library: {} precursors, {} fragments written to: {}",
+                 precursor_ids.len(), records.len(), library_path.display());
     }
     
     let batch_elapsed = batch_start.elapsed();
     println!("\n========== BATCH PROCESSING SUMMARY ==========");
+    println!("Precursor selection: {}", selection_summary);
     println!("Processing mode: {}", if parallel_threads == 1 { "Sequential".to_string() } else { format!("Parallel ({} threads)", parallel_threads) });
     println!("Total batch processing time: {:.5} seconds", batch_elapsed.as_secs_f32());
     println!("Average time per precursor: {:.5} seconds", 
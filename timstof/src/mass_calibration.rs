@@ -0,0 +1,309 @@
+// File: src/mass_calibration.rs
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use rayon::prelude::*;
+
+use crate::calibration::median;
+use crate::config::{Config, ExtractionConfig, MassCalibrationConfig};
+use crate::peaks::{ChromPeak, PeakGroupResult, pick_peak, summed_fragment_trace};
+use crate::processing::{ExtractedPrecursor, FastChunkFinder, RtSelection, extract_precursor};
+use crate::rescore::solve;
+use crate::scores::{matched_peak_stats, mz_tolerance, score_peak_group};
+use crate::utils::{IndexedTimsTOFData, PrecursorLibData, VARIANT_ORIGINAL};
+
+/// Observed (intensity-weighted) m/z of one library fragment.
+#[derive(Debug, Clone)]
+pub struct FragmentMassError {
+    pub product_mz: f32,
+    pub fragment_type: f32,
+    pub observed_mz: f32,
+    pub ppm: f32,
+    pub intensity: f32, // summed intensity of the matched peaks
+}
+
+/// Match every original-variant MS2 fragment against the raw peaks inside
+/// `[rt_min, rt_max]`; fragments without a matching peak are left out.
+pub fn measure_fragment_mass_errors(
+    extracted: &ExtractedPrecursor,
+    rt_min: f32,
+    rt_max: f32,
+    extraction: &ExtractionConfig,
+) -> Vec<FragmentMassError> {
+    let frag_info = &extracted.frag_info;
+    (0..frag_info.shape()[1])
+        .filter(|&row| frag_info[[0, row, 2]] == VARIANT_ORIGINAL && frag_info[[0, row, 0]] > 0.0)
+        .filter_map(|row| {
+            let mz = frag_info[[0, row, 0]];
+            let tolerance = mz_tolerance(mz, extraction.ms2_tolerance, &extraction.mz_unit);
+            let (ppm, _, intensity) = matched_peak_stats(&extracted.ms2_peaks, mz, tolerance, rt_min, rt_max)?;
+            Some(FragmentMassError {
+                product_mz: mz,
+                fragment_type: frag_info[[0, row, 3]],
+                observed_mz: mz * (1.0 + ppm * 1e-6),
+                ppm,
+                intensity,
+            })
+        })
+        .collect()
+}
+
+/// Systematic m/z error as a plane over RT and m/z: ppm = c0 + c1 * rt + c2 * mz.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MzErrorModel {
+    pub coefficients: [f64; 3],
+}
+
+impl MzErrorModel {
+    pub fn predict(&self, rt: f32, mz: f32) -> f32 {
+        let [c0, c1, c2] = self.coefficients;
+        (c0 + c1 * rt as f64 + c2 * mz as f64) as f32
+    }
+
+    fn least_squares(points: &[(f32, f32, f32)]) -> Option<Self> {
+        let mut ata = vec![vec![0.0f64; 3]; 3];
+        let mut atb = vec![0.0f64; 3];
+        for &(rt, mz, ppm) in points {
+            let row = [1.0, rt as f64, mz as f64];
+            for ((ata_row, atb_value), &ri) in ata.iter_mut().zip(atb.iter_mut()).zip(&row) {
+                for (a, &rj) in ata_row.iter_mut().zip(&row) {
+                    *a += ri * rj;
+                }
+                *atb_value += ri * ppm as f64;
+            }
+        }
+        let solution = solve(ata, atb)?;
+        Some(Self { coefficients: [solution[0], solution[1], solution[2]] })
+    }
+
+    /// Fit with one round of outlier trimming (|residual| > 3 robust SD).
+    /// Returns the model and the robust SD of the remaining residuals (ppm).
+    /// Falls back to a constant median offset when there are too few points.
+    pub fn fit(points: &[(f32, f32, f32)]) -> (Self, f32) {
+        let offset_only = |pts: &[(f32, f32, f32)]| {
+            let model = Self { coefficients: [median(pts.iter().map(|p| p.2).collect()) as f64, 0.0, 0.0] };
+            (model, robust_sd(pts, &model))
+        };
+        if points.len() < 20 {
+            return offset_only(points);
+        }
+        let Some(model) = Self::least_squares(points) else { return offset_only(points) };
+        let sd = robust_sd(points, &model);
+        let kept: Vec<(f32, f32, f32)> = points
+            .iter()
+            .copied()
+            .filter(|&(rt, mz, ppm)| (ppm - model.predict(rt, mz)).abs() <= 3.0 * sd.max(0.1))
+            .collect();
+        match Self::least_squares(&kept) {
+            Some(refit) => (refit, robust_sd(&kept, &refit)),
+            None => (model, sd),
+        }
+    }
+}
+
+/// 1.4826 x median absolute residual.
+fn robust_sd(points: &[(f32, f32, f32)], model: &MzErrorModel) -> f32 {
+    let residuals: Vec<f32> = points.iter().map(|&(rt, mz, ppm)| (ppm - model.predict(rt, mz)).abs()).collect();
+    1.4826 * median(residuals)
+}
+
+/// Learned m/z correction and the tighter tolerances for the second pass.
+#[derive(Debug, Clone)]
+pub struct MzCalibration {
+    pub ms1: MzErrorModel,
+    pub ms2: MzErrorModel,
+    pub ms1_tolerance: f32,
+    pub ms2_tolerance: f32,
+    pub n_anchors: usize,
+    pub ms1_sd: f32,
+    pub ms2_sd: f32,
+}
+
+/// Which precursors of a first pass count as calibration anchors.
+pub struct AnchorSelection {
+    pub n_precursors: usize, // first N targets are extracted
+    pub fraction: f32,       // best fraction by library dot product is kept
+    pub min_fragments: usize,
+    pub require_im: bool,    // skip precursors without a library/report IM
+}
+
+/// Extract the first `n_precursors` targets at their expected RT, pick and
+/// score their peak groups and return `measure` of the best `fraction`
+/// (by library dot product) among those with enough fragments at the apex.
+pub fn collect_anchors<T, F>(
+    precursors: &[PrecursorLibData],
+    ms1_indexed: &IndexedTimsTOFData,
+    finder: &FastChunkFinder,
+    config: &Config,
+    selection: &AnchorSelection,
+    device: &str,
+    measure: F,
+) -> Vec<T>
+where
+    T: Send,
+    F: Fn(&PrecursorLibData, &ExtractedPrecursor, &ChromPeak) -> Option<T> + Sync,
+{
+    let extraction = &config.extraction;
+    let targets: Vec<&PrecursorLibData> = precursors
+        .iter()
+        .filter(|p| p.lib_records.first().is_some_and(|r| r.decoy != "1"))
+        .filter(|p| !selection.require_im || p.im > 0.0)
+        .take(selection.n_precursors)
+        .collect();
+    let mut candidates: Vec<(f32, T)> = targets
+        .par_iter()
+        .filter_map(|&precursor| {
+            let extracted = extract_precursor(precursor, ms1_indexed, finder, extraction, RtSelection::Centered, device).ok()?;
+            let trace = summed_fragment_trace(&extracted.rsm_matrix, &extracted.frag_info);
+            let peak = pick_peak(&extracted.all_rt, &trace, &extracted.ms2_columns, precursor.rt, &config.peaks).ok()??;
+            let scores = score_peak_group(&extracted, &peak, precursor.precursor_info[1], precursor.rt, precursor.im, extraction);
+            if (scores.n_fragments as usize) < selection.min_fragments {
+                return None;
+            }
+            Some((scores.library_dotprod, measure(precursor, &extracted, &peak)?))
+        })
+        .collect();
+
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let n_anchors = ((candidates.len() as f32 * selection.fraction).ceil() as usize).min(candidates.len());
+    candidates.truncate(n_anchors);
+    candidates.into_iter().map(|(_, value)| value).collect()
+}
+
+/// First pass: fit the MS1 and MS2 m/z error models on the matched peaks
+/// of the anchor peak groups.
+pub fn learn_mz_calibration(
+    precursors: &[PrecursorLibData],
+    ms1_indexed: &IndexedTimsTOFData,
+    finder: &FastChunkFinder,
+    config: &Config,
+    device: &str,
+) -> Result<MzCalibration, Box<dyn Error>> {
+    let mass_cfg = &config.mass_calibration;
+    let extraction = &config.extraction;
+    if extraction.mz_unit != "ppm" {
+        return Err("m/z recalibration requires mz_unit = \"ppm\"".into());
+    }
+
+    let selection = AnchorSelection {
+        n_precursors: mass_cfg.anchor_precursors,
+        fraction: mass_cfg.anchor_fraction,
+        min_fragments: mass_cfg.min_fragments,
+        require_im: false,
+    };
+    // (MS1 points, MS2 points) of (apex RT, m/z, ppm) per anchor
+    let anchors = collect_anchors(precursors, ms1_indexed, finder, config, &selection, device, |precursor, extracted, peak| {
+        let ms2_points: Vec<(f32, f32, f32)> = measure_fragment_mass_errors(extracted, peak.left_rt, peak.right_rt, extraction)
+            .into_iter()
+            .map(|e| (peak.apex_rt, e.product_mz, e.ppm))
+            .collect();
+        // Monoisotopic m/z: MS1 row 0 is the heaviest isotope kept
+        let precursor_mz = precursor.precursor_info[1];
+        let ms1_tolerance = mz_tolerance(precursor_mz, extraction.ms1_tolerance, &extraction.mz_unit);
+        let ms1_points: Vec<(f32, f32, f32)> = matched_peak_stats(&extracted.ms1_peaks, precursor_mz, ms1_tolerance, peak.left_rt, peak.right_rt)
+            .map(|(ppm, _, _)| vec![(peak.apex_rt, precursor_mz, ppm)])
+            .unwrap_or_default();
+        Some((ms1_points, ms2_points))
+    });
+    let n_anchors = anchors.len();
+    if n_anchors < mass_cfg.min_anchors {
+        return Err(format!("only {} m/z calibration anchors found (need {})", n_anchors, mass_cfg.min_anchors).into());
+    }
+    let ms1_points: Vec<(f32, f32, f32)> = anchors.iter().flat_map(|a| a.0.iter().copied()).collect();
+    let ms2_points: Vec<(f32, f32, f32)> = anchors.iter().flat_map(|a| a.1.iter().copied()).collect();
+
+    let (ms1, ms1_tolerance, ms1_sd) = fit_level(&ms1_points, extraction.ms1_tolerance, mass_cfg, "MS1");
+    let (ms2, ms2_tolerance, ms2_sd) = fit_level(&ms2_points, extraction.ms2_tolerance, mass_cfg, "MS2");
+
+    Ok(MzCalibration { ms1, ms2, ms1_tolerance, ms2_tolerance, n_anchors, ms1_sd, ms2_sd })
+}
+
+/// Error model, tolerance and residual SD of one MS level. With fewer than
+/// `min_anchors` points the SD is meaningless: keep the configured
+/// tolerance and do not correct (SD reported as NaN).
+fn fit_level(points: &[(f32, f32, f32)], current: f32, mass_cfg: &MassCalibrationConfig, level: &str) -> (MzErrorModel, f32, f32) {
+    if points.len() < mass_cfg.min_anchors.max(2) {
+        println!(
+            "  - Warning: only {} {} m/z calibration points (need {}), keeping {:.1} ppm without correction",
+            points.len(), level, mass_cfg.min_anchors, current
+        );
+        return (MzErrorModel::default(), current, f32::NAN);
+    }
+    let (model, sd) = MzErrorModel::fit(points);
+    // Never widen the window; never go below the configured floor
+    let tolerance = (sd * mass_cfg.tolerance_sd_multiplier).max(mass_cfg.min_tolerance_ppm).min(current);
+    (model, tolerance, sd)
+}
+
+/// Remove the predicted error from every peak of `data` (index re-sorted by m/z).
+pub fn apply_mz_correction(model: &MzErrorModel, data: &mut IndexedTimsTOFData) {
+    data.recalibrate_mz(|mz, rt| mz / (1.0 + model.predict(rt, mz) * 1e-6));
+}
+
+/// One line per matched fragment: library vs. observed m/z and ppm error.
+pub fn write_mass_error_table(results: &[PeakGroupResult], run: &str, path: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "run\tprecursor_id\tdecoy\tcandidate\tproduct_mz\tfragment_type\tobserved_mz\tppm\tintensity")?;
+    for result in results {
+        let candidate = result.candidate_rank.map(|r| r.to_string()).unwrap_or_default();
+        for e in &result.mass_errors {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{:.5}\t{}\t{:.5}\t{:.3}\t{:.1}",
+                run, result.precursor_id, result.decoy as u8, candidate, e.product_mz, e.fragment_type, e.observed_mz, e.ppm, e.intensity
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ppm = 2 + 0.1 rt - 0.005 mz on a 6 x 5 grid, plus two gross outliers
+    fn plane_points() -> Vec<(f32, f32, f32)> {
+        let mut points: Vec<(f32, f32, f32)> = (0..30)
+            .map(|k| {
+                let (rt, mz) = (5.0 * (k / 5) as f32, 300.0 + 200.0 * (k % 5) as f32);
+                (rt, mz, 2.0 + 0.1 * rt - 0.005 * mz)
+            })
+            .collect();
+        points.push((10.0, 700.0, 40.0));
+        points.push((20.0, 500.0, -40.0));
+        points
+    }
+
+    #[test]
+    fn fit_recovers_the_error_plane_despite_outliers() {
+        let (model, sd) = MzErrorModel::fit(&plane_points());
+        let expected = [2.0, 0.1, -0.005];
+        for (c, e) in model.coefficients.iter().zip(expected) {
+            assert!((c - e).abs() < 1e-3, "{:?}", model.coefficients);
+        }
+        assert!(sd < 1e-3);
+        assert!((model.predict(12.0, 900.0) - (2.0 + 1.2 - 4.5)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn few_points_fit_a_median_offset() {
+        let (model, sd) = MzErrorModel::fit(&[(1.0, 400.0, 3.0), (2.0, 800.0, 5.0), (3.0, 600.0, 4.0)]);
+        assert_eq!(model.coefficients, [4.0, 0.0, 0.0]);
+        assert!((sd - 1.4826).abs() < 1e-4);
+    }
+
+    #[test]
+    fn levels_with_too_few_points_keep_their_tolerance() {
+        let mass_cfg = MassCalibrationConfig::default();
+        let (model, tolerance, sd) = fit_level(&[(1.0, 500.0, 3.0)], 20.0, &mass_cfg, "MS1");
+        assert_eq!((model.coefficients, tolerance), ([0.0; 3], 20.0));
+        assert!(sd.is_nan());
+        let (_, tolerance, _) = fit_level(&[], 20.0, &mass_cfg, "MS1");
+        assert_eq!(tolerance, 20.0);
+
+        // A clean plane tightens the window down to the floor, never above the current one
+        let (model, tolerance, _) = fit_level(&plane_points(), 20.0, &mass_cfg, "MS2");
+        assert!((model.coefficients[0] - 2.0).abs() < 1e-3);
+        assert_eq!(tolerance, mass_cfg.min_tolerance_ppm);
+    }
+}
@@ -0,0 +1,199 @@
+// File: src/mobilogram.rs
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use ndarray::{Array2, Array3};
+use polars::prelude::*;
+
+use crate::config::{ExtractionConfig, MobilogramConfig};
+use crate::processing::ExtractedPrecursor;
+use crate::scores::mz_tolerance;
+use crate::utils::{MS1_ISOTOPE_COUNT, TimsTOFData};
+
+/// Fragment x IM-bin intensities of one extraction. IM bins split the scan
+/// range of the extracted peaks into `n_bins` equal scan intervals; rows
+/// follow `frag_info` (MS1 isotopes from the MS1 peaks, the rest from MS2).
+pub struct Mobilogram {
+    pub scan_min: u32,
+    pub bin_width: f32,       // scans per bin
+    pub bin_im: Vec<f32>,     // intensity-weighted mobility per bin, 0 when empty
+    pub apex: Array2<f32>,    // (rows, n_bins), summed over the apex RT window
+    pub tensor: Option<Array3<f32>>, // (rows, n_rt, n_bins)
+}
+
+/// Column of `all_rt` nearest to `rt`; `None` outside the view's RT span.
+fn rt_column(all_rt: &[f32], columns: &[usize], rt: f32) -> Option<usize> {
+    let (&first, &last) = (columns.first()?, columns.last()?);
+    let half_step = if columns.len() > 1 { (all_rt[last] - all_rt[first]) / (columns.len() - 1) as f32 / 2.0 } else { 0.0 };
+    if rt < all_rt[first] - half_step || rt > all_rt[last] + half_step {
+        return None;
+    }
+    columns.iter().copied().min_by(|&a, &b| (all_rt[a] - rt).abs().total_cmp(&(all_rt[b] - rt).abs()))
+}
+
+/// Build the apex mobilogram (and optionally the full RT x IM tensor) of an
+/// extraction. `apex_idx` is a column of `extracted.all_rt`.
+pub fn build_mobilogram(
+    extracted: &ExtractedPrecursor,
+    apex_idx: usize,
+    extraction: &ExtractionConfig,
+    config: &MobilogramConfig,
+) -> Mobilogram {
+    let frag_info = &extracted.frag_info;
+    let n_rows = frag_info.shape()[1];
+    let n_rt = extracted.all_rt.len();
+    let n_bins = config.n_bins.max(1);
+    let columns: Vec<usize> = (0..n_rt).filter(|&k| extracted.all_rt[k] > 0.0).collect();
+
+    let scans = extracted.ms1_peaks.scan_indices.iter().chain(&extracted.ms2_peaks.scan_indices);
+    let (scan_min, scan_max) = scans.fold((u32::MAX, 0u32), |(lo, hi), &s| (lo.min(s), hi.max(s)));
+    let scan_min = scan_min.min(scan_max);
+    let bin_width = ((scan_max - scan_min + 1) as f32 / n_bins as f32).max(1.0);
+    let bin_of = |scan: u32| (((scan - scan_min) as f32 / bin_width) as usize).min(n_bins - 1);
+
+    let apex_lo = apex_idx.saturating_sub(config.apex_half_width);
+    let apex_hi = (apex_idx + config.apex_half_width).min(n_rt.saturating_sub(1));
+
+    let mut apex = Array2::<f32>::zeros((n_rows, n_bins));
+    let mut tensor = config.full_tensor.then(|| Array3::<f32>::zeros((n_rows, n_rt, n_bins)));
+    let mut im_weight = vec![0.0f64; n_bins];
+    let mut im_sum = vec![0.0f64; n_bins];
+
+    let mut add_peaks = |row: usize, peaks: &TimsTOFData, tolerance_ppm: f32| {
+        let mz = frag_info[[0, row, 0]];
+        if mz <= 0.0 {
+            return;
+        }
+        let tolerance = mz_tolerance(mz, tolerance_ppm, &extraction.mz_unit);
+        for k in 0..peaks.mz_values.len() {
+            if (peaks.mz_values[k] - mz).abs() > tolerance {
+                continue;
+            }
+            let Some(col) = rt_column(&extracted.all_rt, &columns, peaks.rt_values_min[k]) else { continue };
+            let bin = bin_of(peaks.scan_indices[k]);
+            let intensity = peaks.intensity_values[k] as f32;
+            if let Some(tensor) = tensor.as_mut() {
+                tensor[[row, col, bin]] += intensity;
+            }
+            if col >= apex_lo && col <= apex_hi {
+                apex[[row, bin]] += intensity;
+                im_weight[bin] += intensity as f64;
+                im_sum[bin] += intensity as f64 * peaks.mobility_values[k] as f64;
+            }
+        }
+    };
+    for row in 0..n_rows {
+        if row < MS1_ISOTOPE_COUNT {
+            add_peaks(row, &extracted.ms1_peaks, extraction.ms1_tolerance);
+        } else {
+            add_peaks(row, &extracted.ms2_peaks, extraction.ms2_tolerance);
+        }
+    }
+
+    let bin_im = im_sum.iter().zip(&im_weight).map(|(s, w)| if *w > 0.0 { (s / w) as f32 } else { 0.0 }).collect();
+    Mobilogram { scan_min, bin_width, bin_im, apex, tensor }
+}
+
+/// Append the apex mobilogram as `IM_<bin>` columns to a per-precursor
+/// dataframe; with repeat-resolved rows each fragment's values are repeated.
+pub fn append_mobilogram_columns(df: &mut DataFrame, mobilogram: &Mobilogram) -> Result<(), Box<dyn Error>> {
+    let repeats = (df.height() / mobilogram.apex.nrows().max(1)).max(1);
+    for (bin, column) in mobilogram.apex.columns().into_iter().enumerate() {
+        let values: Vec<f32> = column.iter().flat_map(|&v| std::iter::repeat_n(v, repeats)).collect();
+        df.with_column(Series::new(&format!("IM_{}", bin), values))?;
+    }
+    Ok(())
+}
+
+/// Long-format RT x IM tensor (non-zero cells only), next to the per-precursor dataframe.
+pub fn write_mobility_tensor(mobilogram: &Mobilogram, all_rt: &[f32], path: &str) -> Result<(), Box<dyn Error>> {
+    let Some(tensor) = &mobilogram.tensor else { return Ok(()) };
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "row,rt,im_bin,scan_start,im,intensity")?;
+    for ((row, col, bin), &value) in tensor.indexed_iter() {
+        if value > 0.0 {
+            let scan_start = mobilogram.scan_min as f32 + bin as f32 * mobilogram.bin_width;
+            writeln!(
+                writer,
+                "{},{:.6},{},{:.0},{:.5},{}",
+                row, all_rt[col], bin, scan_start, mobilogram.bin_im[bin], value
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use ndarray::Array4;
+
+    const ALL_RT: [f32; 5] = [0.0, 10.0, 10.1, 10.2, 10.3]; // column 0 is padding
+
+    fn peaks(rows: &[(f32, f32, u32, f32, u32)]) -> TimsTOFData {
+        let mut data = TimsTOFData::new();
+        for &(mz, rt, scan, im, intensity) in rows {
+            data.mz_values.push(mz);
+            data.rt_values_min.push(rt);
+            data.scan_indices.push(scan);
+            data.mobility_values.push(im);
+            data.intensity_values.push(intensity);
+            data.frame_indices.push(0);
+        }
+        data
+    }
+
+    // Monoisotope at m/z 500 in MS1 row 5, fragments at 600 and 700; scans
+    // 100..=179 split into four bins of 20 scans
+    fn extraction() -> ExtractedPrecursor {
+        let frag_info = Array3::from_shape_fn((1, 8, 4), |(_, row, c)| match (row, c) {
+            (5, 0) => 500.0,
+            (6, 0) => 600.0,
+            (7, 0) => 700.0,
+            (_, 0) => 0.0,
+            _ => 1.0,
+        });
+        let mut extracted = ExtractedPrecursor::synthetic(Array4::zeros((1, 1, 8, 5)), frag_info, ALL_RT.to_vec());
+        extracted.ms1_peaks = Arc::new(peaks(&[(500.0, 10.2, 140, 1.0, 20)]));
+        extracted.ms2_peaks = Arc::new(peaks(&[
+            (600.0, 10.1, 100, 1.2, 10),
+            (600.0, 10.2, 125, 1.1, 30),
+            (700.0, 10.2, 179, 0.8, 50),
+            (700.0, 10.3, 150, 0.9, 7),   // outside the apex window
+            (600.0, 10.8, 110, 1.0, 99),  // outside the RT span
+            (650.0, 10.2, 110, 1.0, 99),  // no fragment
+        ]));
+        extracted
+    }
+
+    #[test]
+    fn rt_column_is_nearest_within_half_a_step() {
+        let columns = [1, 2, 3, 4];
+        assert_eq!(rt_column(&ALL_RT, &columns, 10.04), Some(1));
+        assert_eq!(rt_column(&ALL_RT, &columns, 10.16), Some(3));
+        assert_eq!(rt_column(&ALL_RT, &columns, 9.96), Some(1));
+        assert_eq!(rt_column(&ALL_RT, &columns, 9.94), None);
+        assert_eq!(rt_column(&ALL_RT, &columns, 10.36), None);
+        assert_eq!(rt_column(&ALL_RT, &[], 10.0), None);
+    }
+
+    #[test]
+    fn peaks_land_in_their_scan_bin_around_the_apex() {
+        let config = MobilogramConfig { n_bins: 4, full_tensor: true, ..MobilogramConfig::default() };
+        let mobilogram = build_mobilogram(&extraction(), 2, &ExtractionConfig::default(), &config);
+
+        assert_eq!((mobilogram.scan_min, mobilogram.bin_width), (100, 20.0));
+        let mut expected = Array2::<f32>::zeros((8, 4));
+        expected[[5, 2]] = 20.0;
+        expected[[6, 0]] = 10.0;
+        expected[[6, 1]] = 30.0;
+        expected[[7, 3]] = 50.0;
+        assert_eq!(mobilogram.apex, expected);
+        assert_eq!(mobilogram.bin_im, [1.2, 1.1, 1.0, 0.8]);
+
+        let tensor = mobilogram.tensor.unwrap();
+        assert_eq!((tensor[[6, 2, 0]], tensor[[7, 4, 2]]), (10.0, 7.0));
+        assert_eq!(tensor.sum(), 117.0);
+    }
+}
@@ -0,0 +1,324 @@
+// File: src/peaks.rs
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use ndarray::{Array3, Array4, Axis, s};
+
+use crate::config::PeakConfig;
+use crate::mass_calibration::FragmentMassError;
+use crate::quant::PrecursorQuant;
+use crate::scores::PeakGroupScores;
+use crate::spectra::ApexSpectrum;
+use crate::utils::{LibraryRecord, VARIANT_ORIGINAL};
+
+/// Picked chromatographic peak of one precursor (RTs in minutes).
+#[derive(Debug, Clone)]
+pub struct ChromPeak {
+    pub apex_idx: usize, // column indices on the extraction's RT axis
+    pub left_idx: usize,
+    pub right_idx: usize,
+    pub apex_rt: f32,
+    pub left_rt: f32,
+    pub right_rt: f32,
+    pub fwhm: f32,
+    pub apex_intensity: f32, // smoothed summed fragment intensity
+}
+
+/// Summed original-variant MS2 fragment trace (over repeats and fragments).
+pub fn summed_fragment_trace(rsm_matrix: &Array4<f32>, frag_info: &Array3<f32>) -> Vec<f32> {
+    let summed = rsm_matrix.sum_axis(Axis(1));
+    let precursor_data = summed.slice(s![0, .., ..]);
+    let mut trace = vec![0.0f32; precursor_data.shape()[1]];
+    for row in 0..frag_info.shape()[1] {
+        if frag_info[[0, row, 2]] != VARIANT_ORIGINAL || frag_info[[0, row, 0]] <= 0.0 {
+            continue;
+        }
+        for (total, &value) in trace.iter_mut().zip(precursor_data.row(row)) {
+            *total += value;
+        }
+    }
+    trace
+}
+
+/// Quadratic Savitzky-Golay smoothing with a `window`-point (odd) kernel;
+/// edges use the shrunken symmetric window that still fits.
+pub fn savitzky_golay(values: &[f32], window: usize) -> Vec<f32> {
+    let n = values.len();
+    (0..n)
+        .map(|i| {
+            let m = (window / 2).min(i).min(n - 1 - i) as i64;
+            if m == 0 {
+                return values[i];
+            }
+            // closed-form quadratic/cubic coefficients for half-width m
+            let norm = ((2 * m + 1) * (4 * m * m + 4 * m - 3)) as f32;
+            (-m..=m)
+                .map(|k| {
+                    let c = (3 * (3 * m * m + 3 * m - 1) - 15 * k * k) as f32 / norm;
+                    c * values[(i as i64 + k) as usize]
+                })
+                .sum::<f32>()
+                .max(0.0)
+        })
+        .collect()
+}
+
+/// Gaussian smoothing with `sigma` in points, truncated at 3 sigma.
+pub fn gaussian_smooth(values: &[f32], sigma: f32) -> Vec<f32> {
+    let n = values.len();
+    let radius = (3.0 * sigma).ceil() as usize;
+    (0..n)
+        .map(|i| {
+            let lo = i.saturating_sub(radius);
+            let hi = (i + radius).min(n.saturating_sub(1));
+            let (mut weighted, mut total) = (0.0f32, 0.0f32);
+            for (j, &value) in values.iter().enumerate().take(hi + 1).skip(lo) {
+                let d = (j as f32 - i as f32) / sigma;
+                let w = (-0.5 * d * d).exp();
+                weighted += w * value;
+                total += w;
+            }
+            if total > 0.0 { weighted / total } else { 0.0 }
+        })
+        .collect()
+}
+
+pub fn smooth_trace(values: &[f32], config: &PeakConfig) -> Result<Vec<f32>, Box<dyn Error>> {
+    match config.smoothing.as_str() {
+        "none" => Ok(values.to_vec()),
+        "savitzky_golay" | "sg" => Ok(savitzky_golay(values, config.smoothing_window.max(3) | 1)),
+        "gaussian" => Ok(gaussian_smooth(values, config.gaussian_sigma.max(0.1))),
+        other => Err(format!("Invalid smoothing: {}. Use none, savitzky_golay or gaussian.", other).into()),
+    }
+}
+
+/// RT where the trace crosses `level` between two neighbouring points.
+fn crossing_rt(rts: &[f32], values: &[f32], inside: usize, outside: usize, level: f32) -> f32 {
+    let (v_in, v_out) = (values[inside], values[outside]);
+    if v_in <= v_out {
+        return rts[inside];
+    }
+    let w = ((v_in - level) / (v_in - v_out)).clamp(0.0, 1.0);
+    rts[inside] + w * (rts[outside] - rts[inside])
+}
+
+/// Pick the peak on `trace` whose apex is closest to `expected_rt`.
+/// Only the columns set in `mask` (the trace's MS level, see
+/// `column_ms_levels`) are used, so smoothing and boundaries never see the
+/// other level's frames or the zero padding; indices in the result refer to
+/// the original RT axis.
+pub fn pick_peak(
+    all_rt: &[f32],
+    trace: &[f32],
+    mask: &[bool],
+    expected_rt: f32,
+    config: &PeakConfig,
+) -> Result<Option<ChromPeak>, Box<dyn Error>> {
+    let columns: Vec<usize> = (0..all_rt.len()).filter(|&k| mask[k] && all_rt[k] > 0.0).collect();
+    if columns.len() < 3 {
+        return Ok(None);
+    }
+    let rts: Vec<f32> = columns.iter().map(|&k| all_rt[k]).collect();
+    let raw: Vec<f32> = columns.iter().map(|&k| trace[k]).collect();
+    let smoothed = smooth_trace(&raw, config)?;
+
+    let max_value = smoothed.iter().cloned().fold(0.0f32, f32::max);
+    if max_value <= 0.0 {
+        return Ok(None);
+    }
+
+    // 1. Local maxima above the noise floor; apex = the one nearest the expected RT
+    let n = smoothed.len();
+    let min_height = max_value * config.min_apex_fraction;
+    let apex = (0..n)
+        .filter(|&k| {
+            let left_ok = k == 0 || smoothed[k] >= smoothed[k - 1];
+            let right_ok = k == n - 1 || smoothed[k] > smoothed[k + 1];
+            left_ok && right_ok && smoothed[k] >= min_height
+        })
+        .min_by(|&a, &b| {
+            (rts[a] - expected_rt).abs().total_cmp(&(rts[b] - expected_rt).abs())
+                .then(smoothed[b].total_cmp(&smoothed[a]))
+        })
+        .unwrap_or(0);
+    let apex_value = smoothed[apex];
+
+    // 2. Boundaries: walk out until the trace drops below the boundary level or rises again
+    let boundary_level = apex_value * config.boundary_fraction;
+    let mut left = apex;
+    while left > 0 && smoothed[left - 1] <= smoothed[left] && smoothed[left] > boundary_level {
+        left -= 1;
+    }
+    let mut right = apex;
+    while right < n - 1 && smoothed[right + 1] <= smoothed[right] && smoothed[right] > boundary_level {
+        right += 1;
+    }
+
+    // 3. FWHM from the interpolated half-maximum crossings inside the boundaries
+    let half = apex_value * 0.5;
+    let mut l = apex;
+    while l > left && smoothed[l - 1] > half {
+        l -= 1;
+    }
+    let mut r = apex;
+    while r < right && smoothed[r + 1] > half {
+        r += 1;
+    }
+    // a side that never drops below half maximum is cut at the peak boundary
+    let left_half = if l > left { crossing_rt(&rts, &smoothed, l, l - 1, half) } else { rts[left] };
+    let right_half = if r < right { crossing_rt(&rts, &smoothed, r, r + 1, half) } else { rts[right] };
+
+    Ok(Some(ChromPeak {
+        apex_idx: columns[apex],
+        left_idx: columns[left],
+        right_idx: columns[right],
+        apex_rt: rts[apex],
+        left_rt: rts[left],
+        right_rt: rts[right],
+        fwhm: right_half - left_half,
+        apex_intensity: apex_value,
+    }))
+}
+
+/// Columns between the peak boundaries (inclusive) that are set in `mask`.
+pub fn peak_columns(peak: &ChromPeak, mask: &[bool]) -> Vec<usize> {
+    (peak.left_idx..=peak.right_idx).filter(|&k| mask[k]).collect()
+}
+
+/// Peak groups of one precursor run, one per extracted view (candidate).
+#[derive(Debug, Clone)]
+pub struct PeakGroupResult {
+    pub precursor_id: String,
+    pub protein_id: String,
+    pub decoy: bool,
+    pub candidate_rank: Option<usize>,
+    pub expected_rt: f32,
+    pub peak: Option<ChromPeak>,
+    pub quant: Option<PrecursorQuant>,
+    pub scores: Option<PeakGroupScores>,
+    pub mass_errors: Vec<FragmentMassError>,
+    pub spectrum: Option<ApexSpectrum>,
+    pub library_entry: Vec<LibraryRecord>, // empirical library records, empty unless requested
+    #[cfg(feature = "inference")]
+    pub model_input: Option<crate::inference::ModelInput>, // consumed by `score_peak_groups`
+    #[cfg(feature = "inference")]
+    pub model_score: Option<f32>,
+}
+
+#[cfg(test)]
+impl PeakGroupResult {
+    /// Test peak group without a peak, quantities or scores.
+    pub fn empty(precursor_id: &str, decoy: bool) -> Self {
+        PeakGroupResult {
+            precursor_id: precursor_id.to_string(),
+            protein_id: String::new(),
+            decoy,
+            candidate_rank: None,
+            expected_rt: 0.0,
+            peak: None,
+            quant: None,
+            scores: None,
+            mass_errors: Vec::new(),
+            spectrum: None,
+            library_entry: Vec::new(),
+            #[cfg(feature = "inference")]
+            model_input: None,
+            #[cfg(feature = "inference")]
+            model_score: None,
+        }
+    }
+}
+
+/// Write one line per peak group; precursors without a detectable peak get NA.
+pub fn write_peak_table(results: &[PeakGroupResult], path: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "precursor_id\tdecoy\tcandidate\texpected_rt\tapex_rt\tleft_rt\tright_rt\tfwhm\tapex_intensity")?;
+    for result in results {
+        let candidate = result.candidate_rank.map(|r| r.to_string()).unwrap_or_default();
+        match &result.peak {
+            Some(p) => writeln!(
+                writer,
+                "{}\t{}\t{}\t{:.4}\t{:.4}\t{:.4}\t{:.4}\t{:.4}\t{:.1}",
+                result.precursor_id, result.decoy as u8, candidate, result.expected_rt,
+                p.apex_rt, p.left_rt, p.right_rt, p.fwhm, p.apex_intensity
+            )?,
+            None => writeln!(
+                writer,
+                "{}\t{}\t{}\t{:.4}\tNA\tNA\tNA\tNA\tNA",
+                result.precursor_id, result.decoy as u8, candidate, result.expected_rt
+            )?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(smoothing: &str) -> PeakConfig {
+        PeakConfig { enabled: true, smoothing: smoothing.to_string(), ..PeakConfig::default() }
+    }
+
+    // Gaussian peaks (height, centre index, sigma in points) on 41 points, RT 10.00 .. 10.40
+    fn trace(peaks: &[(f32, f32, f32)]) -> (Vec<f32>, Vec<f32>) {
+        let rts: Vec<f32> = (0..41).map(|k| 10.0 + 0.01 * k as f32).collect();
+        let values = (0..41)
+            .map(|k| peaks.iter().map(|&(h, c, s)| h * (-0.5 * ((k as f32 - c) / s).powi(2)).exp()).sum())
+            .collect();
+        (rts, values)
+    }
+
+    #[test]
+    fn savitzky_golay_keeps_cubic_trends_and_raw_edges() {
+        let cubic: Vec<f32> = (0..12).map(|k| (k as f32).powi(3) - 8.0 * (k as f32).powi(2) + 100.0).collect();
+        let smoothed = savitzky_golay(&cubic, 5);
+        assert_eq!(smoothed[0], cubic[0]);
+        assert!(smoothed.iter().zip(&cubic).all(|(s, c)| (s - c).abs() < 1e-2), "{:?}", smoothed);
+
+        // A spike is spread out and never goes negative
+        let spike = savitzky_golay(&[0.0, 0.0, 0.0, 10.0, 0.0, 0.0, 0.0], 5);
+        assert!(spike[3] < 10.0 && spike.iter().all(|&v| v >= 0.0));
+    }
+
+    #[test]
+    fn gaussian_smoothing_keeps_constants_and_the_apex() {
+        assert!(gaussian_smooth(&[4.0; 9], 1.5).iter().all(|&v| (v - 4.0).abs() < 1e-5));
+        let (_, values) = trace(&[(100.0, 20.0, 3.0)]);
+        let smoothed = gaussian_smooth(&values, 1.0);
+        let apex = (0..smoothed.len()).max_by(|&a, &b| smoothed[a].total_cmp(&smoothed[b])).unwrap();
+        assert_eq!(apex, 20);
+        assert!(smooth_trace(&values, &config("loess")).is_err());
+    }
+
+    #[test]
+    fn gaussian_peak_apex_boundaries_and_fwhm() {
+        let (rts, values) = trace(&[(100.0, 20.0, 3.0)]);
+        let peak = pick_peak(&rts, &values, &[true; 41], 10.21, &config("none")).unwrap().unwrap();
+
+        assert_eq!((peak.apex_idx, peak.left_idx, peak.right_idx), (20, 12, 28));
+        assert!((peak.apex_rt - 10.2).abs() < 1e-6);
+        assert_eq!(peak.apex_intensity, 100.0);
+        // FWHM = 2.355 sigma = 0.0707 min
+        assert!((peak.fwhm - 0.0707).abs() < 2e-3, "{}", peak.fwhm);
+    }
+
+    #[test]
+    fn apex_nearest_the_expected_rt_on_masked_columns() {
+        let (rts, values) = trace(&[(100.0, 10.0, 2.0), (60.0, 30.0, 2.0)]);
+        assert_eq!(pick_peak(&rts, &values, &[true; 41], 10.1, &config("none")).unwrap().unwrap().apex_idx, 10);
+        assert_eq!(pick_peak(&rts, &values, &[true; 41], 10.28, &config("none")).unwrap().unwrap().apex_idx, 30);
+
+        // Zeroed columns of the other MS level are skipped, indices stay on the full axis
+        let mask: Vec<bool> = (0..41usize).map(|k| k.is_multiple_of(2)).collect();
+        let interleaved: Vec<f32> = values.iter().enumerate().map(|(k, &v)| if mask[k] { v } else { 0.0 }).collect();
+        let peak = pick_peak(&rts, &interleaved, &mask, 10.28, &config("savitzky_golay")).unwrap().unwrap();
+        assert_eq!(peak.apex_idx, 30);
+        assert!(peak.left_idx.is_multiple_of(2) && peak.right_idx.is_multiple_of(2));
+        // 2.355 sigma = 0.047 min, widened a little by smoothing the thinned trace
+        assert!(peak.fwhm > 0.04 && peak.fwhm < 0.07, "{}", peak.fwhm);
+
+        assert!(pick_peak(&rts, &[0.0; 41], &[true; 41], 10.2, &config("none")).unwrap().is_none());
+        assert!(pick_peak(&rts, &values, &[false; 41], 10.2, &config("none")).unwrap().is_none());
+    }
+}
@@ -4,28 +4,128 @@ use crate::utils::{
     library_records_to_dataframe, merge_library_and_report, get_unique_precursor_ids, 
     process_library_fast, create_rt_im_dicts, build_lib_matrix, build_precursors_matrix_step1, 
     build_precursors_matrix_step2, build_range_matrix_step3, build_precursors_matrix_step3, 
-    build_frag_info, get_rt_list, LibCols, quantize, FrameSplit, MergeFrom, PrecursorLibData,
+    build_frag_info, get_rt_list, fit_rt_list, LibCols, quantize, FrameSplit, MergeFrom, PrecursorLibData,
+    VARIANT_ORIGINAL,
 };
+use crate::config::{Config, ExtractionConfig};
+use crate::candidates::{RtCandidate, generate_rt_candidates, slice_candidate};
+use crate::resample::{grid_extraction_range, resample_extraction};
+use crate::rt_window::{WindowFit, window_grid, window_width};
+use crate::peaks::{PeakGroupResult, pick_peak, summed_fragment_trace};
+use crate::quant::quantify_peak_group;
+use crate::scores::score_peak_group;
+use crate::mass_calibration::measure_fragment_mass_errors;
+use crate::spectra::apex_spectrum;
+use crate::empirical_library::empirical_records;
+use crate::interference::with_interference_mask;
+use crate::mobilogram::{append_mobilogram_columns, build_mobilogram, write_mobility_tensor};
 use rayon::prelude::*;
 use std::{collections::HashMap, error::Error, cmp::Ordering, sync::Arc, time::Instant};
-use ndarray::{Array1, Array2, Array3, Array4, s, Axis, concatenate};
+use ndarray::{Array1, Array2, Array3, Array4, ArrayView3, s, Axis, concatenate};
 use polars::prelude::*;
 use std::fs::File;
 
 // 在 processing.rs 中添加
 
-pub fn process_single_precursor(
+/// How the RT axis of an extraction is chosen.
+#[derive(Debug, Clone, Copy)]
+pub enum RtSelection {
+    /// Fixed-length window (see `get_rt_list`) centred on the precursor RT.
+    Centered,
+    /// Every frame RT inside `[rt_min, rt_max]` (minutes).
+    Range(f32, f32),
+    /// Frame RTs inside `[rt_min, rt_max]`, cropped or zero-padded to `n` points
+    /// around the precursor RT (see `fit_rt_list`).
+    Window(f32, f32, usize),
+}
+
+/// Extracted traces of one precursor: the repeat-resolved fragment x RT
+/// matrix, its fragment annotations and the RT axis with the MS level of
+/// every column, plus the raw MS1/MS2 peaks (float m/z) the traces were
+/// built from, shared by every view cut from the same extraction.
+pub struct ExtractedPrecursor {
+    pub rsm_matrix: Array4<f32>,
+    pub frag_info: Array3<f32>,
+    pub all_rt: Vec<f32>,
+    pub ms1_columns: Vec<bool>, // see `column_ms_levels`
+    pub ms2_columns: Vec<bool>,
+    pub ms1_peaks: Arc<crate::utils::TimsTOFData>,
+    pub ms2_peaks: Arc<crate::utils::TimsTOFData>,
+}
+
+#[cfg(test)]
+impl ExtractedPrecursor {
+    /// Test extraction with every column on both MS levels and no raw peaks.
+    pub fn synthetic(rsm_matrix: Array4<f32>, frag_info: Array3<f32>, all_rt: Vec<f32>) -> Self {
+        let columns = vec![true; all_rt.len()];
+        ExtractedPrecursor {
+            rsm_matrix,
+            frag_info,
+            all_rt,
+            ms1_columns: columns.clone(),
+            ms2_columns: columns,
+            ms1_peaks: Arc::new(crate::utils::TimsTOFData::new()),
+            ms2_peaks: Arc::new(crate::utils::TimsTOFData::new()),
+        }
+    }
+}
+
+/// Per `all_rt` column: whether it holds an MS1 / MS2 frame. On the frame
+/// axis MS1 and MS2 frames never share an RT, so every trace is zero on the
+/// columns of the other level; on the cycle axis each column is both.
+/// Zero-RT padding columns are neither.
+pub fn column_ms_levels(
+    all_rt: &[f32],
+    ms1_peaks: &crate::utils::TimsTOFData,
+    ms2_peaks: &crate::utils::TimsTOFData,
+    rt_axis: &str,
+) -> (Vec<bool>, Vec<bool>) {
+    if rt_axis != "frame" {
+        let columns: Vec<bool> = all_rt.iter().map(|&rt| rt > 0.0).collect();
+        return (columns.clone(), columns);
+    }
+    // Same rounding as `collect_unique_rt_values`, so the RTs compare bit for bit
+    let level_rts = |peaks: &crate::utils::TimsTOFData| -> std::collections::HashSet<u32> {
+        peaks.rt_values_min.iter().map(|&rt| ((rt * 1e6) as i32 as f32 / 1e6).to_bits()).collect()
+    };
+    let (ms1_rts, ms2_rts) = (level_rts(ms1_peaks), level_rts(ms2_peaks));
+    (
+        all_rt.iter().map(|&rt| rt > 0.0 && ms1_rts.contains(&rt.to_bits())).collect(),
+        all_rt.iter().map(|&rt| rt > 0.0 && ms2_rts.contains(&rt.to_bits())).collect(),
+    )
+}
+
+/// IM window of a precursor; an unknown IM (<= 0) extracts the full mobility range.
+pub fn precursor_im_range(im: f32, im_tolerance: f32) -> (f32, f32) {
+    if im > 0.0 {
+        (im - im_tolerance, im + im_tolerance)
+    } else {
+        (f32::NEG_INFINITY, f32::INFINITY)
+    }
+}
+
+/// IM half-width for a precursor according to `im_tolerance_mode`.
+pub fn precursor_im_tolerance(precursor_data: &PrecursorLibData, extraction: &ExtractionConfig) -> Result<f32, Box<dyn Error>> {
+    match extraction.im_tolerance_mode.as_str() {
+        "fixed" => Ok(extraction.im_tolerance),
+        "relative" => Ok(precursor_data.im * extraction.im_tolerance_relative),
+        "charge" => {
+            let charge = precursor_data.precursor_info.get(2).copied().unwrap_or(0.0) as u8;
+            Ok(extraction.im_tolerance_by_charge.get(&charge.to_string()).copied().unwrap_or(extraction.im_tolerance))
+        }
+        other => Err(format!("Invalid im_tolerance_mode: {}. Use fixed, relative or charge.", other).into()),
+    }
+}
+
+pub fn extract_precursor(
     precursor_data: &PrecursorLibData,
     ms1_indexed: &IndexedTimsTOFData,
     finder: &FastChunkFinder,
-    frag_repeat_num: usize,
+    extraction: &ExtractionConfig,
+    rt_selection: RtSelection,
     device: &str,
-    output_dir: &str,
-) -> Result<(), Box<dyn Error>> {
-    // let start_time = Instant::now();
-    
-    // println!("\n========== Processing Precursor: {} ==========", precursor_data.precursor_id);
-    // println!("RT: {:.2}, IM: {:.4}", precursor_data.rt, precursor_data.im);
+) -> Result<ExtractedPrecursor, Box<dyn Error>> {
+    let frag_repeat_num = extraction.frag_repeat_num;
     
     // Step 1: Build tensor representations
     let (ms1_data_tensor, ms2_data_tensor) = build_precursors_matrix_step1(
@@ -41,9 +141,9 @@ pub fn process_single_precursor(
         &ms1_data_tensor,
         &ms2_data_tensor_processed,
         frag_repeat_num,
-        "ppm",
-        20.0,
-        50.0,
+        &extraction.mz_unit,
+        extraction.ms1_tolerance,
+        extraction.ms2_tolerance,
         device,
     )?;
     
@@ -52,30 +152,29 @@ pub fn process_single_precursor(
             &ms1_data_tensor,
             &ms2_data_tensor_processed,
             frag_repeat_num,
-            "ppm",
-            20.0,
-            50.0,
+            &extraction.mz_unit,
+            extraction.ms1_tolerance,
+            extraction.ms2_tolerance,
             device,
         )?;
     
     // Step 3: Calculate extraction ranges
     let i = 0; // 因为我们一次只处理一个precursor
     let (ms1_range_min, ms1_range_max) = calculate_mz_range(&ms1_range_list, i);
-    let im_tolerance = 0.05f32;
-    let im_min = precursor_data.im - im_tolerance;
-    let im_max = precursor_data.im + im_tolerance;
+    let (im_min, im_max) = precursor_im_range(precursor_data.im, precursor_im_tolerance(precursor_data, extraction)?);
     
     let precursor_mz = precursor_data.precursor_info[1]; // precursor_info的第二个元素是precursor_mz
     
-    // Step 4: Extract MS1 data
-    let mut precursor_result_filtered = ms1_indexed.slice_by_mz_im_range(
+    // Step 4: Extract MS1 data (float m/z copy kept for mass error / IM scores)
+    let ms1_peaks = ms1_indexed.slice_by_mz_im_range(
         ms1_range_min, ms1_range_max, im_min, im_max
     );
+    let mut precursor_result_filtered = ms1_peaks.clone();
     precursor_result_filtered.mz_values.iter_mut()
         .for_each(|mz| *mz = (*mz * 1000.0).ceil());
     
     // Step 5: Extract MS2 data
-    let mut frag_result_filtered = extract_ms2_data(
+    let ms2_peaks = extract_ms2_data(
         finder,
         precursor_mz,
         &ms2_range_list,
@@ -83,6 +182,9 @@ pub fn process_single_precursor(
         im_min,
         im_max,
     )?;
+    let mut frag_result_filtered = ms2_peaks.clone();
+    frag_result_filtered.mz_values.iter_mut()
+        .for_each(|mz| *mz = (*mz * 1000.0).ceil());
     
     // Step 6: Build mask matrices
     let (ms1_frag_moz_matrix, ms2_frag_moz_matrix) = build_mask_matrices(
@@ -94,11 +196,28 @@ pub fn process_single_precursor(
     )?;
     
     // Step 7: Extract aligned RT values
-    let all_rt = extract_aligned_rt_values(
-        &precursor_result_filtered,
-        &frag_result_filtered,
-        precursor_data.rt,
-    );
+    let all_rt = match rt_selection {
+        RtSelection::Centered => extract_aligned_rt_values(
+            &precursor_result_filtered,
+            &frag_result_filtered,
+            precursor_data.rt,
+        ),
+        RtSelection::Range(rt_min, rt_max) => collect_unique_rt_values(
+            &precursor_result_filtered,
+            &frag_result_filtered,
+        )
+        .into_iter()
+        .filter(|&rt| rt >= rt_min && rt <= rt_max)
+        .collect(),
+        RtSelection::Window(rt_min, rt_max, n_points) => fit_rt_list(
+            collect_unique_rt_values(&precursor_result_filtered, &frag_result_filtered)
+                .into_iter()
+                .filter(|&rt| rt >= rt_min && rt <= rt_max)
+                .collect(),
+            precursor_data.rt,
+            n_points,
+        ),
+    };
     
     // Step 8: Build intensity matrices
     let ms1_extract_slice = ms1_extract_width_range_list.slice(s![i, .., ..]).to_owned();
@@ -133,32 +252,250 @@ pub fn process_single_precursor(
         device,
     );
     
-    // Step 11: Create final dataframe
-    let final_df = create_final_dataframe(
-        &rsm_matrix,
-        &frag_info,
-        &all_rt,
-        i,
+    let (ms1_columns, ms2_columns) = column_ms_levels(&all_rt, &precursor_result_filtered, &frag_result_filtered, &extraction.rt_axis);
+    
+    Ok(ExtractedPrecursor { rsm_matrix, frag_info, all_rt, ms1_columns, ms2_columns, ms1_peaks: Arc::new(ms1_peaks), ms2_peaks: Arc::new(ms2_peaks) })
+}
+
+pub fn process_single_precursor(
+    precursor_data: &PrecursorLibData,
+    ms1_indexed: &IndexedTimsTOFData,
+    finder: &FastChunkFinder,
+    config: &Config,
+    repeat_reduction: RepeatReduction,
+    device: &str,
+    output_dir: &str,
+) -> Result<Vec<PeakGroupResult>, Box<dyn Error>> {
+    // let start_time = Instant::now();
+    
+    // println!("\n========== Processing Precursor: {} ==========", precursor_data.precursor_id);
+    // println!("RT: {:.2}, IM: {:.4}", precursor_data.rt, precursor_data.im);
+    
+    let candidate_cfg = &config.candidates;
+    let resampling_cfg = &config.resampling;
+    // Adaptive window from the reported peak width (outside candidate mode)
+    let window = if config.rt_window.enabled && !candidate_cfg.enabled {
+        match window_width(precursor_data, &config.rt_window)? {
+            Some(width) => Some((width, WindowFit::from_name(&config.rt_window.fit)?)),
+            None => None,
+        }
+    } else {
+        None
+    };
+    let window_resampling = match window {
+        Some((width, WindowFit::Resample)) => Some(window_grid(width, &config.rt_window)),
+        _ => None,
+    };
+    let rt_selection = if let Some(grid) = &window_resampling {
+        // Extract enough frames to cover the window grid
+        let (rt_min, rt_max) = grid_extraction_range(precursor_data.rt, grid)?;
+        RtSelection::Range(rt_min, rt_max)
+    } else if let Some((width, _)) = window {
+        RtSelection::Window(
+            precursor_data.rt - width * 0.5,
+            precursor_data.rt + width * 0.5,
+            config.rt_window.n_points,
+        )
+    } else if !candidate_cfg.enabled {
+        if resampling_cfg.enabled {
+            // Extract enough frames to cover the whole resampling grid
+            let (rt_min, rt_max) = grid_extraction_range(precursor_data.rt, resampling_cfg)?;
+            RtSelection::Range(rt_min, rt_max)
+        } else {
+            RtSelection::Centered
+        }
+    } else if candidate_cfg.rt_tolerance > 0.0 {
+        RtSelection::Range(
+            precursor_data.rt - candidate_cfg.rt_tolerance,
+            precursor_data.rt + candidate_cfg.rt_tolerance,
+        )
+    } else {
+        RtSelection::Range(f32::NEG_INFINITY, f32::INFINITY)
+    };
+    
+    // Steps 1-10: Extract fragment traces (centred window or broad RT range)
+    let extracted = extract_precursor(
+        precursor_data,
+        ms1_indexed,
+        finder,
+        &config.extraction,
+        rt_selection,
+        device,
     )?;
     
-    // // Step 12: Save results with precursor info in filename
-    // let output_filename = format!(
-    //     "{}/{}_RT{:.2}_IM{:.4}_final_dataframe.csv",
-    //     output_dir,
-    //     precursor_data.precursor_id,
-    //     precursor_data.rt,
-    //     precursor_data.im
-    // );
+    // Candidate mode: split the broad extraction into ranked sliding windows
+    // (or resample around each candidate apex when a fixed grid is requested)
+    let mut views: Vec<(Option<RtCandidate>, ExtractedPrecursor)> = if candidate_cfg.enabled {
+        generate_rt_candidates(&extracted, candidate_cfg)
+            .into_iter()
+            .map(|candidate| {
+                let view = if resampling_cfg.enabled {
+                    resample_extraction(&extracted, candidate.apex_rt, resampling_cfg)?
+                } else {
+                    slice_candidate(&extracted, &candidate, candidate_cfg.window_length)
+                };
+                Ok((Some(candidate), view))
+            })
+            .collect::<Result<_, Box<dyn Error>>>()?
+    } else if let Some(grid) = &window_resampling {
+        vec![(None, resample_extraction(&extracted, precursor_data.rt, grid)?)]
+    } else if window.is_some() {
+        vec![(None, extracted)]
+    } else if resampling_cfg.enabled {
+        vec![(None, resample_extraction(&extracted, precursor_data.rt, resampling_cfg)?)]
+    } else {
+        vec![(None, extracted)]
+    };
+    
+    // Interference mask as an extra frag_info column
+    if config.interference.enabled {
+        for (_, view) in views.iter_mut() {
+            view.frag_info = with_interference_mask(&view.frag_info, &precursor_data.interference);
+        }
+    }
     
-    // let mut file = File::create(&output_filename)?;
-    // CsvWriter::new(&mut file)
-    //     .include_header(true)
-    //     .finish(&mut final_df.clone())?;
+    let mut results = Vec::with_capacity(views.len());
+    for (candidate, view) in &views {
+        // Peak picking on the summed fragment trace, anchored at the expected (or candidate apex) RT
+        let expected_rt = candidate.as_ref().map(|c| c.apex_rt).unwrap_or(precursor_data.rt);
+        let peak = if config.peaks.enabled || config.quant.enabled || config.features.enabled || config.rescoring.enabled
+            || config.mobilogram.enabled || config.spectra.enabled || config.empirical_library.enabled
+        {
+            let trace = summed_fragment_trace(&view.rsm_matrix, &view.frag_info);
+            pick_peak(&view.all_rt, &trace, &view.ms2_columns, expected_rt, &config.peaks)?
+        } else {
+            None
+        };
+        let quant = match (&peak, config.quant.enabled) {
+            (Some(peak), true) => Some(quantify_peak_group(&view.rsm_matrix, &view.frag_info, &view.all_rt, &view.ms2_columns, peak, &config.quant)?),
+            _ => None,
+        };
+        let scores = match (&peak, config.features.enabled || config.rescoring.enabled || config.empirical_library.enabled) {
+            (Some(peak), true) => Some(score_peak_group(view, peak, precursor_data.precursor_info[1], expected_rt, precursor_data.im, &config.extraction)),
+            _ => None,
+        };
+        // Observed fragment m/z inside the peak (or the whole view without one)
+        let mass_errors = if config.mass_calibration.report_fragment_errors {
+            let (rt_min, rt_max) = match &peak {
+                Some(peak) => (peak.left_rt, peak.right_rt),
+                None => (f32::MIN_POSITIVE, f32::INFINITY),
+            };
+            measure_fragment_mass_errors(view, rt_min, rt_max, &config.extraction)
+        } else {
+            Vec::new()
+        };
+        let spectrum = if config.spectra.enabled {
+            let apex_rt = peak.as_ref().map(|p| p.apex_rt).unwrap_or(expected_rt);
+            apex_spectrum(precursor_data, view, finder, apex_rt, &config.extraction, &config.spectra)?
+        } else {
+            None
+        };
+        let library_entry = match (&peak, config.empirical_library.enabled) {
+            (Some(peak), true) => empirical_records(precursor_data, view, peak, &config.extraction),
+            _ => Vec::new(),
+        };
+        // Mobilogram at the picked apex (highest summed fragment point without a peak)
+        let mobilogram = (config.mobilogram.enabled && config.processing.save_dataframes).then(|| {
+            let apex_idx = match &peak {
+                Some(peak) => peak.apex_idx,
+                None => {
+                    let trace = summed_fragment_trace(&view.rsm_matrix, &view.frag_info);
+                    (0..trace.len()).max_by(|&a, &b| trace[a].total_cmp(&trace[b])).unwrap_or(0)
+                }
+            };
+            build_mobilogram(view, apex_idx, &config.extraction, &config.mobilogram)
+        });
+        results.push(PeakGroupResult {
+            precursor_id: precursor_data.precursor_id.clone(),
+            protein_id: precursor_data.lib_records.first().map(|r| r.protein_id.clone()).unwrap_or_default(),
+            decoy: precursor_data.lib_records.first().is_some_and(|r| r.decoy == "1"),
+            candidate_rank: candidate.as_ref().map(|c| c.rank),
+            expected_rt,
+            peak,
+            quant,
+            scores,
+            mass_errors,
+            spectrum,
+            library_entry,
+            #[cfg(feature = "inference")]
+            model_input: if config.inference.enabled {
+                Some(crate::inference::model_input(precursor_data, view, expected_rt)?)
+            } else {
+                None
+            },
+            #[cfg(feature = "inference")]
+            model_score: None,
+        });
+        
+        // Step 11: Create final dataframe and save it with precursor info in filename
+        if config.processing.save_dataframes {
+            let mut final_df = create_final_dataframe(&view.rsm_matrix, &view.frag_info, &view.all_rt, 0, repeat_reduction)?;
+            if let Some(mobilogram) = &mobilogram {
+                append_mobilogram_columns(&mut final_df, mobilogram)?;
+            }
+            
+            let candidate_tag = candidate
+                .as_ref()
+                .map(|c| format!("_cand{}_apex{:.2}_score{:.0}", c.rank, c.apex_rt, c.pre_score))
+                .unwrap_or_default();
+            let decoy_tag = if precursor_data.lib_records.first().is_some_and(|r| r.decoy == "1") { "_decoy" } else { "" };
+            let output_filename = format!(
+                "{}/{}_RT{:.2}_IM{:.4}{}{}_final_dataframe.csv",
+                output_dir,
+                precursor_data.precursor_id,
+                precursor_data.rt,
+                precursor_data.im,
+                candidate_tag,
+                decoy_tag
+            );
+            
+            let mut file = File::create(&output_filename)?;
+            CsvWriter::new(&mut file)
+                .include_header(true)
+                .finish(&mut final_df)?;
+            
+            if let Some(mobilogram) = &mobilogram {
+                let tensor_filename = output_filename.replace("_final_dataframe.csv", "_mobility_tensor.csv");
+                write_mobility_tensor(mobilogram, &view.all_rt, &tensor_filename)?;
+            }
+        }
+    }
     
     // println!("Processing time: {:.3} seconds", start_time.elapsed().as_secs_f32());
-    // println!("Output saved to: {}", output_filename);
     
-    Ok(())
+    Ok(results)
+}
+
+/// Cheap per-RT pre-score: summed intensity of the original-variant MS2
+/// fragments, weighted by the fraction of those fragments detected at that RT.
+pub fn fragment_coelution_scores(rsm_matrix: &Array4<f32>, frag_info: &Array3<f32>) -> (Vec<f32>, Vec<usize>) {
+    let summed = rsm_matrix.sum_axis(Axis(1));
+    let precursor_data = summed.slice(s![0, .., ..]);
+    let n_rt = precursor_data.shape()[1];
+    
+    let ms2_rows: Vec<usize> = (0..frag_info.shape()[1])
+        .filter(|&row| frag_info[[0, row, 2]] == VARIANT_ORIGINAL && frag_info[[0, row, 0]] > 0.0)
+        .collect();
+    
+    let mut scores = vec![0.0f32; n_rt];
+    let mut detected = vec![0usize; n_rt];
+    if ms2_rows.is_empty() {
+        return (scores, detected);
+    }
+    
+    for rt_idx in 0..n_rt {
+        let mut total = 0.0f32;
+        for &row in &ms2_rows {
+            let inten = precursor_data[[row, rt_idx]];
+            if inten > 0.0 {
+                total += inten;
+                detected[rt_idx] += 1;
+            }
+        }
+        scores[rt_idx] = total * detected[rt_idx] as f32 / ms2_rows.len() as f32;
+    }
+    
+    (scores, detected)
 }
 
 pub struct FastChunkFinder {
@@ -186,15 +523,30 @@ impl FastChunkFinder {
     
     #[inline]
     pub fn find(&self, mz: f32) -> Option<&IndexedTimsTOFData> {
+        self.window_of(mz).map(|idx| &self.chunks[idx])
+    }
+    
+    /// Index of the MS2 window whose isolation range contains `mz`.
+    #[inline]
+    pub fn window_of(&self, mz: f32) -> Option<usize> {
         match self.low_bounds.binary_search_by(|probe| probe.partial_cmp(&mz).unwrap()) {
-            Ok(idx) => Some(&self.chunks[idx]),
+            Ok(idx) => Some(idx),
             Err(0) => None,
             Err(pos) => {
                 let idx = pos - 1;
-                if mz <= self.high_bounds[idx] { Some(&self.chunks[idx]) } else { None }
+                if mz <= self.high_bounds[idx] { Some(idx) } else { None }
             }
         }
     }
+    
+    pub fn n_windows(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Mutable access to the MS2 window chunks (e.g. for m/z recalibration).
+    pub fn chunks_mut(&mut self) -> &mut [IndexedTimsTOFData] {
+        &mut self.chunks
+    }
 }
 
 pub fn build_intensity_matrix_optimized(
@@ -321,7 +673,7 @@ pub fn extract_ms2_data(
     im_min: f32,
     im_max: f32,
 ) -> Result<crate::utils::TimsTOFData, Box<dyn Error>> {
-    let mut result = if let Some(ms2_indexed) = finder.find(precursor_mz) {
+    let result = if let Some(ms2_indexed) = finder.find(precursor_mz) {
         // Process all 66 MS2 ranges in parallel
         let frag_results: Vec<crate::utils::TimsTOFData> = (0..66)
             .into_iter()
@@ -348,10 +700,6 @@ pub fn extract_ms2_data(
         crate::utils::TimsTOFData::new()
     };
     
-    // Convert m/z values to integers
-    result.mz_values.iter_mut()
-        .for_each(|mz| *mz = (*mz * 1000.0).ceil());
-    
     Ok(result)
 }
 
@@ -408,10 +756,9 @@ pub fn build_mask_matrices(
     Ok((ms1_frag_moz_matrix, ms2_frag_moz_matrix))
 }
 
-pub fn extract_aligned_rt_values(
+pub fn collect_unique_rt_values(
     precursor_result_filtered: &crate::utils::TimsTOFData,
     frag_result_filtered: &crate::utils::TimsTOFData,
-    target_rt: f32,
 ) -> Vec<f32> {
     use std::collections::HashSet;
     
@@ -433,6 +780,15 @@ pub fn extract_aligned_rt_values(
         .collect();
     
     all_rt_vec.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    all_rt_vec
+}
+
+pub fn extract_aligned_rt_values(
+    precursor_result_filtered: &crate::utils::TimsTOFData,
+    frag_result_filtered: &crate::utils::TimsTOFData,
+    target_rt: f32,
+) -> Vec<f32> {
+    let all_rt_vec = collect_unique_rt_values(precursor_result_filtered, frag_result_filtered);
     
     // Get RT list with target RT in the center
     get_rt_list(all_rt_vec, target_rt)
@@ -479,38 +835,145 @@ pub fn reshape_and_combine_matrices(
     Ok(full_frag_rt_matrix.insert_axis(Axis(0)))
 }
 
+/// How `create_final_dataframe` treats the `frag_repeat_num` axis, i.e. the
+/// sub-windows `extract_width` cuts each fragment's tolerance window into.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RepeatReduction {
+    /// One row per fragment and sub-window, with a `Repeat` column.
+    Full,
+    /// Sum over the sub-windows (original behaviour).
+    Sum,
+    /// Per-point maximum over the sub-windows.
+    Max,
+    /// Per fragment, the sub-window at the intensity-weighted centre of its
+    /// repeat profile (the middle one when the fragment has no signal).
+    Centre,
+}
+
+impl RepeatReduction {
+    pub fn from_name(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name {
+            "full" => Ok(RepeatReduction::Full),
+            "sum" => Ok(RepeatReduction::Sum),
+            "max" => Ok(RepeatReduction::Max),
+            "centre" | "center" => Ok(RepeatReduction::Centre),
+            _ => Err(format!("Invalid repeat_reduction: {}. Use sum, max, centre or full.", name).into()),
+        }
+    }
+    
+    pub fn name(&self) -> &'static str {
+        match self {
+            RepeatReduction::Full => "full",
+            RepeatReduction::Sum => "sum",
+            RepeatReduction::Max => "max",
+            RepeatReduction::Centre => "centre",
+        }
+    }
+}
+
+/// Collapse (repeats, fragments, rt) to (fragments, rt); `Full` keeps every
+/// sub-window as its own row (fragment-major).
+fn reduce_repeats(rsm: ArrayView3<f32>, reduction: RepeatReduction) -> Array2<f32> {
+    let (n_repeats, n_frags, n_rt) = rsm.dim();
+    match reduction {
+        RepeatReduction::Full => {
+            let mut rows = Array2::<f32>::zeros((n_frags * n_repeats, n_rt));
+            for frag_idx in 0..n_frags {
+                for repeat in 0..n_repeats {
+                    rows.row_mut(frag_idx * n_repeats + repeat).assign(&rsm.slice(s![repeat, frag_idx, ..]));
+                }
+            }
+            rows
+        }
+        RepeatReduction::Sum => rsm.sum_axis(Axis(0)),
+        RepeatReduction::Max => rsm.fold_axis(Axis(0), 0.0f32, |&acc, &x| acc.max(x)),
+        RepeatReduction::Centre => {
+            let mut rows = Array2::<f32>::zeros((n_frags, n_rt));
+            for frag_idx in 0..n_frags {
+                let profile = rsm.slice(s![.., frag_idx, ..]).sum_axis(Axis(1));
+                let total: f32 = profile.sum();
+                let centre = if total > 0.0 {
+                    let weighted: f32 = profile.iter().enumerate().map(|(repeat, &w)| repeat as f32 * w).sum();
+                    ((weighted / total).round() as usize).min(n_repeats - 1)
+                } else {
+                    n_repeats / 2
+                };
+                rows.row_mut(frag_idx).assign(&rsm.slice(s![centre, frag_idx, ..]));
+            }
+            rows
+        }
+    }
+}
+
 pub fn create_final_dataframe(
     rsm_matrix: &Array4<f32>,
     frag_info: &Array3<f32>,
     all_rt: &[f32],
     i: usize,
+    reduction: RepeatReduction,
 ) -> Result<DataFrame, Box<dyn Error>> {
     // Aggregate across repeat dimension
-    let aggregated_x_sum = rsm_matrix.sum_axis(Axis(1));
-    
-    // Extract data for this precursor
-    let precursor_data = aggregated_x_sum.slice(s![0, .., ..]);
+    let precursor_data = reduce_repeats(rsm_matrix.index_axis(Axis(0), 0), reduction);
     let precursor_frag_info = frag_info.slice(s![i, .., ..]);
     
-    let total_frags = precursor_data.shape()[0];
+    let total_rows = precursor_data.shape()[0];
+    let n_repeats = if reduction == RepeatReduction::Full { rsm_matrix.shape()[1] } else { 1 };
     let mut columns = Vec::new();
     
     // Add RT columns
     for (rt_idx, &rt_val) in all_rt.iter().enumerate() {
-        let col_data: Vec<f32> = (0..total_frags)
-            .map(|frag_idx| precursor_data[[frag_idx, rt_idx]])
+        let col_data: Vec<f32> = (0..total_rows)
+            .map(|row_idx| precursor_data[[row_idx, rt_idx]])
             .collect();
         columns.push(Series::new(&format!("{:.6}", rt_val), col_data));
     }
     
     // Add fragment info columns
-    let info_names = ["ProductMz", "LibraryIntensity", "frag_type", "FragmentType"];
-    for col_idx in 0..4.min(precursor_frag_info.shape()[1]) {
-        let col_data: Vec<f32> = (0..total_frags)
-            .map(|row_idx| precursor_frag_info[[row_idx, col_idx]])
+    let info_names = ["ProductMz", "LibraryIntensity", "frag_type", "FragmentType", "Interference"];
+    for col_idx in 0..info_names.len().min(precursor_frag_info.shape()[1]) {
+        let col_data: Vec<f32> = (0..total_rows)
+            .map(|row_idx| precursor_frag_info[[row_idx / n_repeats, col_idx]])
             .collect();
         columns.push(Series::new(info_names[col_idx], col_data));
     }
+    if reduction == RepeatReduction::Full {
+        let repeats: Vec<u32> = (0..total_rows).map(|row_idx| (row_idx % n_repeats) as u32).collect();
+        columns.push(Series::new("Repeat", repeats));
+    }
     
     Ok(DataFrame::new(columns)?)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Three repeats of two fragments over two RT points; fragment 0 peaks in
+    // the last repeat, fragment 1 has no signal
+    fn rsm() -> Array3<f32> {
+        Array3::from_shape_fn((3, 2, 2), |(repeat, frag, rt)| if frag == 0 { (repeat * repeat) as f32 + rt as f32 } else { 0.0 })
+    }
+
+    #[test]
+    fn sum_and_max_collapse_the_repeats() {
+        let rsm = rsm();
+        assert_eq!(reduce_repeats(rsm.view(), RepeatReduction::Sum).row(0).to_vec(), vec![5.0, 8.0]);
+        assert_eq!(reduce_repeats(rsm.view(), RepeatReduction::Max).row(0).to_vec(), vec![4.0, 5.0]);
+    }
+
+    #[test]
+    fn full_keeps_one_row_per_fragment_and_repeat() {
+        let full = reduce_repeats(rsm().view(), RepeatReduction::Full);
+        assert_eq!(full.dim(), (6, 2));
+        assert_eq!(full.row(2).to_vec(), vec![4.0, 5.0]);
+        assert_eq!(full.row(3).to_vec(), vec![0.0, 0.0]);
+    }
+
+    #[test]
+    fn centre_takes_the_weighted_repeat_or_the_middle_one() {
+        let centre = reduce_repeats(rsm().view(), RepeatReduction::Centre);
+        // Fragment 0: repeat weights 1, 3, 9 -> centre (3 + 18) / 13 = 1.6 -> repeat 2
+        assert_eq!(centre.row(0).to_vec(), vec![4.0, 5.0]);
+        assert_eq!(centre.row(1).to_vec(), vec![0.0, 0.0]);
+    }
+}
@@ -0,0 +1,247 @@
+// File: src/quant.rs
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use ndarray::{Array3, Array4, Axis, s};
+
+use crate::config::QuantConfig;
+use crate::peaks::{ChromPeak, PeakGroupResult, peak_columns};
+use crate::utils::VARIANT_ORIGINAL;
+
+/// Area of one library fragment between the peak boundaries.
+#[derive(Debug, Clone)]
+pub struct FragmentQuant {
+    pub product_mz: f32,
+    pub fragment_type: f32,
+    pub library_intensity: f32,
+    pub area_trapezoid: f32, // intensity x minutes
+    pub area_sum: f32,
+    pub correlation: f32,    // with the summed fragment trace inside the peak
+    pub interfered: bool,    // shared with a co-isolated precursor (interference mask)
+    pub used: bool,          // contributed to the precursor quantity
+}
+
+#[derive(Debug, Clone)]
+pub struct PrecursorQuant {
+    pub quantity: f32,
+    pub fragments: Vec<FragmentQuant>,
+}
+
+/// Subtract the background under the peak: `linear` removes the line
+/// between the boundary points, `min` the lower boundary value.
+fn subtract_background(values: &mut [f32], rts: &[f32], method: &str) -> Result<(), Box<dyn Error>> {
+    let (Some(&first), Some(&last)) = (values.first(), values.last()) else { return Ok(()) };
+    match method {
+        "none" => {}
+        "min" => {
+            let floor = first.min(last);
+            values.iter_mut().for_each(|v| *v = (*v - floor).max(0.0));
+        }
+        "linear" => {
+            let (rt0, rt1) = (rts[0], rts[rts.len() - 1]);
+            let span = (rt1 - rt0).max(f32::EPSILON);
+            for (v, &rt) in values.iter_mut().zip(rts) {
+                let baseline = first + (last - first) * (rt - rt0) / span;
+                *v = (*v - baseline).max(0.0);
+            }
+        }
+        other => return Err(format!("Invalid background method: {}. Use none, min or linear.", other).into()),
+    }
+    Ok(())
+}
+
+fn trapezoid(values: &[f32], rts: &[f32]) -> f32 {
+    values
+        .windows(2)
+        .zip(rts.windows(2))
+        .map(|(v, t)| 0.5 * (v[0] + v[1]) * (t[1] - t[0]))
+        .sum()
+}
+
+pub fn pearson(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len() as f32;
+    if n < 2.0 {
+        return 0.0;
+    }
+    let (mean_a, mean_b) = (a.iter().sum::<f32>() / n, b.iter().sum::<f32>() / n);
+    let (mut cov, mut var_a, mut var_b) = (0.0f32, 0.0f32, 0.0f32);
+    for (&x, &y) in a.iter().zip(b) {
+        cov += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a).powi(2);
+        var_b += (y - mean_b).powi(2);
+    }
+    if var_a <= 0.0 || var_b <= 0.0 { 0.0 } else { cov / (var_a * var_b).sqrt() }
+}
+
+/// Integrate every original-variant MS2 fragment between the peak boundaries
+/// (MS2 columns only, see `column_ms_levels`) and combine them into a precursor quantity (`top_n`, `all` or `robust`).
+/// `robust` drops fragments whose shape correlates poorly with the summed
+/// trace (likely interfered) or that carry the interference mask before
+/// taking the top-N.
+pub fn quantify_peak_group(
+    rsm_matrix: &Array4<f32>,
+    frag_info: &Array3<f32>,
+    all_rt: &[f32],
+    ms2_columns: &[bool],
+    peak: &ChromPeak,
+    config: &QuantConfig,
+) -> Result<PrecursorQuant, Box<dyn Error>> {
+    if !matches!(config.integration.as_str(), "trapezoid" | "sum") {
+        return Err(format!("Invalid integration: {}. Use trapezoid or sum.", config.integration).into());
+    }
+    let summed = rsm_matrix.sum_axis(Axis(1));
+    let precursor_data = summed.slice(s![0, .., ..]);
+    let columns = peak_columns(peak, ms2_columns);
+    let rts: Vec<f32> = columns.iter().map(|&k| all_rt[k]).collect();
+
+    let mut traces = Vec::new();
+    let mut fragments = Vec::new();
+    for row in 0..frag_info.shape()[1] {
+        if frag_info[[0, row, 2]] != VARIANT_ORIGINAL || frag_info[[0, row, 0]] <= 0.0 {
+            continue;
+        }
+        let mut trace: Vec<f32> = columns.iter().map(|&k| precursor_data[[row, k]]).collect();
+        subtract_background(&mut trace, &rts, &config.background)?;
+        fragments.push(FragmentQuant {
+            product_mz: frag_info[[0, row, 0]],
+            fragment_type: frag_info[[0, row, 3]],
+            library_intensity: frag_info[[0, row, 1]],
+            area_trapezoid: trapezoid(&trace, &rts),
+            area_sum: trace.iter().sum(),
+            correlation: 0.0,
+            interfered: frag_info.shape()[2] > 4 && frag_info[[0, row, 4]] > 0.0,
+            used: false,
+        });
+        traces.push(trace);
+    }
+
+    let mut profile = vec![0.0f32; rts.len()];
+    for trace in &traces {
+        for (total, &v) in profile.iter_mut().zip(trace) {
+            *total += v;
+        }
+    }
+    for (fragment, trace) in fragments.iter_mut().zip(&traces) {
+        fragment.correlation = pearson(trace, &profile);
+    }
+
+    let area = |f: &FragmentQuant| if config.integration == "sum" { f.area_sum } else { f.area_trapezoid };
+    let mut ranked: Vec<usize> = (0..fragments.len())
+        .filter(|&k| area(&fragments[k]) > 0.0)
+        .filter(|&k| config.method != "robust" || (fragments[k].correlation >= config.min_correlation && !fragments[k].interfered))
+        .collect();
+    ranked.sort_by(|&a, &b| area(&fragments[b]).total_cmp(&area(&fragments[a])));
+    match config.method.as_str() {
+        "all" => {}
+        "top_n" | "robust" => ranked.truncate(config.top_n),
+        other => return Err(format!("Invalid quant method: {}. Use top_n, all or robust.", other).into()),
+    }
+
+    let mut quantity = 0.0f32;
+    for &k in &ranked {
+        fragments[k].used = true;
+        quantity += area(&fragments[k]);
+    }
+
+    Ok(PrecursorQuant { quantity, fragments })
+}
+
+/// Precursor quantities (`quant.tsv`) and per-fragment areas (`fragment_quant.tsv`).
+pub fn write_quant_tables(
+    results: &[PeakGroupResult],
+    run: &str,
+    precursor_path: &str,
+    fragment_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut precursor_writer = BufWriter::new(File::create(precursor_path)?);
+    let mut fragment_writer = BufWriter::new(File::create(fragment_path)?);
+    writeln!(precursor_writer, "run\tprecursor_id\tdecoy\tcandidate\tquantity\tn_fragments")?;
+    writeln!(fragment_writer, "run\tprecursor_id\tdecoy\tcandidate\tproduct_mz\tfragment_type\tlibrary_intensity\tarea_trapezoid\tarea_sum\tcorrelation\tinterfered\tused")?;
+
+    for result in results {
+        let Some(quant) = &result.quant else { continue };
+        let candidate = result.candidate_rank.map(|r| r.to_string()).unwrap_or_default();
+        let n_used = quant.fragments.iter().filter(|f| f.used).count();
+        writeln!(precursor_writer, "{}\t{}\t{}\t{}\t{:.3}\t{}", run, result.precursor_id, result.decoy as u8, candidate, quant.quantity, n_used)?;
+        for f in &quant.fragments {
+            writeln!(
+                fragment_writer,
+                "{}\t{}\t{}\t{}\t{:.4}\t{}\t{:.4}\t{:.3}\t{:.3}\t{:.4}\t{}\t{}",
+                run, result.precursor_id, result.decoy as u8, candidate, f.product_mz, f.fragment_type, f.library_intensity,
+                f.area_trapezoid, f.area_sum, f.correlation, f.interfered as u8, f.used as u8
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RTS: [f32; 5] = [10.0, 10.1, 10.2, 10.3, 10.4];
+
+    // A triangle, twice the triangle, a trace anti-correlated with both and a
+    // non-original variant; every trace is split evenly over two repeats.
+    fn group(columns: usize) -> (Array4<f32>, Array3<f32>) {
+        let traces = [[0.0, 2.0, 4.0, 2.0, 0.0], [0.0, 4.0, 8.0, 4.0, 0.0], [3.0, 0.0, 0.0, 0.0, 3.0], [90.0; 5]];
+        let rsm = Array4::from_shape_fn((1, 2, 4, 5), |(_, _, f, k)| 0.5 * traces[f][k]);
+        let frag_info = Array3::from_shape_fn((1, 4, columns), |(_, f, c)| match c {
+            0 => 300.0 + 100.0 * f as f32,
+            1 => 1.0,
+            2 if f == 3 => 1.0,
+            2 => VARIANT_ORIGINAL,
+            3 => 2.0,
+            _ => (f == 1) as u8 as f32,
+        });
+        (rsm, frag_info)
+    }
+
+    fn quantify(columns: usize, integration: &str, method: &str, top_n: usize) -> Result<PrecursorQuant, Box<dyn Error>> {
+        let (rsm, frag_info) = group(columns);
+        let peak = ChromPeak { apex_idx: 2, left_idx: 0, right_idx: 4, apex_rt: 10.2, left_rt: 10.0, right_rt: 10.4, fwhm: 0.2, apex_intensity: 12.0 };
+        let config = QuantConfig { integration: integration.to_string(), method: method.to_string(), top_n, ..QuantConfig::default() };
+        quantify_peak_group(&rsm, &frag_info, &RTS, &[true; 5], &peak, &config)
+    }
+
+    #[test]
+    fn background_is_removed_under_the_boundary_line() {
+        let mut linear = vec![1.0, 2.0, 7.0, 4.0, 5.0];
+        subtract_background(&mut linear, &RTS, "linear").unwrap();
+        assert!(linear.iter().zip([0.0, 0.0, 4.0, 0.0, 0.0]).all(|(v, e)| (v - e).abs() < 1e-4), "{:?}", linear);
+
+        let mut min = vec![1.0, 2.0, 7.0, 4.0, 5.0];
+        subtract_background(&mut min, &RTS, "min").unwrap();
+        assert_eq!(min, [0.0, 1.0, 6.0, 3.0, 4.0]);
+        assert!(subtract_background(&mut min, &RTS, "median").is_err());
+    }
+
+    #[test]
+    fn trapezoid_and_sum_areas_of_the_top_fragments() {
+        let quant = quantify(4, "trapezoid", "top_n", 2).unwrap();
+        assert_eq!(quant.fragments.len(), 3);
+        let areas: Vec<f32> = quant.fragments.iter().map(|f| f.area_trapezoid).collect();
+        assert!(areas.iter().zip([0.8, 1.6, 0.3]).all(|(a, e)| (a - e).abs() < 1e-4), "{:?}", areas);
+        assert_eq!(quant.fragments.iter().map(|f| f.area_sum).collect::<Vec<_>>(), [8.0, 16.0, 6.0]);
+        assert_eq!(quant.fragments.iter().map(|f| f.used).collect::<Vec<_>>(), [true, true, false]);
+        assert!((quant.quantity - 2.4).abs() < 1e-4);
+
+        assert_eq!(quantify(4, "sum", "top_n", 2).unwrap().quantity, 24.0);
+        assert!((quantify(4, "trapezoid", "all", 2).unwrap().quantity - 2.7).abs() < 1e-4);
+        assert!(quantify(4, "simpson", "top_n", 2).is_err());
+        assert!(quantify(4, "trapezoid", "median", 2).is_err());
+    }
+
+    #[test]
+    fn robust_skips_poorly_correlated_and_interfered_fragments() {
+        let quant = quantify(4, "trapezoid", "robust", 3).unwrap();
+        assert!(quant.fragments[0].correlation > 0.95 && quant.fragments[1].correlation > 0.95);
+        assert!(quant.fragments[2].correlation < 0.0);
+        assert!((quant.quantity - 2.4).abs() < 1e-4);
+
+        // The interference mask column flags the second fragment
+        let quant = quantify(5, "trapezoid", "robust", 3).unwrap();
+        assert!(quant.fragments[1].interfered && !quant.fragments[1].used);
+        assert!((quant.quantity - 0.8).abs() < 1e-4);
+    }
+}
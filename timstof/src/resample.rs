@@ -0,0 +1,239 @@
+// File: src/resample.rs
+use std::error::Error;
+use std::sync::Arc;
+use ndarray::Array4;
+
+use crate::config::ResamplingConfig;
+use crate::processing::ExtractedPrecursor;
+use crate::utils::MS1_ISOTOPE_COUNT;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResampleKernel {
+    /// Value of the closest observed point.
+    Nearest,
+    /// Linear interpolation between the two neighbouring observed points.
+    Linear,
+    /// Gaussian-weighted average of observed points (sigma = one grid step).
+    Gaussian,
+}
+
+impl ResampleKernel {
+    pub fn from_name(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name {
+            "nearest" => Ok(ResampleKernel::Nearest),
+            "linear" => Ok(ResampleKernel::Linear),
+            "gaussian" => Ok(ResampleKernel::Gaussian),
+            _ => Err(format!("Invalid resampling kernel: {}. Use nearest, linear or gaussian.", name).into()),
+        }
+    }
+}
+
+/// Grid step in minutes.
+pub fn grid_step_minutes(config: &ResamplingConfig) -> Result<f32, Box<dyn Error>> {
+    match config.unit.as_str() {
+        "minutes" | "min" => Ok(config.spacing),
+        "seconds" | "s" => Ok(config.spacing / 60.0),
+        other => Err(format!("Invalid resampling unit: {}. Use minutes or seconds.", other).into()),
+    }
+}
+
+/// `n_points` equally spaced RTs (minutes) centred on `center_rt`.
+pub fn rt_grid(center_rt: f32, config: &ResamplingConfig) -> Result<Vec<f32>, Box<dyn Error>> {
+    let step = grid_step_minutes(config)?;
+    let half_span = (config.n_points.saturating_sub(1)) as f32 * 0.5 * step;
+    Ok((0..config.n_points)
+        .map(|k| center_rt - half_span + k as f32 * step)
+        .collect())
+}
+
+/// RT range to extract so the grid around `center_rt` is fully covered,
+/// with one extra grid step on each side for the interpolation neighbours.
+pub fn grid_extraction_range(center_rt: f32, config: &ResamplingConfig) -> Result<(f32, f32), Box<dyn Error>> {
+    let grid = rt_grid(center_rt, config)?;
+    let step = grid_step_minutes(config)?;
+    match (grid.first(), grid.last()) {
+        (Some(&first), Some(&last)) => Ok((first - step, last + step)),
+        _ => Ok((center_rt, center_rt)),
+    }
+}
+
+/// Value of the trace (`values` at sorted `rts`) at `target`; 0 outside the observed range.
+pub fn interpolate(rts: &[f32], values: &[f32], target: f32, kernel: ResampleKernel, step: f32) -> f32 {
+    // Grid points outside the observed range stay 0, like the zero padding of get_rt_list
+    let (Some(&first), Some(&last)) = (rts.first(), rts.last()) else { return 0.0 };
+    if target < first || target > last {
+        return 0.0;
+    }
+
+    let upper = rts.partition_point(|&rt| rt < target).min(rts.len() - 1);
+    let lower = upper.saturating_sub(1);
+
+    match kernel {
+        ResampleKernel::Nearest => {
+            if (target - rts[lower]).abs() <= (rts[upper] - target).abs() { values[lower] } else { values[upper] }
+        }
+        ResampleKernel::Linear => {
+            let span = rts[upper] - rts[lower];
+            if span <= 0.0 {
+                values[upper]
+            } else {
+                let w = (target - rts[lower]) / span;
+                values[lower] * (1.0 - w) + values[upper] * w
+            }
+        }
+        ResampleKernel::Gaussian => {
+            let sigma = step.max(f32::EPSILON);
+            let start = rts.partition_point(|&rt| rt < target - 3.0 * sigma);
+            let end = rts.partition_point(|&rt| rt <= target + 3.0 * sigma);
+            let (mut weighted, mut total) = (0.0f32, 0.0f32);
+            for (&rt, &value) in rts[start..end].iter().zip(&values[start..end]) {
+                let d = (rt - target) / sigma;
+                let w = (-0.5 * d * d).exp();
+                weighted += w * value;
+                total += w;
+            }
+            if total > 0.0 { weighted / total } else { 0.0 }
+        }
+    }
+}
+
+/// Interpolate every trace of `extracted` onto the fixed grid around `center_rt`,
+/// each row over the columns of its own MS level only (zero padding and, on
+/// the frame axis, the other level's frames are ignored).
+pub fn resample_extraction(
+    extracted: &ExtractedPrecursor,
+    center_rt: f32,
+    config: &ResamplingConfig,
+) -> Result<ExtractedPrecursor, Box<dyn Error>> {
+    let kernel = ResampleKernel::from_name(&config.kernel)?;
+    let step = grid_step_minutes(config)?;
+    let grid = rt_grid(center_rt, config)?;
+
+    // Observed (column, RT) of one MS level, sorted by RT
+    let observed_columns = |mask: &[bool]| -> (Vec<usize>, Vec<f32>) {
+        let mut observed: Vec<(usize, f32)> = extracted
+            .all_rt
+            .iter()
+            .enumerate()
+            .filter(|&(idx, &rt)| rt > 0.0 && mask[idx])
+            .map(|(idx, &rt)| (idx, rt))
+            .collect();
+        observed.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+        observed.into_iter().unzip()
+    };
+    let ms1_observed = observed_columns(&extracted.ms1_columns);
+    let ms2_observed = observed_columns(&extracted.ms2_columns);
+
+    let shape = extracted.rsm_matrix.shape();
+    let (n_prec, n_repeat, n_frag) = (shape[0], shape[1], shape[2]);
+    let mut rsm_matrix = Array4::<f32>::zeros((n_prec, n_repeat, n_frag, grid.len()));
+
+    let mut values = Vec::new();
+    for p in 0..n_prec {
+        for r in 0..n_repeat {
+            for f in 0..n_frag {
+                // MS1 isotope rows come first
+                let (columns, rts) = if f < MS1_ISOTOPE_COUNT { &ms1_observed } else { &ms2_observed };
+                values.clear();
+                values.extend(columns.iter().map(|&idx| extracted.rsm_matrix[[p, r, f, idx]]));
+                if values.iter().all(|&v| v == 0.0) {
+                    continue;
+                }
+                for (g, &target) in grid.iter().enumerate() {
+                    rsm_matrix[[p, r, f, g]] = interpolate(rts, &values, target, kernel, step);
+                }
+            }
+        }
+    }
+
+    Ok(ExtractedPrecursor {
+        rsm_matrix,
+        frag_info: extracted.frag_info.clone(),
+        ms1_columns: vec![true; grid.len()],
+        ms2_columns: vec![true; grid.len()],
+        all_rt: grid,
+        ms1_peaks: Arc::clone(&extracted.ms1_peaks),
+        ms2_peaks: Arc::clone(&extracted.ms2_peaks),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array3;
+
+    fn grid_config(n_points: usize, spacing: f32, unit: &str, kernel: &str) -> ResamplingConfig {
+        ResamplingConfig { enabled: true, n_points, spacing, unit: unit.to_string(), kernel: kernel.to_string() }
+    }
+
+    fn assert_all_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        assert!(actual.iter().zip(expected).all(|(a, e)| (a - e).abs() < 1e-3), "{:?} vs {:?}", actual, expected);
+    }
+
+    // RTs 10.0, 10.1, ..., 11.0 with value 100 * (rt - 10)
+    fn ramp() -> (Vec<f32>, Vec<f32>) {
+        let rts: Vec<f32> = (0..=10).map(|k| 10.0 + 0.1 * k as f32).collect();
+        let values = rts.iter().map(|rt| 100.0 * (rt - 10.0)).collect();
+        (rts, values)
+    }
+
+    #[test]
+    fn grid_is_centred_and_extraction_adds_one_step() {
+        let config = grid_config(5, 3.0, "seconds", "linear");
+        assert_all_close(&rt_grid(10.0, &config).unwrap(), &[9.9, 9.95, 10.0, 10.05, 10.1]);
+        let (rt_min, rt_max) = grid_extraction_range(10.0, &config).unwrap();
+        assert_all_close(&[rt_min, rt_max], &[9.85, 10.15]);
+
+        assert!(rt_grid(10.0, &grid_config(5, 3.0, "hours", "linear")).is_err());
+        assert!(ResampleKernel::from_name("cubic").is_err());
+    }
+
+    #[test]
+    fn linear_and_nearest_reproduce_a_ramp() {
+        let (rts, values) = ramp();
+        let targets = [10.0, 10.24, 10.26, 10.55, 11.0];
+        let at = |kernel| targets.iter().map(|&t| interpolate(&rts, &values, t, kernel, 0.05)).collect::<Vec<f32>>();
+
+        assert_all_close(&at(ResampleKernel::Linear), &[0.0, 24.0, 26.0, 55.0, 100.0]);
+        assert_all_close(&at(ResampleKernel::Nearest), &[0.0, 20.0, 30.0, 50.0, 100.0]);
+        // Symmetric weights keep the ramp value away from the edges
+        assert!((interpolate(&rts, &values, 10.5, ResampleKernel::Gaussian, 0.1) - 50.0).abs() < 1e-3);
+        assert!((interpolate(&rts, &values, 10.55, ResampleKernel::Gaussian, 0.1) - 55.0).abs() < 0.5);
+
+        for kernel in [ResampleKernel::Nearest, ResampleKernel::Linear, ResampleKernel::Gaussian] {
+            assert_eq!(interpolate(&rts, &values, 9.99, kernel, 0.05), 0.0);
+            assert_eq!(interpolate(&rts, &values, 11.01, kernel, 0.05), 0.0);
+            assert_eq!(interpolate(&[], &[], 10.0, kernel, 0.05), 0.0);
+        }
+    }
+
+    #[test]
+    fn each_row_is_resampled_over_its_own_ms_level() {
+        // Alternating MS1 / MS2 frames 0.05 min apart; the MS1 isotope ramps
+        // 0..100 over the MS1 frames, the fragment 100..0 over the MS2 frames
+        let n_rt = 20;
+        let all_rt: Vec<f32> = (0..n_rt).map(|k| 10.0 + 0.05 * k as f32).collect();
+        let mut rsm = Array4::<f32>::zeros((1, 1, 72, n_rt));
+        for (k, &rt) in all_rt.iter().enumerate() {
+            let level_value = 100.0 * (rt - 10.0) / 0.95;
+            if k % 2 == 0 {
+                rsm[[0, 0, 0, k]] = level_value;
+            } else {
+                rsm[[0, 0, 10, k]] = 100.0 - level_value;
+            }
+        }
+        let mut extracted = ExtractedPrecursor::synthetic(rsm, Array3::zeros((1, 72, 4)), all_rt);
+        extracted.ms1_columns = (0..n_rt).map(|k| k % 2 == 0).collect();
+        extracted.ms2_columns = (0..n_rt).map(|k| k % 2 == 1).collect();
+
+        let resampled = resample_extraction(&extracted, 10.45, &grid_config(5, 0.1, "minutes", "linear")).unwrap();
+        assert_all_close(&resampled.all_rt, &[10.25, 10.35, 10.45, 10.55, 10.65]);
+        let row = |f: usize| resampled.rsm_matrix.slice(ndarray::s![0, 0, f, ..]).to_vec();
+        let expected: Vec<f32> = resampled.all_rt.iter().map(|rt| 100.0 * (rt - 10.0) / 0.95).collect();
+        assert_all_close(&row(0), &expected);
+        assert_all_close(&row(10), &expected.iter().map(|v| 100.0 - v).collect::<Vec<f32>>());
+        assert!(row(11).iter().all(|&v| v == 0.0));
+        assert!(resampled.ms1_columns.iter().chain(&resampled.ms2_columns).all(|&c| c));
+    }
+}
@@ -0,0 +1,461 @@
+// File: src/rescore.rs
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::config::RescoringConfig;
+use crate::peaks::PeakGroupResult;
+use crate::scores::SCORE_NAMES;
+use crate::utils::SeededRng;
+
+/// Subscores where only the magnitude matters for the classifier.
+const SIGNED_SCORES: [&str; 4] = ["mass_error_ppm", "ms1_mass_error_ppm", "im_delta", "delta_rt"];
+
+/// Subscores (as magnitudes) where lower is better; negated after
+/// standardisation so every initial score ranks upwards.
+const LOWER_IS_BETTER: [&str; 5] = ["xcorr_coelution", "mass_error_ppm", "ms1_mass_error_ppm", "im_delta", "delta_rt"];
+
+/// Final discriminant and q-values of the best peak group of one precursor.
+#[derive(Debug, Clone)]
+pub struct RescoredPrecursor {
+    pub result_index: usize, // into the peak group list
+    pub d_score: f64,
+    pub q_value: f64,
+    pub protein_q_value: f64,
+}
+
+#[derive(Debug)]
+pub struct RescoringSummary {
+    pub precursors: Vec<RescoredPrecursor>,
+    pub pi0: f64,
+    pub protein_pi0: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Classifier {
+    Lda,
+    Svm,
+}
+
+/// Storey pi0 from decoy-based p-values of the target scores.
+pub fn estimate_pi0(target_scores: &[f64], decoy_scores: &[f64], lambda: f64) -> f64 {
+    if target_scores.is_empty() || decoy_scores.is_empty() {
+        return 1.0;
+    }
+    let mut decoys = decoy_scores.to_vec();
+    decoys.sort_by(|a, b| a.total_cmp(b));
+    let n_above_lambda = target_scores
+        .iter()
+        .filter(|&&s| {
+            let n_ge = decoys.len() - decoys.partition_point(|&d| d < s);
+            n_ge as f64 / decoys.len() as f64 > lambda
+        })
+        .count();
+    (n_above_lambda as f64 / (target_scores.len() as f64 * (1.0 - lambda))).clamp(0.0, 1.0)
+}
+
+/// Target-decoy q-values of `target_scores` (same order), FDR scaled by `pi0`.
+pub fn q_values(target_scores: &[f64], decoy_scores: &[f64], pi0: f64) -> Vec<f64> {
+    let n_targets = target_scores.len();
+    let n_decoys = decoy_scores.len().max(1);
+    let mut decoys = decoy_scores.to_vec();
+    decoys.sort_by(|a, b| a.total_cmp(b));
+
+    let mut order: Vec<usize> = (0..n_targets).collect();
+    order.sort_by(|&a, &b| target_scores[b].total_cmp(&target_scores[a]));
+
+    let mut q = vec![1.0f64; n_targets];
+    for (rank, &idx) in order.iter().enumerate() {
+        let s = target_scores[idx];
+        let n_decoys_ge = decoys.len() - decoys.partition_point(|&d| d < s);
+        let fdr = pi0 * (n_decoys_ge as f64 / n_decoys as f64) * n_targets as f64 / (rank + 1) as f64;
+        q[idx] = fdr.min(1.0);
+    }
+    // q-value = minimal FDR at this or any lower score threshold
+    let mut running = 1.0f64;
+    for &idx in order.iter().rev() {
+        running = running.min(q[idx]);
+        q[idx] = running;
+    }
+    q
+}
+
+fn dot(w: &[f64], x: &[f64]) -> f64 {
+    w.iter().zip(x).map(|(a, b)| a * b).sum()
+}
+
+/// Solve `a x = b` by Gaussian elimination with partial pivoting.
+pub fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        let pivot_row = a[col].clone();
+        for row in (col + 1)..n {
+            let factor = a[row][col] / pivot_row[col];
+            for (v, p) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *v -= factor * p;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+    let mut x = vec![0.0f64; n];
+    for row in (0..n).rev() {
+        let tail: f64 = ((row + 1)..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - tail) / a[row][row];
+    }
+    Some(x)
+}
+
+/// Fisher LDA direction with a small ridge on the pooled covariance.
+fn train_lda(positives: &[&[f64]], negatives: &[&[f64]]) -> Option<Vec<f64>> {
+    let d = positives.first()?.len();
+    let mean = |rows: &[&[f64]]| -> Vec<f64> {
+        let mut m = vec![0.0f64; d];
+        for row in rows {
+            for (acc, v) in m.iter_mut().zip(row.iter()) {
+                *acc += v;
+            }
+        }
+        m.iter().map(|v| v / rows.len() as f64).collect()
+    };
+    let (mu_pos, mu_neg) = (mean(positives), mean(negatives));
+
+    let mut cov = vec![vec![0.0f64; d]; d];
+    for (rows, mu) in [(positives, &mu_pos), (negatives, &mu_neg)] {
+        for row in rows {
+            for i in 0..d {
+                for j in 0..d {
+                    cov[i][j] += (row[i] - mu[i]) * (row[j] - mu[j]);
+                }
+            }
+        }
+    }
+    let n = (positives.len() + negatives.len()) as f64;
+    for (i, cov_row) in cov.iter_mut().enumerate() {
+        for v in cov_row.iter_mut() {
+            *v /= n;
+        }
+        cov_row[i] += 1e-3;
+    }
+    let delta: Vec<f64> = mu_pos.iter().zip(&mu_neg).map(|(p, q)| p - q).collect();
+    solve(cov, delta)
+}
+
+/// Linear SVM (Pegasos, hinge loss); the bias is dropped since only the ranking matters.
+fn train_svm(positives: &[&[f64]], negatives: &[&[f64]], lambda: f64, rng: &mut SeededRng) -> Option<Vec<f64>> {
+    let d = positives.first()?.len();
+    let mut samples: Vec<(&[f64], f64)> = positives.iter().map(|&x| (x, 1.0)).collect();
+    samples.extend(negatives.iter().map(|&x| (x, -1.0)));
+
+    let (mut w, mut b) = (vec![0.0f64; d], 0.0f64);
+    let mut t = 0.0f64;
+    for _ in 0..20 {
+        rng.shuffle(&mut samples);
+        for &(x, y) in &samples {
+            t += 1.0;
+            let eta = 1.0 / (lambda * t);
+            let margin = y * (dot(&w, x) + b);
+            w.iter_mut().for_each(|v| *v *= 1.0 - eta * lambda);
+            if margin < 1.0 {
+                for (v, xi) in w.iter_mut().zip(x) {
+                    *v += eta * y * xi;
+                }
+                b += eta * y;
+            }
+        }
+    }
+    Some(w)
+}
+
+/// Index of the best-scoring peak group of each precursor among `indices`.
+fn best_per_precursor(indices: &[usize], groups: &[usize], scores: &[f64]) -> Vec<usize> {
+    let mut best: HashMap<usize, usize> = HashMap::new();
+    for &i in indices {
+        best.entry(groups[i])
+            .and_modify(|b| if scores[i] > scores[*b] { *b = i })
+            .or_insert(i);
+    }
+    let mut picked: Vec<usize> = best.into_values().collect();
+    picked.sort_unstable();
+    picked
+}
+
+/// Standardised features with decoy labels and precursor group per peak group.
+struct TrainingData<'a> {
+    features: &'a [Vec<f64>],
+    decoy: &'a [bool],
+    groups: &'a [usize],
+}
+
+/// Semi-supervised training on `train`: start from the initial score, then
+/// repeatedly retrain on confident targets vs. all best decoys.
+fn train_fold(
+    data: &TrainingData,
+    train: &[usize],
+    initial: usize,
+    classifier: Classifier,
+    config: &RescoringConfig,
+    rng: &mut SeededRng,
+) -> Vec<f64> {
+    let TrainingData { features, decoy, groups } = *data;
+    let d = features[0].len();
+    let mut weights: Vec<f64> = (0..d).map(|k| if k == initial { 1.0 } else { 0.0 }).collect();
+
+    for iteration in 0..config.iterations {
+        let scores: Vec<f64> = features.iter().map(|x| dot(&weights, x)).collect();
+        let best = best_per_precursor(train, groups, &scores);
+        let (targets, decoys): (Vec<usize>, Vec<usize>) = best.into_iter().partition(|&i| !decoy[i]);
+        let target_scores: Vec<f64> = targets.iter().map(|&i| scores[i]).collect();
+        let decoy_scores: Vec<f64> = decoys.iter().map(|&i| scores[i]).collect();
+
+        let fdr = if iteration == 0 { config.initial_fdr } else { config.iteration_fdr };
+        let q = q_values(&target_scores, &decoy_scores, 1.0);
+        let positives: Vec<&[f64]> = targets.iter().zip(&q).filter(|(_, &q)| q <= fdr).map(|(&i, _)| features[i].as_slice()).collect();
+        let negatives: Vec<&[f64]> = decoys.iter().map(|&i| features[i].as_slice()).collect();
+        if positives.len() < 5 || negatives.len() < 5 {
+            break;
+        }
+
+        let trained = match classifier {
+            Classifier::Lda => train_lda(&positives, &negatives),
+            Classifier::Svm => train_svm(&positives, &negatives, config.svm_lambda, rng),
+        };
+        match trained {
+            Some(w) => weights = w,
+            None => break,
+        }
+    }
+    weights
+}
+
+fn mean_sd(values: &[f64]) -> (f64, f64) {
+    if values.is_empty() {
+        return (0.0, 1.0);
+    }
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let sd = (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n).sqrt();
+    (mean, if sd > 0.0 { sd } else { 1.0 })
+}
+
+/// mProphet-style rescoring of all scored peak groups: k-fold semi-supervised
+/// LDA/SVM, best peak group per precursor, pi0-corrected precursor and
+/// protein q-values.
+pub fn rescore_peak_groups(results: &[PeakGroupResult], config: &RescoringConfig) -> Result<RescoringSummary, Box<dyn Error>> {
+    let classifier = match config.classifier.as_str() {
+        "lda" => Classifier::Lda,
+        "svm" => Classifier::Svm,
+        other => return Err(format!("Invalid classifier: {}. Use lda or svm.", other).into()),
+    };
+    let initial = SCORE_NAMES
+        .iter()
+        .position(|&name| name == config.initial_score)
+        .ok_or_else(|| format!("Unknown initial_score: {}", config.initial_score))?;
+
+    // 1. Feature matrix (standardised; signed errors as magnitudes)
+    let scored: Vec<usize> = (0..results.len()).filter(|&i| results[i].scores.is_some()).collect();
+    if scored.is_empty() {
+        return Err("no scored peak groups to rescore".into());
+    }
+    let mut features: Vec<Vec<f64>> = scored
+        .iter()
+        .map(|&i| {
+            let values = results[i].scores.as_ref().map(|s| s.values()).unwrap_or_default();
+            SCORE_NAMES
+                .iter()
+                .zip(values)
+                .map(|(name, v)| if SIGNED_SCORES.contains(name) { v.abs() as f64 } else { v as f64 })
+                .collect()
+        })
+        .collect();
+    for (k, name) in SCORE_NAMES.iter().enumerate() {
+        let column: Vec<f64> = features.iter().map(|x| x[k]).collect();
+        let (mean, sd) = mean_sd(&column);
+        let sign = if LOWER_IS_BETTER.contains(name) { -1.0 } else { 1.0 };
+        features.iter_mut().for_each(|x| x[k] = sign * (x[k] - mean) / sd);
+    }
+
+    let decoy: Vec<bool> = scored.iter().map(|&i| results[i].decoy).collect();
+    let mut precursor_index: HashMap<&str, usize> = HashMap::new();
+    let groups: Vec<usize> = scored
+        .iter()
+        .map(|&i| {
+            let next = precursor_index.len();
+            *precursor_index.entry(results[i].precursor_id.as_str()).or_insert(next)
+        })
+        .collect();
+    let n_decoy_groups = decoy.iter().filter(|&&d| d).count();
+    if n_decoy_groups == 0 {
+        return Err("rescoring needs decoys (enable [decoys] generate or use a library with decoys)".into());
+    }
+
+    // 2. Cross-validation folds by precursor
+    let mut rng = SeededRng::new(config.seed);
+    let mut precursor_order: Vec<usize> = (0..precursor_index.len()).collect();
+    rng.shuffle(&mut precursor_order);
+    let n_folds = config.folds.max(1);
+    let mut fold_of = vec![0usize; precursor_index.len()];
+    for (rank, &p) in precursor_order.iter().enumerate() {
+        fold_of[p] = rank % n_folds;
+    }
+
+    let mut d_scores = vec![0.0f64; scored.len()];
+    for fold in 0..n_folds {
+        // a single fold trains and scores on everything (no cross-validation)
+        let (train, test): (Vec<usize>, Vec<usize>) = if n_folds > 1 {
+            let (test, train) = (0..scored.len()).partition(|&i| fold_of[groups[i]] == fold);
+            (train, test)
+        } else {
+            ((0..scored.len()).collect(), (0..scored.len()).collect())
+        };
+
+        let data = TrainingData { features: &features, decoy: &decoy, groups: &groups };
+        let weights = train_fold(&data, &train, initial, classifier, config, &mut rng);
+
+        // Normalise by the training decoys so folds share one score scale
+        let raw: Vec<f64> = features.iter().map(|x| dot(&weights, x)).collect();
+        let best_train = best_per_precursor(&train, &groups, &raw);
+        let train_decoys: Vec<f64> = best_train.iter().filter(|&&i| decoy[i]).map(|&i| raw[i]).collect();
+        let (mean, sd) = mean_sd(&train_decoys);
+        for &i in &test {
+            d_scores[i] = (raw[i] - mean) / sd;
+        }
+    }
+
+    // 3. Best peak group per precursor, precursor-level q-values
+    let all: Vec<usize> = (0..scored.len()).collect();
+    let best = best_per_precursor(&all, &groups, &d_scores);
+    let (targets, decoys): (Vec<usize>, Vec<usize>) = best.iter().partition(|&&i| !decoy[i]);
+    let target_scores: Vec<f64> = targets.iter().map(|&i| d_scores[i]).collect();
+    let decoy_scores: Vec<f64> = decoys.iter().map(|&i| d_scores[i]).collect();
+    let pi0 = estimate_pi0(&target_scores, &decoy_scores, config.pi0_lambda);
+    let target_q = q_values(&target_scores, &decoy_scores, pi0);
+    let decoy_q = q_values(&decoy_scores, &decoy_scores, 1.0);
+
+    // 4. Protein level: best precursor per protein; decoy proteins are kept apart
+    let protein_key = |i: usize| {
+        let result = &results[scored[i]];
+        if result.decoy { format!("DECOY_{}", result.protein_id) } else { result.protein_id.clone() }
+    };
+    let mut protein_best: HashMap<String, (f64, bool)> = HashMap::new();
+    for &i in &best {
+        let entry = protein_best.entry(protein_key(i)).or_insert((f64::NEG_INFINITY, decoy[i]));
+        entry.0 = entry.0.max(d_scores[i]);
+    }
+    let mut proteins: Vec<(&String, &(f64, bool))> = protein_best.iter().collect();
+    proteins.sort_by(|a, b| a.0.cmp(b.0));
+    let protein_targets: Vec<f64> = proteins.iter().filter(|p| !p.1 .1).map(|p| p.1 .0).collect();
+    let protein_decoys: Vec<f64> = proteins.iter().filter(|p| p.1 .1).map(|p| p.1 .0).collect();
+    let protein_pi0 = estimate_pi0(&protein_targets, &protein_decoys, config.pi0_lambda);
+    let protein_target_q = q_values(&protein_targets, &protein_decoys, protein_pi0);
+    let mut protein_q: HashMap<&str, f64> = HashMap::new();
+    let mut target_iter = protein_target_q.iter();
+    for (key, (_, is_decoy)) in &proteins {
+        let q = if *is_decoy { 1.0 } else { *target_iter.next().unwrap_or(&1.0) };
+        protein_q.insert(key.as_str(), q);
+    }
+
+    let mut precursors = Vec::with_capacity(best.len());
+    for (list, qs) in [(&targets, &target_q), (&decoys, &decoy_q)] {
+        for (&i, &q) in list.iter().zip(qs.iter()) {
+            precursors.push(RescoredPrecursor {
+                result_index: scored[i],
+                d_score: d_scores[i],
+                q_value: q,
+                protein_q_value: protein_q.get(protein_key(i).as_str()).copied().unwrap_or(1.0),
+            });
+        }
+    }
+    precursors.sort_by(|a, b| b.d_score.total_cmp(&a.d_score));
+
+    Ok(RescoringSummary { precursors, pi0, protein_pi0 })
+}
+
+/// Best peak group per precursor with its discriminant score and q-values.
+pub fn write_rescored_table(results: &[PeakGroupResult], summary: &RescoringSummary, run: &str, path: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "run\tprecursor_id\tprotein_id\tdecoy\tcandidate\tapex_rt\td_score\tq_value\tprotein_q_value")?;
+    for p in &summary.precursors {
+        let result = &results[p.result_index];
+        let candidate = result.candidate_rank.map(|r| r.to_string()).unwrap_or_default();
+        let apex_rt = result.peak.as_ref().map(|peak| peak.apex_rt).unwrap_or(0.0);
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{:.4}\t{:.5}\t{:.6}\t{:.6}",
+            run, result.precursor_id, result.protein_id, result.decoy as u8, candidate,
+            apex_rt, p.d_score, p.q_value, p.protein_q_value
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scores::PeakGroupScores;
+
+    #[test]
+    fn pi0_is_one_when_targets_look_like_decoys() {
+        let scores: Vec<f64> = (1..=100).map(f64::from).collect();
+        assert_eq!(estimate_pi0(&scores, &scores, 0.5), 1.0);
+        assert_eq!(estimate_pi0(&[], &scores, 0.5), 1.0);
+    }
+
+    #[test]
+    fn pi0_is_zero_when_every_target_beats_the_decoys() {
+        let targets = [10.0, 11.0, 12.0];
+        let decoys = [1.0, 2.0, 3.0];
+        assert_eq!(estimate_pi0(&targets, &decoys, 0.5), 0.0);
+    }
+
+    #[test]
+    fn q_values_are_monotone_in_the_score() {
+        // Raw FDRs from the top: 0, 1.0, 0.667, 0.5 -> q-values 0, 0.5, 0.5, 0.5
+        let q = q_values(&[5.0, 4.0, 3.0, 2.0], &[4.5, 0.0], 1.0);
+        let expected = [0.0, 0.5, 0.5, 0.5];
+        for (q, expected) in q.iter().zip(expected) {
+            assert!((q - expected).abs() < 1e-12, "{:?}", q);
+        }
+    }
+
+    #[test]
+    fn q_values_keep_the_target_order_and_scale_with_pi0() {
+        let q = q_values(&[1.0, 10.0, 9.0], &[2.0, 0.5], 0.5);
+        assert_eq!(q, vec![0.25, 0.0, 0.0]);
+    }
+
+    // Every lower-is-better subscore set to `error`: small for targets, large for decoys
+    fn group(id: usize, decoy: bool, error: f32) -> PeakGroupResult {
+        let scores = PeakGroupScores {
+            xcorr_coelution: error.abs(),
+            mass_error_ppm: error,
+            ms1_mass_error_ppm: error,
+            im_delta: error,
+            delta_rt: error,
+            ..PeakGroupScores::default()
+        };
+        PeakGroupResult { scores: Some(scores), ..PeakGroupResult::empty(&format!("p{}", id), decoy) }
+    }
+
+    #[test]
+    fn lower_is_better_initial_scores_rank_targets_first() {
+        let sign = |k: usize| if k.is_multiple_of(2) { 1.0 } else { -1.0 };
+        let mut results: Vec<PeakGroupResult> = (0..20).map(|k| group(k, false, sign(k) * 0.01 * k as f32)).collect();
+        results.extend((0..20).map(|k| group(20 + k, true, sign(k) * (1.0 + 0.1 * k as f32))));
+
+        for name in LOWER_IS_BETTER {
+            let config = RescoringConfig { initial_score: name.to_string(), iterations: 0, folds: 1, ..RescoringConfig::default() };
+            let summary = rescore_peak_groups(&results, &config).unwrap();
+            let (targets, decoys): (Vec<_>, Vec<_>) = summary.precursors.iter().partition(|p| !results[p.result_index].decoy);
+            let lowest_target = targets.iter().map(|p| p.d_score).fold(f64::INFINITY, f64::min);
+            assert!(decoys.iter().all(|p| p.d_score < lowest_target), "{}", name);
+            assert!(targets.iter().all(|p| p.q_value == 0.0), "{}", name);
+        }
+    }
+}
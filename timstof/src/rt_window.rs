@@ -0,0 +1,115 @@
+// File: src/rt_window.rs
+use std::collections::HashMap;
+use std::error::Error;
+use polars::prelude::*;
+
+use crate::config::{ResamplingConfig, RtWindowConfig};
+use crate::utils::{PrecursorLibData, create_report_value_dict};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WindowFit {
+    /// Frames inside the window, cropped or zero-padded to `n_points` (see `fit_rt_list`).
+    Pad,
+    /// Traces interpolated onto `n_points` RTs spanning the window.
+    Resample,
+}
+
+impl WindowFit {
+    pub fn from_name(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name {
+            "pad" => Ok(WindowFit::Pad),
+            "resample" => Ok(WindowFit::Resample),
+            _ => Err(format!("Invalid rt_window fit: {}. Use pad or resample.", name).into()),
+        }
+    }
+}
+
+/// Reported peak width (stop - start, minutes) per precursor, over all of
+/// its report rows (earliest start, latest stop).
+pub fn report_peak_widths(report_df: &DataFrame, config: &RtWindowConfig) -> Result<HashMap<String, f32>, Box<dyn Error>> {
+    let starts = create_report_value_dict(report_df, &config.start_column, f32::min)?;
+    let stops = create_report_value_dict(report_df, &config.stop_column, f32::max)?;
+    Ok(stops
+        .into_iter()
+        .filter_map(|(id, stop)| Some((stop - starts.get(&id)?, id)))
+        .filter(|(width, _)| width.is_finite() && *width > 0.0)
+        .map(|(width, id)| (id, width))
+        .collect())
+}
+
+/// Copy the peak widths onto the prepared precursors; returns how many got one.
+pub fn assign_peak_widths(precursors: &mut [PrecursorLibData], peak_widths: &HashMap<String, f32>) -> usize {
+    let mut n_assigned = 0;
+    for precursor in precursors.iter_mut() {
+        precursor.peak_width = peak_widths.get(&precursor.precursor_id).copied();
+        n_assigned += precursor.peak_width.is_some() as usize;
+    }
+    n_assigned
+}
+
+/// Window width (minutes) of a precursor, `None` without a reported peak width.
+pub fn window_width(precursor: &PrecursorLibData, config: &RtWindowConfig) -> Result<Option<f32>, Box<dyn Error>> {
+    if config.min_width <= 0.0 || config.min_width > config.max_width {
+        return Err(format!(
+            "Invalid rt_window widths: min_width {} must be positive and at most max_width {}.",
+            config.min_width, config.max_width
+        )
+        .into());
+    }
+    if config.n_points < 2 {
+        return Err(format!("Invalid rt_window n_points: {}. Use at least 2.", config.n_points).into());
+    }
+    Ok(precursor
+        .peak_width
+        .map(|width| (width * config.multiplier).clamp(config.min_width, config.max_width)))
+}
+
+/// Resampling grid of `n_points` RTs spanning `width` minutes.
+pub fn window_grid(width: f32, config: &RtWindowConfig) -> ResamplingConfig {
+    ResamplingConfig {
+        enabled: true,
+        n_points: config.n_points,
+        spacing: width / (config.n_points - 1) as f32,
+        unit: "minutes".to_string(),
+        kernel: config.kernel.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn precursor(peak_width: Option<f32>) -> PrecursorLibData {
+        PrecursorLibData { peak_width, ..PrecursorLibData::test_precursor("P", 500.0, 10.0, 1.0, &[300.0]) }
+    }
+
+    #[test]
+    fn window_is_the_scaled_peak_width_clamped_to_the_limits() {
+        let config = RtWindowConfig::default(); // x3, 0.5 .. 3.0 minutes
+        assert_eq!(window_width(&precursor(Some(0.4)), &config).unwrap(), Some(0.4f32 * 3.0));
+        assert_eq!(window_width(&precursor(Some(0.1)), &config).unwrap(), Some(0.5));
+        assert_eq!(window_width(&precursor(Some(2.0)), &config).unwrap(), Some(3.0));
+        assert_eq!(window_width(&precursor(None), &config).unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_widths_and_too_few_points_are_rejected() {
+        let check = |config: RtWindowConfig| window_width(&precursor(Some(1.0)), &config);
+        assert!(check(RtWindowConfig { n_points: 1, ..RtWindowConfig::default() }).is_err());
+        assert!(check(RtWindowConfig { min_width: 0.0, ..RtWindowConfig::default() }).is_err());
+        assert!(check(RtWindowConfig { min_width: 4.0, ..RtWindowConfig::default() }).is_err());
+        assert!(check(RtWindowConfig { n_points: 2, min_width: 3.0, ..RtWindowConfig::default() }).is_ok());
+        assert!(WindowFit::from_name("crop").is_err());
+    }
+
+    #[test]
+    fn grid_spans_the_window_and_widths_are_assigned_by_id() {
+        let grid = window_grid(1.5, &RtWindowConfig { n_points: 4, ..RtWindowConfig::default() });
+        assert_eq!((grid.n_points, grid.spacing, grid.unit.as_str()), (4, 0.5, "minutes"));
+
+        let mut precursors = vec![precursor(None), PrecursorLibData::test_precursor("Q", 600.0, 20.0, 1.0, &[300.0])];
+        let widths = HashMap::from([("Q".to_string(), 0.3)]);
+        assert_eq!(assign_peak_widths(&mut precursors, &widths), 1);
+        assert_eq!((precursors[0].peak_width, precursors[1].peak_width), (None, Some(0.3)));
+    }
+}
@@ -0,0 +1,382 @@
+// File: src/scores.rs
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use ndarray::{Array3, Axis, s};
+
+use crate::config::ExtractionConfig;
+use crate::peaks::{ChromPeak, PeakGroupResult, peak_columns};
+use crate::processing::ExtractedPrecursor;
+use crate::quant::pearson;
+use crate::resample::{ResampleKernel, interpolate};
+use crate::utils::{MS1_ISOTOPE_COUNT, TimsTOFData, VARIANT_ORIGINAL};
+
+/// Names of the feature table columns, in the order of `PeakGroupScores::values`.
+pub const SCORE_NAMES: [&str; 12] = [
+    "xcorr_coelution",
+    "xcorr_shape",
+    "library_dotprod",
+    "spectral_angle",
+    "ms1_ms2_correlation",
+    "mass_error_ppm",
+    "ms1_mass_error_ppm",
+    "im_delta",
+    "log_sn",
+    "n_fragments",
+    "delta_rt",
+    "fwhm",
+];
+
+/// OpenSWATH/mProphet-style subscores of one peak group.
+#[derive(Debug, Clone, Default)]
+pub struct PeakGroupScores {
+    pub xcorr_coelution: f32,     // mean + sd of the best cross-correlation lag over fragment pairs
+    pub xcorr_shape: f32,         // mean best cross-correlation over fragment pairs
+    pub library_dotprod: f32,     // cosine of sqrt-transformed observed areas and library intensities
+    pub spectral_angle: f32,      // normalised spectral contrast angle, 1 = identical
+    pub ms1_ms2_correlation: f32, // monoisotope trace vs summed fragment trace
+    pub mass_error_ppm: f32,      // intensity-weighted fragment m/z error
+    pub ms1_mass_error_ppm: f32,
+    pub im_delta: f32,            // observed - expected ion mobility, 0 when IM is unknown
+    pub log_sn: f32,
+    pub n_fragments: f32,         // fragments with signal at the apex
+    pub delta_rt: f32,            // apex - expected RT (minutes)
+    pub fwhm: f32,
+}
+
+impl PeakGroupScores {
+    pub fn values(&self) -> [f32; 12] {
+        [
+            self.xcorr_coelution,
+            self.xcorr_shape,
+            self.library_dotprod,
+            self.spectral_angle,
+            self.ms1_ms2_correlation,
+            self.mass_error_ppm,
+            self.ms1_mass_error_ppm,
+            self.im_delta,
+            self.log_sn,
+            self.n_fragments,
+            self.delta_rt,
+            self.fwhm,
+        ]
+    }
+}
+
+/// Half-width of the extraction window around `mz`, in Th.
+pub fn mz_tolerance(mz: f32, tolerance: f32, mz_unit: &str) -> f32 {
+    if mz_unit == "Da" { tolerance } else { mz * tolerance * 1e-6 }
+}
+
+/// Intensity-weighted mean ppm error and mobility of the peaks matching
+/// `target_mz` inside `[rt_min, rt_max]`; `None` when nothing matches.
+pub fn matched_peak_stats(
+    peaks: &TimsTOFData,
+    target_mz: f32,
+    tolerance: f32,
+    rt_min: f32,
+    rt_max: f32,
+) -> Option<(f32, f32, f32)> {
+    let (mut weight, mut ppm, mut im) = (0.0f64, 0.0f64, 0.0f64);
+    for k in 0..peaks.mz_values.len() {
+        let (mz, rt) = (peaks.mz_values[k], peaks.rt_values_min[k]);
+        if (mz - target_mz).abs() > tolerance || rt < rt_min || rt > rt_max {
+            continue;
+        }
+        let w = peaks.intensity_values[k] as f64;
+        weight += w;
+        ppm += w * ((mz - target_mz) / target_mz * 1e6) as f64;
+        im += w * peaks.mobility_values[k] as f64;
+    }
+    (weight > 0.0).then(|| ((ppm / weight) as f32, (im / weight) as f32, weight as f32))
+}
+
+fn standardize(values: &[f32]) -> Option<Vec<f32>> {
+    let n = values.len() as f32;
+    let mean = values.iter().sum::<f32>() / n;
+    let sd = (values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n).sqrt();
+    (sd > 0.0).then(|| values.iter().map(|v| (v - mean) / sd).collect())
+}
+
+/// Best (lag, value) of the normalised cross-correlation of two standardised traces.
+fn best_xcorr(a: &[f32], b: &[f32]) -> (i64, f32) {
+    let n = a.len() as i64;
+    let max_lag = (n / 2).max(1);
+    let mut best = (0i64, f32::NEG_INFINITY);
+    for lag in -max_lag..=max_lag {
+        let mut total = 0.0f32;
+        for t in 0..n {
+            let u = t + lag;
+            if u >= 0 && u < n {
+                total += a[t as usize] * b[u as usize];
+            }
+        }
+        let value = total / n as f32;
+        if value > best.1 || (value == best.1 && lag.abs() < best.0.abs()) {
+            best = (lag, value);
+        }
+    }
+    best
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|y| y * y).sum::<f32>().sqrt();
+    if norm > 0.0 { (dot / norm).clamp(-1.0, 1.0) } else { 0.0 }
+}
+
+/// frag_info row of the monoisotopic MS1 trace. MS1 rows are sorted by
+/// descending m/z, so this is the row closest to `precursor_mz` (the lowest
+/// isotope kept when the monoisotope was dropped).
+pub fn monoisotope_row(frag_info: &Array3<f32>, precursor_mz: f32) -> Option<usize> {
+    (0..MS1_ISOTOPE_COUNT.min(frag_info.shape()[1]))
+        .filter(|&row| frag_info[[0, row, 0]] > 0.0)
+        .min_by(|&a, &b| (frag_info[[0, a, 0]] - precursor_mz).abs().total_cmp(&(frag_info[[0, b, 0]] - precursor_mz).abs()))
+}
+
+/// Compute all subscores of the peak group `peak` of an extraction of the
+/// precursor with monoisotopic m/z `precursor_mz`.
+pub fn score_peak_group(
+    extracted: &ExtractedPrecursor,
+    peak: &ChromPeak,
+    precursor_mz: f32,
+    expected_rt: f32,
+    expected_im: f32,
+    extraction: &ExtractionConfig,
+) -> PeakGroupScores {
+    let summed = extracted.rsm_matrix.sum_axis(Axis(1));
+    let precursor_data = summed.slice(s![0, .., ..]);
+    let frag_info = &extracted.frag_info;
+    let (left, right) = (peak.left_idx, peak.right_idx);
+    // Each trace only on the columns of its own MS level (see `column_ms_levels`)
+    let ms2_columns = peak_columns(peak, &extracted.ms2_columns);
+    let ms1_columns = peak_columns(peak, &extracted.ms1_columns);
+
+    let fragment_rows: Vec<usize> = (0..frag_info.shape()[1])
+        .filter(|&row| frag_info[[0, row, 2]] == VARIANT_ORIGINAL && frag_info[[0, row, 0]] > 0.0)
+        .collect();
+    let traces: Vec<Vec<f32>> = fragment_rows
+        .iter()
+        .map(|&row| ms2_columns.iter().map(|&k| precursor_data[[row, k]]).collect())
+        .collect();
+
+    // 1. Cross-correlation coelution and shape over all fragment pairs
+    let standardized: Vec<Vec<f32>> = traces.iter().filter_map(|t| standardize(t)).collect();
+    let mut lags = Vec::new();
+    let mut shapes = Vec::new();
+    for i in 0..standardized.len() {
+        for j in (i + 1)..standardized.len() {
+            let (lag, value) = best_xcorr(&standardized[i], &standardized[j]);
+            lags.push(lag.unsigned_abs() as f32);
+            shapes.push(value);
+        }
+    }
+    let (xcorr_coelution, xcorr_shape) = if lags.is_empty() {
+        (0.0, 0.0)
+    } else {
+        let n = lags.len() as f32;
+        let mean_lag = lags.iter().sum::<f32>() / n;
+        let sd_lag = (lags.iter().map(|l| (l - mean_lag).powi(2)).sum::<f32>() / n).sqrt();
+        (mean_lag + sd_lag, shapes.iter().sum::<f32>() / n)
+    };
+
+    // 2. Library similarity of the observed fragment areas
+    let areas: Vec<f32> = traces.iter().map(|t| t.iter().sum()).collect();
+    let library: Vec<f32> = fragment_rows.iter().map(|&row| frag_info[[0, row, 1]].max(0.0)).collect();
+    let sqrt_areas: Vec<f32> = areas.iter().map(|a| a.sqrt()).collect();
+    let sqrt_library: Vec<f32> = library.iter().map(|l| l.sqrt()).collect();
+    let library_dotprod = cosine(&sqrt_areas, &sqrt_library);
+    let spectral_angle = 1.0 - 2.0 * cosine(&areas, &library).acos() / std::f32::consts::PI;
+
+    // 3. MS1 monoisotope vs summed fragments
+    let ms1_trace: Vec<f32> = match monoisotope_row(frag_info, precursor_mz) {
+        Some(row) => ms1_columns.iter().map(|&k| precursor_data[[row, k]]).collect(),
+        None => vec![0.0; ms1_columns.len()],
+    };
+    let mut ms2_trace = vec![0.0f32; ms2_columns.len()];
+    for trace in &traces {
+        for (total, &v) in ms2_trace.iter_mut().zip(trace) {
+            *total += v;
+        }
+    }
+    // MS1 and MS2 frames alternate on the frame axis: compare at the MS1 RTs
+    let ms2_rts: Vec<f32> = ms2_columns.iter().map(|&k| extracted.all_rt[k]).collect();
+    let ms2_at_ms1: Vec<f32> = ms1_columns
+        .iter()
+        .map(|&k| interpolate(&ms2_rts, &ms2_trace, extracted.all_rt[k], ResampleKernel::Linear, 0.0))
+        .collect();
+    let ms1_ms2_correlation = pearson(&ms1_trace, &ms2_at_ms1);
+
+    // 4. Mass error and ion mobility from the raw peaks inside the boundaries
+    let (mut weight, mut ppm, mut im) = (0.0f32, 0.0f32, 0.0f32);
+    for &row in &fragment_rows {
+        let mz = frag_info[[0, row, 0]];
+        let tolerance = mz_tolerance(mz, extraction.ms2_tolerance, &extraction.mz_unit);
+        if let Some((p, m, w)) = matched_peak_stats(&extracted.ms2_peaks, mz, tolerance, peak.left_rt, peak.right_rt) {
+            weight += w;
+            ppm += w * p;
+            im += w * m;
+        }
+    }
+    let (mass_error_ppm, im_delta) = if weight > 0.0 {
+        (ppm / weight, if expected_im > 0.0 { im / weight - expected_im } else { 0.0 })
+    } else {
+        (0.0, 0.0)
+    };
+
+    let ms1_tolerance = mz_tolerance(precursor_mz, extraction.ms1_tolerance, &extraction.mz_unit);
+    let ms1_mass_error_ppm = matched_peak_stats(&extracted.ms1_peaks, precursor_mz, ms1_tolerance, peak.left_rt, peak.right_rt)
+        .map(|(p, _, _)| p)
+        .unwrap_or(0.0);
+
+    // 5. Signal to noise: apex of the summed trace over the mean outside the peak
+    let full_trace: Vec<f32> = (0..precursor_data.shape()[1])
+        .map(|k| fragment_rows.iter().map(|&row| precursor_data[[row, k]]).sum())
+        .collect();
+    let outside: Vec<f32> = full_trace
+        .iter()
+        .enumerate()
+        .filter(|&(k, _)| (k < left || k > right) && extracted.ms2_columns[k])
+        .map(|(_, &v)| v)
+        .collect();
+    let noise = if outside.is_empty() { 1.0 } else { (outside.iter().sum::<f32>() / outside.len() as f32).max(1.0) };
+    let log_sn = (full_trace[peak.apex_idx].max(1.0) / noise).ln();
+
+    let n_fragments = fragment_rows.iter().filter(|&&row| precursor_data[[row, peak.apex_idx]] > 0.0).count() as f32;
+
+    PeakGroupScores {
+        xcorr_coelution,
+        xcorr_shape,
+        library_dotprod,
+        spectral_angle,
+        ms1_ms2_correlation,
+        mass_error_ppm,
+        ms1_mass_error_ppm,
+        im_delta,
+        log_sn,
+        n_fragments,
+        delta_rt: peak.apex_rt - expected_rt,
+        fwhm: peak.fwhm,
+    }
+}
+
+/// One row per scored peak group: identifiers, decoy label and every subscore.
+pub fn write_feature_table(results: &[PeakGroupResult], run: &str, path: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "run\tprecursor_id\tdecoy\tcandidate\tapex_rt\t{}", SCORE_NAMES.join("\t"))?;
+    for result in results {
+        let (Some(peak), Some(scores)) = (&result.peak, &result.scores) else { continue };
+        let candidate = result.candidate_rank.map(|r| r.to_string()).unwrap_or_default();
+        let values: Vec<String> = scores.values().iter().map(|v| format!("{:.5}", v)).collect();
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{:.4}\t{}",
+            run, result.precursor_id, result.decoy as u8, candidate, peak.apex_rt, values.join("\t")
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use ndarray::Array4;
+    use crate::utils::MS1_TYPE_MARKER;
+
+    const SHAPE: [f32; 7] = [0.0, 1.0, 3.0, 6.0, 3.0, 1.0, 0.0];
+    const RTS: [f32; 7] = [10.0, 10.1, 10.2, 10.3, 10.4, 10.5, 10.6];
+
+    fn peaks(rows: &[(f32, f32, f32, u32)]) -> TimsTOFData {
+        let mut data = TimsTOFData::new();
+        for &(mz, rt, im, intensity) in rows {
+            data.mz_values.push(mz);
+            data.rt_values_min.push(rt);
+            data.mobility_values.push(im);
+            data.intensity_values.push(intensity);
+            data.frame_indices.push(0);
+            data.scan_indices.push(0);
+        }
+        data
+    }
+
+    // Six MS1 isotopes of a precursor at m/z 500 (heaviest first, the
+    // monoisotope in row 5 follows SHAPE, the others do not) and three
+    // fragments with library intensities 1, 1, 4 observed at 4 : 1 : 1.
+    fn extraction(shift_last: bool) -> ExtractedPrecursor {
+        let frag_info = Array3::from_shape_fn((1, 9, 4), |(_, row, c)| match (row, c) {
+            (0..=5, 0) => 500.0 + 0.5 * (5 - row) as f32,
+            (0..=5, 2) => MS1_TYPE_MARKER,
+            (_, 0) => 300.0 + 100.0 * row as f32,
+            (_, 1) => if row == 8 { 4.0 } else { 1.0 },
+            (_, 2) => VARIANT_ORIGINAL,
+            _ => 2.0,
+        });
+        let rsm = Array4::from_shape_fn((1, 1, 9, 7), |(_, _, row, k)| match row {
+            5 => SHAPE[k],
+            0..=4 => [3.0, 3.0, 0.0, 0.0, 0.0, 3.0, 3.0][k],
+            6 => 4.0 * SHAPE[k],
+            8 if shift_last => SHAPE[k.saturating_sub(1)],
+            _ => SHAPE[k],
+        });
+        let mut extracted = ExtractedPrecursor::synthetic(rsm, frag_info, RTS.to_vec());
+        extracted.ms1_peaks = Arc::new(peaks(&[(500.005, 10.3, 1.0, 100), (499.995, 10.3, 1.2, 300), (500.0, 11.0, 1.0, 1000)]));
+        extracted.ms2_peaks = Arc::new(peaks(&[(900.009, 10.3, 1.1, 50)]));
+        extracted
+    }
+
+    fn peak() -> ChromPeak {
+        ChromPeak { apex_idx: 3, left_idx: 1, right_idx: 5, apex_rt: 10.3, left_rt: 10.1, right_rt: 10.5, fwhm: 0.2, apex_intensity: 36.0 }
+    }
+
+    fn extraction_config() -> ExtractionConfig {
+        ExtractionConfig { mz_unit: "Da".to_string(), ms1_tolerance: 0.1, ms2_tolerance: 0.1, ..ExtractionConfig::default() }
+    }
+
+    #[test]
+    fn monoisotope_is_the_ms1_row_closest_to_the_precursor() {
+        let mut frag_info = extraction(false).frag_info;
+        assert_eq!(monoisotope_row(&frag_info, 500.0), Some(5));
+        frag_info[[0, 5, 0]] = 0.0;
+        assert_eq!(monoisotope_row(&frag_info, 500.0), Some(4));
+        assert_eq!(monoisotope_row(&Array3::zeros((1, 9, 4)), 500.0), None);
+    }
+
+    #[test]
+    fn matched_peaks_are_intensity_weighted_inside_the_window() {
+        let data = peaks(&[(500.005, 10.3, 1.0, 100), (499.995, 10.3, 1.2, 300), (500.0, 11.0, 1.0, 1000), (500.5, 10.3, 1.0, 1000)]);
+        let (ppm, im, weight) = matched_peak_stats(&data, 500.0, 0.1, 10.1, 10.5).unwrap();
+        assert!((ppm + 5.0).abs() < 0.1, "{}", ppm);
+        assert!((im - 1.15).abs() < 1e-5);
+        assert_eq!(weight, 400.0);
+        assert!(matched_peak_stats(&data, 700.0, 0.1, 10.1, 10.5).is_none());
+    }
+
+    #[test]
+    fn coeluting_fragments_score_their_library_similarity() {
+        let scores = score_peak_group(&extraction(false), &peak(), 500.0, 10.25, 1.0, &extraction_config());
+
+        assert_eq!(scores.xcorr_coelution, 0.0);
+        assert!((scores.xcorr_shape - 1.0).abs() < 1e-5);
+        // sqrt areas (2, 1, 1) against sqrt library (1, 1, 2)
+        assert!((scores.library_dotprod - 5.0 / 6.0).abs() < 1e-5, "{}", scores.library_dotprod);
+        assert!(scores.spectral_angle > 0.0 && scores.spectral_angle < scores.library_dotprod);
+        // Only the monoisotope row follows the fragments
+        assert!((scores.ms1_ms2_correlation - 1.0).abs() < 1e-5);
+        assert!((scores.ms1_mass_error_ppm + 5.0).abs() < 0.1);
+        assert!((scores.mass_error_ppm - 10.0).abs() < 0.1, "{}", scores.mass_error_ppm);
+        assert!((scores.im_delta - 0.1).abs() < 1e-5);
+        assert_eq!(scores.n_fragments, 3.0);
+        assert!((scores.log_sn - 36.0f32.ln()).abs() < 1e-5);
+        assert!((scores.delta_rt - 0.05).abs() < 1e-5);
+    }
+
+    #[test]
+    fn a_shifted_fragment_raises_the_coelution_score() {
+        let scores = score_peak_group(&extraction(true), &peak(), 500.0, 10.25, 0.0, &extraction_config());
+        // Best lags (0, 1, 1) over the three pairs: mean + sd = 2/3 + 0.471
+        assert!((scores.xcorr_coelution - 1.1381).abs() < 1e-3, "{}", scores.xcorr_coelution);
+        assert!(scores.xcorr_shape < 1.0);
+        assert_eq!(scores.im_delta, 0.0);
+    }
+}
@@ -0,0 +1,239 @@
+// File: src/selection.rs
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+
+use crate::config::SelectionConfig;
+use crate::utils::{LibraryRecord, SeededRng};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelectionStrategy {
+    /// Every precursor of the report.
+    All,
+    /// The first `max_precursors` in report order (original behaviour).
+    First,
+    /// Seeded uniform sample of `max_precursors`.
+    Random,
+    /// Seeded sample spread proportionally over RT / m/z / charge strata.
+    Stratified,
+    /// The `max_precursors` with the highest report quantity.
+    TopQuantity,
+    /// The precursors listed in `id_list_file`.
+    IdList,
+}
+
+impl SelectionStrategy {
+    pub fn from_name(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name {
+            "all" => Ok(SelectionStrategy::All),
+            "first" => Ok(SelectionStrategy::First),
+            "random" => Ok(SelectionStrategy::Random),
+            "stratified" => Ok(SelectionStrategy::Stratified),
+            "top_quantity" => Ok(SelectionStrategy::TopQuantity),
+            "id_list" => Ok(SelectionStrategy::IdList),
+            _ => Err(format!(
+                "Invalid selection strategy: {}. Use all, first, random, stratified, top_quantity or id_list.",
+                name
+            )
+            .into()),
+        }
+    }
+}
+
+/// Selected precursor IDs (in report order, or file order for `id_list`)
+/// and a one-line description for the run summary.
+pub struct PrecursorSelection {
+    pub ids: Vec<String>,
+    pub summary: String,
+}
+
+/// Precursor m/z and charge per ID, from the first library record of each precursor.
+pub fn precursor_mz_charge(library_records: &[LibraryRecord]) -> HashMap<&str, (f32, u8)> {
+    let mut mz_charge = HashMap::new();
+    for record in library_records {
+        mz_charge.entry(record.transition_group_id.as_str()).or_insert_with(|| {
+            (
+                record.precursor_mz.parse::<f32>().unwrap_or(0.0),
+                record.precursor_charge.parse::<u8>().unwrap_or(0),
+            )
+        });
+    }
+    mz_charge
+}
+
+/// IDs of an ID list file: first tab/comma separated field of each
+/// non-empty line; `#` comments and a `transition_group_id` / `Precursor.Id`
+/// header are skipped.
+fn read_id_list(path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Failed to read id_list_file {}: {}", path, e))?;
+    Ok(content
+        .lines()
+        .filter_map(|line| line.split(['\t', ',']).next())
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && !id.starts_with('#') && *id != "transition_group_id" && *id != "Precursor.Id")
+        .map(str::to_string)
+        .collect())
+}
+
+/// Quantile bin (0..n_bins) of every value, ties kept in the same bin.
+fn quantile_bins(values: &[f32], n_bins: usize) -> Vec<usize> {
+    let mut sorted: Vec<f32> = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let n = sorted.len().max(1);
+    values
+        .iter()
+        .map(|v| (sorted.partition_point(|x| x < v) * n_bins / n).min(n_bins.saturating_sub(1)))
+        .collect()
+}
+
+/// Proportional allocation of `n_select` over strata (largest remainder),
+/// each stratum sampled after a seeded shuffle.
+fn stratified_sample(strata: &[Vec<usize>], n_total: usize, n_select: usize, rng: &mut SeededRng) -> Vec<usize> {
+    let exact: Vec<f64> = strata.iter().map(|s| s.len() as f64 * n_select as f64 / n_total as f64).collect();
+    let mut quotas: Vec<usize> = exact.iter().map(|q| q.floor() as usize).collect();
+    let mut order: Vec<usize> = (0..strata.len()).collect();
+    order.sort_by(|&a, &b| (exact[b] - exact[b].floor()).total_cmp(&(exact[a] - exact[a].floor())).then(a.cmp(&b)));
+    let missing = n_select.saturating_sub(quotas.iter().sum());
+    for &stratum in order.iter().take(missing) {
+        quotas[stratum] += 1;
+    }
+
+    let mut selected = Vec::with_capacity(n_select);
+    for (members, quota) in strata.iter().zip(quotas) {
+        let mut members = members.clone();
+        rng.shuffle(&mut members);
+        selected.extend(members.into_iter().take(quota));
+    }
+    selected
+}
+
+/// Apply the configured strategy to the report precursors. `max_precursors`
+/// bounds every strategy except `all` and `id_list`.
+pub fn select_precursors(
+    precursor_ids: &[String],
+    rt_dict: &HashMap<String, f32>,
+    mz_charge: &HashMap<&str, (f32, u8)>,
+    quantities: &HashMap<String, f32>,
+    config: &SelectionConfig,
+    max_precursors: usize,
+) -> Result<PrecursorSelection, Box<dyn Error>> {
+    let strategy = SelectionStrategy::from_name(&config.strategy)?;
+    let n_total = precursor_ids.len();
+    let n_select = max_precursors.min(n_total);
+    let mut rng = SeededRng::new(config.seed);
+
+    let (mut indices, detail): (Vec<usize>, String) = match strategy {
+        SelectionStrategy::All => ((0..n_total).collect(), String::new()),
+        SelectionStrategy::First => ((0..n_select).collect(), String::new()),
+        SelectionStrategy::Random => {
+            let mut indices: Vec<usize> = (0..n_total).collect();
+            rng.shuffle(&mut indices);
+            indices.truncate(n_select);
+            (indices, format!(", seed {}", config.seed))
+        }
+        SelectionStrategy::Stratified => {
+            let mut keys: Vec<Vec<usize>> = vec![Vec::new(); n_total];
+            for dimension in &config.stratify_by {
+                let column: Vec<usize> = match dimension.as_str() {
+                    "rt" => {
+                        let rts: Vec<f32> = precursor_ids.iter().map(|id| rt_dict.get(id).copied().unwrap_or(0.0)).collect();
+                        quantile_bins(&rts, config.n_bins)
+                    }
+                    "mz" => {
+                        let mzs: Vec<f32> = precursor_ids.iter().map(|id| mz_charge.get(id.as_str()).map_or(0.0, |mc| mc.0)).collect();
+                        quantile_bins(&mzs, config.n_bins)
+                    }
+                    "charge" => precursor_ids.iter().map(|id| mz_charge.get(id.as_str()).map_or(0, |mc| mc.1 as usize)).collect(),
+                    other => return Err(format!("Invalid stratify_by dimension: {}. Use rt, mz or charge.", other).into()),
+                };
+                for (key, bin) in keys.iter_mut().zip(column) {
+                    key.push(bin);
+                }
+            }
+            // Strata in first-seen order keep the sample independent of hashing
+            let mut stratum_of: HashMap<&[usize], usize> = HashMap::new();
+            let mut strata: Vec<Vec<usize>> = Vec::new();
+            for (index, key) in keys.iter().enumerate() {
+                let stratum = *stratum_of.entry(key.as_slice()).or_insert_with(|| {
+                    strata.push(Vec::new());
+                    strata.len() - 1
+                });
+                strata[stratum].push(index);
+            }
+            let indices = stratified_sample(&strata, n_total, n_select, &mut rng);
+            (indices, format!(", {} strata by {}, seed {}", strata.len(), config.stratify_by.join("/"), config.seed))
+        }
+        SelectionStrategy::TopQuantity => {
+            let mut indices: Vec<usize> = (0..n_total).filter(|&i| quantities.contains_key(&precursor_ids[i])).collect();
+            indices.sort_by(|&a, &b| quantities[&precursor_ids[b]].total_cmp(&quantities[&precursor_ids[a]]));
+            indices.truncate(n_select);
+            (indices, format!(", by {}", config.quantity_column))
+        }
+        SelectionStrategy::IdList => {
+            let path = config.id_list_file.as_deref().ok_or("selection strategy id_list needs id_list_file")?;
+            let position: HashMap<&str, usize> = precursor_ids.iter().enumerate().map(|(i, id)| (id.as_str(), i)).collect();
+            let listed = read_id_list(path)?;
+            let mut seen = HashSet::new();
+            let indices: Vec<usize> = listed
+                .iter()
+                .filter_map(|id| position.get(id.as_str()).copied())
+                .filter(|&i| seen.insert(i))
+                .collect();
+            let n_missing = listed.iter().filter(|id| !position.contains_key(id.as_str())).count();
+            let ids: Vec<String> = indices.iter().map(|&i| precursor_ids[i].clone()).collect();
+            let summary = format!("id_list ({}): {} of {} precursors, {} listed IDs not in the report", path, ids.len(), n_total, n_missing);
+            return Ok(PrecursorSelection { ids, summary });
+        }
+    };
+
+    // Report order regardless of how the sample was drawn
+    indices.sort_unstable();
+    let ids: Vec<String> = indices.iter().map(|&i| precursor_ids[i].clone()).collect();
+    let summary = format!("{}{}: {} of {} precursors", config.strategy, detail, ids.len(), n_total);
+    Ok(PrecursorSelection { ids, summary })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantile_bins_keep_ties_together() {
+        assert_eq!(quantile_bins(&[4.0, 1.0, 3.0, 2.0], 2), vec![1, 0, 1, 0]);
+        assert_eq!(quantile_bins(&[1.0, 1.0, 1.0, 2.0], 2), vec![0, 0, 0, 1]);
+        assert_eq!(quantile_bins(&[5.0], 4), vec![0]);
+    }
+
+    #[test]
+    fn stratified_sample_allocates_by_largest_remainder() {
+        let strata = vec![(0..6).collect(), (6..9).collect(), vec![9]];
+        let selected = stratified_sample(&strata, 10, 5, &mut SeededRng::new(7));
+
+        // Exact quotas 3, 1.5, 0.5: the tied remainder goes to the lower stratum index
+        let per_stratum: Vec<usize> = strata.iter().map(|s| selected.iter().filter(|i| s.contains(i)).count()).collect();
+        assert_eq!(per_stratum, vec![3, 2, 0]);
+        assert_eq!(selected, stratified_sample(&strata, 10, 5, &mut SeededRng::new(7)));
+    }
+
+    #[test]
+    fn stratified_selection_keeps_charge_proportions_in_report_order() {
+        let ids: Vec<String> = (0..8).map(|i| format!("p{}", i)).collect();
+        let mz_charge: HashMap<&str, (f32, u8)> =
+            ids.iter().enumerate().map(|(i, id)| (id.as_str(), (400.0 + i as f32, if i < 6 { 2 } else { 3 }))).collect();
+        let config = SelectionConfig {
+            strategy: "stratified".to_string(),
+            stratify_by: vec!["charge".to_string()],
+            ..SelectionConfig::default()
+        };
+
+        let selection = select_precursors(&ids, &HashMap::new(), &mz_charge, &HashMap::new(), &config, 4).unwrap();
+        assert_eq!(selection.ids.len(), 4);
+        assert_eq!(selection.ids.iter().filter(|id| mz_charge[id.as_str()].1 == 3).count(), 1);
+        let mut sorted = selection.ids.clone();
+        sorted.sort_by_key(|id| id[1..].parse::<usize>().unwrap());
+        assert_eq!(selection.ids, sorted);
+
+        let config = SelectionConfig { stratify_by: vec!["im".to_string()], ..config };
+        assert!(select_precursors(&ids, &HashMap::new(), &mz_charge, &HashMap::new(), &config, 4).is_err());
+    }
+}
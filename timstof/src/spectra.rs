@@ -0,0 +1,220 @@
+// File: src/spectra.rs
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::chemistry::fragment_series_name;
+use crate::config::{ExtractionConfig, SpectrumConfig};
+use crate::peaks::PeakGroupResult;
+use crate::processing::{ExtractedPrecursor, FastChunkFinder, precursor_im_range, precursor_im_tolerance};
+use crate::scores::mz_tolerance;
+use crate::utils::{LibraryRecord, PrecursorLibData};
+
+/// Centroided peak of an apex spectrum; `annotation` names the library
+/// fragment matched to it (e.g. `y7^1`, `b4^1-H2O`).
+#[derive(Debug, Clone)]
+pub struct SpectrumPeak {
+    pub mz: f32,
+    pub intensity: f32,
+    pub annotation: Option<String>,
+}
+
+/// Full MS2 spectrum of the precursor's isolation window around the apex.
+#[derive(Debug, Clone)]
+pub struct ApexSpectrum {
+    pub precursor_mz: f32,
+    pub charge: u8,
+    pub apex_rt: f32,
+    pub im: f32,
+    pub n_frames: usize,
+    pub peaks: Vec<SpectrumPeak>,
+}
+
+/// `y7^1`-style label of a library fragment.
+fn fragment_label(record: &LibraryRecord) -> String {
+    let mut label = format!(
+        "{}{}^{}",
+        fragment_series_name(&record.fragment_type),
+        record.fragment_number,
+        record.fragment_charge
+    );
+    let loss = record.fragment_loss_type.as_str();
+    if !loss.is_empty() && !loss.eq_ignore_ascii_case("noloss") {
+        label.push('-');
+        label.push_str(loss);
+    }
+    label
+}
+
+/// Merge raw peaks (sorted by m/z) within `tolerance_ppm` of the first
+/// m/z of their cluster into intensity-weighted centroids. Anchoring on the
+/// first m/z keeps the running centroid from dragging a cluster wider.
+fn centroid(mut raw: Vec<(f32, f32)>, tolerance_ppm: f32) -> Vec<SpectrumPeak> {
+    raw.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut peaks: Vec<SpectrumPeak> = Vec::new();
+    let (mut cluster_mz, mut weighted_mz) = (0.0f32, 0.0f64);
+    for (mz, intensity) in raw {
+        if let Some(last) = peaks.last_mut() {
+            if (mz - cluster_mz) / cluster_mz * 1e6 <= tolerance_ppm {
+                weighted_mz += mz as f64 * intensity as f64;
+                last.intensity += intensity;
+                last.mz = (weighted_mz / last.intensity as f64) as f32;
+                continue;
+            }
+        }
+        cluster_mz = mz;
+        weighted_mz = mz as f64 * intensity as f64;
+        peaks.push(SpectrumPeak { mz, intensity, annotation: None });
+    }
+    peaks
+}
+
+/// Sum all peaks of the precursor's MS2 window over `frames_each_side`
+/// frames around `apex_rt` and over its IM range, then annotate the
+/// library fragments. `None` when the window has no peaks.
+pub fn apex_spectrum(
+    precursor_data: &PrecursorLibData,
+    view: &ExtractedPrecursor,
+    finder: &FastChunkFinder,
+    apex_rt: f32,
+    extraction: &ExtractionConfig,
+    config: &SpectrumConfig,
+) -> Result<Option<ApexSpectrum>, Box<dyn Error>> {
+    let precursor_mz = precursor_data.precursor_info[1];
+    let Some(window) = finder.find(precursor_mz) else { return Ok(None) };
+
+    // MS2 frame RTs of this window seen in the extraction, nearest to the apex +- frames_each_side
+    let mut frame_rts: Vec<f32> = view.ms2_peaks.rt_values_min.clone();
+    frame_rts.sort_by(|a, b| a.total_cmp(b));
+    frame_rts.dedup();
+    let Some(apex_pos) = (0..frame_rts.len()).min_by(|&a, &b| (frame_rts[a] - apex_rt).abs().total_cmp(&(frame_rts[b] - apex_rt).abs())) else {
+        return Ok(None);
+    };
+    let first = apex_pos.saturating_sub(config.frames_each_side);
+    let last = (apex_pos + config.frames_each_side).min(frame_rts.len() - 1);
+
+    let (im_min, im_max) = precursor_im_range(precursor_data.im, precursor_im_tolerance(precursor_data, extraction)?);
+    let raw = window.slice_by_rt_im_range(frame_rts[first], frame_rts[last], im_min, im_max);
+    let raw_peaks: Vec<(f32, f32)> = raw
+        .mz_values
+        .iter()
+        .zip(&raw.intensity_values)
+        .map(|(&mz, &intensity)| (mz, intensity as f32))
+        .collect();
+    let mut peaks = centroid(raw_peaks, config.merge_tolerance_ppm);
+    peaks.retain(|p| p.intensity >= config.min_intensity);
+    if peaks.is_empty() {
+        return Ok(None);
+    }
+
+    // Annotate each library fragment on the most intense peak within the MS2 tolerance
+    for record in &precursor_data.lib_records {
+        let Ok(product_mz) = record.product_mz.parse::<f32>() else { continue };
+        let tolerance = mz_tolerance(product_mz, extraction.ms2_tolerance, &extraction.mz_unit);
+        let start = peaks.partition_point(|p| p.mz < product_mz - tolerance);
+        let end = peaks.partition_point(|p| p.mz <= product_mz + tolerance);
+        let Some(best) = (start..end).max_by(|&a, &b| peaks[a].intensity.total_cmp(&peaks[b].intensity)) else { continue };
+        let label = fragment_label(record);
+        match &mut peaks[best].annotation {
+            Some(existing) => {
+                existing.push('/');
+                existing.push_str(&label);
+            }
+            none => *none = Some(label),
+        }
+    }
+
+    Ok(Some(ApexSpectrum {
+        precursor_mz,
+        charge: precursor_data.precursor_info.get(2).copied().unwrap_or(0.0) as u8,
+        apex_rt: frame_rts[apex_pos],
+        im: precursor_data.im,
+        n_frames: last - first + 1,
+        peaks,
+    }))
+}
+
+/// MGF-like export: one BEGIN/END IONS block per spectrum, peak lines carry
+/// the library fragment annotation as an optional third column.
+pub fn write_spectrum_mgf(results: &[PeakGroupResult], run: &str, path: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for result in results {
+        let Some(spectrum) = &result.spectrum else { continue };
+        let candidate = result.candidate_rank.map(|r| format!(".cand{}", r)).unwrap_or_default();
+        writeln!(writer, "BEGIN IONS")?;
+        writeln!(writer, "TITLE={}.{}{}", run, result.precursor_id, candidate)?;
+        writeln!(writer, "PEPMASS={:.5}", spectrum.precursor_mz)?;
+        if spectrum.charge > 0 {
+            writeln!(writer, "CHARGE={}+", spectrum.charge)?;
+        }
+        writeln!(writer, "RTINSECONDS={:.2}", spectrum.apex_rt * 60.0)?;
+        writeln!(writer, "ION_MOBILITY={:.4}", spectrum.im)?;
+        writeln!(writer, "DECOY={}", result.decoy as u8)?;
+        writeln!(writer, "NUM_FRAMES={}", spectrum.n_frames)?;
+        for peak in &spectrum.peaks {
+            match &peak.annotation {
+                Some(label) => writeln!(writer, "{:.5} {:.1} {}", peak.mz, peak.intensity, label)?,
+                None => writeln!(writer, "{:.5} {:.1}", peak.mz, peak.intensity)?,
+            }
+        }
+        writeln!(writer, "END IONS\n")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use ndarray::{Array3, Array4};
+    use crate::utils::{IndexedTimsTOFData, TimsTOFData};
+
+    #[test]
+    fn centroids_merge_within_the_tolerance_of_the_first_mz() {
+        // 9.9 ppm merges, 10.1 ppm splits
+        let peaks = centroid(vec![(500.00505, 1.0), (500.0, 1.0), (500.00495, 2.0)], 10.0);
+        assert_eq!(peaks.len(), 2);
+        assert_eq!((peaks[0].intensity, peaks[1].intensity), (3.0, 1.0));
+        assert!((peaks[0].mz - 500.0033).abs() < 1e-3, "{}", peaks[0].mz);
+
+        // The weighted centroid (500.00396) would reach 500.008, the first m/z does not
+        let peaks = centroid(vec![(500.0, 1.0), (500.004, 100.0), (500.008, 1.0)], 10.0);
+        assert_eq!(peaks.iter().map(|p| p.intensity).collect::<Vec<_>>(), [101.0, 1.0]);
+    }
+
+    #[test]
+    fn apex_spectrum_sums_frames_around_the_apex_and_annotates() {
+        let mut data = TimsTOFData::new();
+        for frame in 0..5u32 {
+            let rt = 10.0 + 0.1 * frame as f32;
+            // (mz, im, intensity): a 6.7 ppm pair, a 15 ppm pair and a peak outside the IM window
+            for (mz, im, intensity) in [(300.0, 1.0, 100), (300.002, 1.0, 100), (400.0, 1.0, 100), (400.006, 1.0, 50), (300.0, 2.0, 1000)] {
+                data.mz_values.push(mz);
+                data.rt_values_min.push(rt);
+                data.mobility_values.push(im);
+                data.intensity_values.push(intensity);
+                data.frame_indices.push(frame);
+                data.scan_indices.push(0);
+            }
+        }
+        let finder = FastChunkFinder::new(vec![((490.0, 510.0), IndexedTimsTOFData::from_timstof_data(data.clone()))]).unwrap();
+        let mut view = ExtractedPrecursor::synthetic(Array4::zeros((1, 1, 1, 5)), Array3::zeros((1, 1, 4)), vec![10.0, 10.1, 10.2, 10.3, 10.4]);
+        view.ms2_peaks = Arc::new(data);
+
+        let mut precursor = PrecursorLibData::test_precursor("P", 500.0, 10.2, 1.0, &[300.001, 400.0]);
+        precursor.lib_records[1].fragment_type = "1".to_string();
+        precursor.lib_records[1].fragment_loss_type = "H2O".to_string();
+        let config = SpectrumConfig { frames_each_side: 1, min_intensity: 200.0, ..SpectrumConfig::default() };
+        let spectrum = apex_spectrum(&precursor, &view, &finder, 10.21, &ExtractionConfig::default(), &config).unwrap().unwrap();
+
+        assert_eq!(spectrum.n_frames, 3);
+        assert!((spectrum.apex_rt - 10.2).abs() < 1e-6);
+        let peaks: Vec<(f32, Option<&str>)> = spectrum.peaks.iter().map(|p| (p.intensity, p.annotation.as_deref())).collect();
+        assert_eq!(peaks, [(600.0, Some("y3^1")), (300.0, Some("b3^1-H2O"))]);
+        assert!((spectrum.peaks[0].mz - 300.001).abs() < 1e-4);
+
+        assert!(apex_spectrum(&PrecursorLibData::test_precursor("Q", 700.0, 10.2, 1.0, &[300.0]), &view, &finder, 10.2, &ExtractionConfig::default(), &config)
+            .unwrap()
+            .is_none());
+    }
+}
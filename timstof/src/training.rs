@@ -0,0 +1,274 @@
+// File: src/training.rs
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use rayon::prelude::*;
+
+use crate::config::Config;
+use crate::interference::with_interference_mask;
+use crate::processing::{FastChunkFinder, RtSelection, extract_precursor, prepare_precursor_features};
+use crate::utils::{IndexedTimsTOFData, PrecursorLibData, SeededRng};
+
+pub const SPLITS: [&str; 3] = ["train", "validation", "test"];
+
+/// One labelled extraction planned from a precursor of the run.
+struct PlannedSample {
+    precursor: usize,
+    label: u8,
+    kind: &'static str, // `target`, `decoy` or `rt_shift`
+    rt_shift: f32,
+}
+
+/// Extracted tensors of one sample, flattened in row-major order.
+struct TrainingSample {
+    plan: PlannedSample,
+    rsm: Vec<f32>,
+    rsm_shape: Vec<usize>,
+    frag_info: Vec<f32>,
+    frag_info_shape: Vec<usize>,
+}
+
+#[derive(Debug, Default)]
+pub struct TrainingSummary {
+    pub n_positives: usize,
+    pub n_decoys: usize,
+    pub n_rt_shifted: usize,
+    pub n_per_split: [usize; 3],
+}
+
+fn is_decoy(precursor: &PrecursorLibData) -> bool {
+    precursor.lib_records.first().is_some_and(|r| r.decoy == "1")
+}
+
+/// RT offset of the k-th shifted negative (k from 1): +s, -s, +2s, -2s, ...
+fn rt_offset(k: usize, rt_shift: f32) -> f32 {
+    let step = k.div_ceil(2) as f32 * rt_shift;
+    if k % 2 == 1 { step } else { -step }
+}
+
+/// Split index of a peptide, stable for a given seed.
+fn split_of(sequence: &str, seed: u64, validation_fraction: f32, test_fraction: f32) -> usize {
+    // FNV-1a over the sequence, mixed with the seed
+    let hash = sequence.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| (h ^ b as u64).wrapping_mul(0x0100_0000_01b3));
+    let draw = (SeededRng::new(hash ^ seed).next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+    if draw < test_fraction as f64 {
+        2
+    } else if draw < (test_fraction + validation_fraction) as f64 {
+        1
+    } else {
+        0
+    }
+}
+
+/// Write a little-endian f32 array in NumPy `.npy` (v1.0) format.
+fn write_npy(path: &Path, shape: &[usize], data: &[f32]) -> Result<(), Box<dyn Error>> {
+    let dims: Vec<String> = shape.iter().map(|d| d.to_string()).collect();
+    let shape_str = if dims.len() == 1 { format!("({},)", dims[0]) } else { format!("({})", dims.join(", ")) };
+    let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': {}, }}", shape_str);
+    // magic (6) + version (2) + header length (2) + header, padded to 64 bytes with a trailing newline
+    let padding = 64 - (10 + header.len() + 1) % 64;
+    header.push_str(&" ".repeat(padding % 64));
+    header.push('\n');
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(b"\x93NUMPY\x01\x00")?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    for value in data {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Directory the training set is written to (`training.output_dir` under the run output).
+pub fn training_dir(config: &Config) -> PathBuf {
+    Path::new(&config.processing.output_dir).join(&config.training.output_dir)
+}
+
+/// Extract and write the labelled training set to `training_dir`: per split
+/// `<split>_rsm.npy` (n, repeats, fragments, rt), `<split>_frag_info.npy`,
+/// `<split>_precursor_feat.npy` (`prepare_precursor_features` rows) and
+/// `<split>_samples.tsv` with the labels. `decoy_pairs` (decoy ID -> target ID, see `pair_decoys_with_targets`) keeps
+/// every decoy in the split of its target's peptide.
+pub fn export_training_set(
+    precursors: &[PrecursorLibData],
+    q_values: &HashMap<String, f32>,
+    decoy_pairs: &HashMap<String, String>,
+    ms1_indexed: &IndexedTimsTOFData,
+    finder: &FastChunkFinder,
+    config: &Config,
+    device: &str,
+) -> Result<TrainingSummary, Box<dyn Error>> {
+    let cfg = &config.training;
+    let output_dir = training_dir(config);
+    if cfg.validation_fraction + cfg.test_fraction >= 1.0 {
+        return Err(format!(
+            "Invalid training splits: validation_fraction {} + test_fraction {} must be below 1.",
+            cfg.validation_fraction, cfg.test_fraction
+        )
+        .into());
+    }
+
+    // 1. Sample plan: positives (+ shifted windows) and decoys
+    let mut plan = Vec::new();
+    for (index, precursor) in precursors.iter().enumerate() {
+        if is_decoy(precursor) {
+            plan.push(PlannedSample { precursor: index, label: 0, kind: "decoy", rt_shift: 0.0 });
+            continue;
+        }
+        let Some(&q) = q_values.get(&precursor.precursor_id) else { continue };
+        if q >= cfg.q_value {
+            continue;
+        }
+        plan.push(PlannedSample { precursor: index, label: 1, kind: "target", rt_shift: 0.0 });
+        for k in 1..=cfg.rt_shift_negatives {
+            plan.push(PlannedSample { precursor: index, label: 0, kind: "rt_shift", rt_shift: rt_offset(k, cfg.rt_shift) });
+        }
+    }
+
+    // 2. Centred extraction at the (shifted) expected RT
+    let samples: Vec<TrainingSample> = plan
+        .into_par_iter()
+        .filter_map(|plan| {
+            let source = &precursors[plan.precursor];
+            let shifted;
+            let precursor = if plan.rt_shift != 0.0 {
+                shifted = PrecursorLibData { rt: source.rt + plan.rt_shift, ..source.clone() };
+                &shifted
+            } else {
+                source
+            };
+            let extracted = extract_precursor(precursor, ms1_indexed, finder, &config.extraction, RtSelection::Centered, device).ok()?;
+            let frag_info = if config.interference.enabled {
+                with_interference_mask(&extracted.frag_info, &precursor.interference)
+            } else {
+                extracted.frag_info
+            };
+            Some(TrainingSample {
+                rsm_shape: extracted.rsm_matrix.shape()[1..].to_vec(),
+                rsm: extracted.rsm_matrix.iter().copied().collect(),
+                frag_info_shape: frag_info.shape()[1..].to_vec(),
+                frag_info: frag_info.iter().copied().collect(),
+                plan,
+            })
+        })
+        .collect();
+
+    // 3. Peptide-level splits; decoys follow their paired target's peptide
+    let peptide_of: HashMap<&str, &str> = precursors
+        .iter()
+        .filter_map(|p| Some((p.precursor_id.as_str(), p.lib_records.first()?.peptide_sequence.as_str())))
+        .collect();
+    let split_peptide = |precursor: &PrecursorLibData| -> String {
+        let Some(record) = precursor.lib_records.first() else { return precursor.precursor_id.clone() };
+        let target_peptide = decoy_pairs
+            .get(&precursor.precursor_id)
+            .and_then(|target_id| peptide_of.get(target_id.as_str()));
+        match target_peptide {
+            Some(target_peptide) if is_decoy(precursor) => target_peptide.to_string(),
+            _ => record.peptide_sequence.clone(),
+        }
+    };
+
+    let mut summary = TrainingSummary::default();
+    let mut by_split: [Vec<&TrainingSample>; 3] = Default::default();
+    for sample in &samples {
+        let precursor = &precursors[sample.plan.precursor];
+        let split = split_of(&split_peptide(precursor), cfg.seed, cfg.validation_fraction, cfg.test_fraction);
+        by_split[split].push(sample);
+        match sample.plan.kind {
+            "target" => summary.n_positives += 1,
+            "decoy" => summary.n_decoys += 1,
+            _ => summary.n_rt_shifted += 1,
+        }
+    }
+
+    // 4. Stacked tensors and labels per split
+    std::fs::create_dir_all(&output_dir)?;
+    for (split, split_samples) in by_split.iter().enumerate() {
+        let name = SPLITS[split];
+        let Some(first) = split_samples.first() else { continue };
+        // Centred windows have a fixed length; drop anything that deviates
+        let kept: Vec<&TrainingSample> = split_samples
+            .iter()
+            .copied()
+            .filter(|s| s.rsm_shape == first.rsm_shape && s.frag_info_shape == first.frag_info_shape)
+            .collect();
+        summary.n_per_split[split] = kept.len();
+
+        let keys: Vec<Vec<String>> = kept.iter().enumerate().map(|(k, _)| vec![k.to_string()]).collect();
+        let infos: Vec<Vec<f32>> = kept.iter().map(|s| precursors[s.plan.precursor].precursor_info.clone()).collect();
+        let mut rt_dict = HashMap::new();
+        let mut im_dict = HashMap::new();
+        for (key, sample) in keys.iter().zip(&kept) {
+            let precursor = &precursors[sample.plan.precursor];
+            rt_dict.insert(key[0].clone(), precursor.rt + sample.plan.rt_shift);
+            im_dict.insert(key[0].clone(), precursor.im);
+        }
+        let precursor_feat = prepare_precursor_features(&keys, &infos, &rt_dict, &im_dict)?;
+
+        let n = kept.len();
+        let rsm: Vec<f32> = kept.iter().flat_map(|s| s.rsm.iter().copied()).collect();
+        let frag_info: Vec<f32> = kept.iter().flat_map(|s| s.frag_info.iter().copied()).collect();
+        write_npy(&output_dir.join(format!("{}_rsm.npy", name)), &[[n].as_slice(), &first.rsm_shape].concat(), &rsm)?;
+        write_npy(&output_dir.join(format!("{}_frag_info.npy", name)), &[[n].as_slice(), &first.frag_info_shape].concat(), &frag_info)?;
+        write_npy(
+            &output_dir.join(format!("{}_precursor_feat.npy", name)),
+            precursor_feat.shape(),
+            &precursor_feat.iter().copied().collect::<Vec<f32>>(),
+        )?;
+
+        let mut writer = BufWriter::new(File::create(output_dir.join(format!("{}_samples.tsv", name)))?);
+        writeln!(writer, "index\tprecursor_id\tsplit_peptide\tdecoy\tlabel\tkind\trt\trt_shift\tq_value")?;
+        for (k, sample) in kept.iter().enumerate() {
+            let precursor = &precursors[sample.plan.precursor];
+            let q_value = q_values.get(&precursor.precursor_id).map(|q| format!("{:.6}", q)).unwrap_or_else(|| "NA".to_string());
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{:.4}\t{:.4}\t{}",
+                k,
+                precursor.precursor_id,
+                split_peptide(precursor),
+                is_decoy(precursor) as u8,
+                sample.plan.label,
+                sample.plan.kind,
+                precursor.rt + sample.plan.rt_shift,
+                sample.plan.rt_shift,
+                q_value
+            )?;
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn npy_header_is_aligned_and_data_follows_it() {
+        let path = std::env::temp_dir().join(format!("dia_peak_test_{}.npy", std::process::id()));
+        write_npy(&path, &[2, 3], &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        let header = std::str::from_utf8(&bytes[10..10 + header_len]).unwrap();
+        assert!(header.contains("'shape': (2, 3)"), "{}", header);
+        assert!(header.ends_with('\n'));
+        let data: Vec<f32> = bytes[10 + header_len..].chunks(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+        assert_eq!(data, [1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn split_of_is_stable_per_seed() {
+        let splits: Vec<usize> = (0..200).map(|k| split_of(&format!("PEPTIDE{}K", k), 1, 0.1, 0.1)).collect();
+        assert_eq!(splits, (0..200).map(|k| split_of(&format!("PEPTIDE{}K", k), 1, 0.1, 0.1)).collect::<Vec<_>>());
+        assert!((0..3).all(|split| splits.contains(&split)));
+        assert!(splits.iter().filter(|&&s| s == 0).count() > 120);
+    }
+}
@@ -10,6 +10,10 @@ use std::path::Path;
 use std::sync::Arc;
 use timsrust::{converters::ConvertableDomain, readers::{FrameReader, MetadataReader}, MSLevel};
 use serde::{Serialize, Deserialize};
+use crate::chemistry::{Composition, INTERNAL_FRAGMENT_CODE, ModifiedPeptide, NEUTRON_SPACING, PROTON_MASS,
+    fragment_type_code, isotope_distribution, parse_fragment_annotation};
+use crate::config::LibraryConfig;
+use crate::interference::FragmentInterference;
 
 #[derive(Debug, Clone)]
 pub struct PrecursorLibData {
@@ -20,6 +24,8 @@ pub struct PrecursorLibData {
     pub ms1_data: MSDataArray,
     pub ms2_data: MSDataArray,
     pub precursor_info: Vec<f32>,
+    pub interference: Vec<FragmentInterference>, // filled by `annotate_interference`
+    pub peak_width: Option<f32>, // reported RT.Stop - RT.Start (minutes), set when `rt_window` is enabled
 }
 
 pub fn prepare_precursor_lib_data(
@@ -28,6 +34,7 @@ pub fn prepare_precursor_lib_data(
     assay_rt_dict: &HashMap<String, f32>,
     assay_im_dict: &HashMap<String, f32>,
     lib_cols: &LibCols,
+    library_cfg: &LibraryConfig,
     max_precursors: usize,
 ) -> Result<Vec<PrecursorLibData>, Box<dyn Error>> {
     // 获取前N个unique precursor IDs
@@ -39,15 +46,18 @@ pub fn prepare_precursor_lib_data(
     
     println!("Preparing library data for {} precursors...", unique_precursors.len());
     
+    // 一次性按precursor分组记录索引，避免每个precursor都扫描整个library
+    let record_index = group_library_records(library_records);
+    
     // 并行处理每个precursor
     let precursor_data_list: Vec<PrecursorLibData> = unique_precursors
         .par_iter()
         .filter_map(|precursor_id| {
             // 获取该precursor的所有library records
-            let each_lib_data: Vec<LibraryRecord> = library_records
+            let each_lib_data: Vec<LibraryRecord> = record_index
+                .get(precursor_id.as_str())?
                 .iter()
-                .filter(|record| &record.transition_group_id == precursor_id)
-                .cloned()
+                .map(|&idx| library_records[idx].clone())
                 .collect();
             
             if each_lib_data.is_empty() {
@@ -59,7 +69,7 @@ pub fn prepare_precursor_lib_data(
             let im = assay_im_dict.get(precursor_id).copied().unwrap_or(0.0);
             
             // 构建library matrices
-            match build_lib_matrix(&each_lib_data, lib_cols, 5.0, 1801.0, 20) {
+            match build_lib_matrix(&each_lib_data, lib_cols, library_cfg, 5.0, 1801.0, 20) {
                 Ok((precursors_list, ms1_data_list, ms2_data_list, precursor_info_list)) => {
                     if !precursors_list.is_empty() {
                         Some(PrecursorLibData {
@@ -70,6 +80,8 @@ pub fn prepare_precursor_lib_data(
                             ms1_data: ms1_data_list[0].clone(),
                             ms2_data: ms2_data_list[0].clone(),
                             precursor_info: precursor_info_list[0].clone(),
+                            interference: Vec::new(),
+                            peak_width: None,
                         })
                     } else {
                         None
@@ -83,6 +95,48 @@ pub fn prepare_precursor_lib_data(
     Ok(precursor_data_list)
 }
 
+/// Map each transition_group_id to the indices of its library records.
+pub fn group_library_records(library_records: &[LibraryRecord]) -> HashMap<&str, Vec<usize>> {
+    let mut record_index: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (idx, record) in library_records.iter().enumerate() {
+        record_index.entry(record.transition_group_id.as_str()).or_default().push(idx);
+    }
+    record_index
+}
+
+/// Unique precursor IDs in library order (used when no report is available).
+pub fn get_library_precursor_ids(library_records: &[LibraryRecord]) -> Vec<String> {
+    let mut seen = std::collections::HashSet::new();
+    library_records
+        .iter()
+        .filter(|record| seen.insert(record.transition_group_id.as_str()))
+        .map(|record| record.transition_group_id.clone())
+        .collect()
+}
+
+/// Build iRT and IM dictionaries straight from the library columns.
+/// Precursors without a parseable IonMobility value get no IM entry.
+pub fn create_library_rt_im_dicts(library_records: &[LibraryRecord]) -> (HashMap<String, f32>, HashMap<String, f32>) {
+    let mut irt_dict = HashMap::new();
+    let mut im_dict = HashMap::new();
+    
+    for record in library_records {
+        if irt_dict.contains_key(&record.transition_group_id) {
+            continue;
+        }
+        if let Ok(irt) = record.tr_recalibrated.parse::<f32>() {
+            irt_dict.insert(record.transition_group_id.clone(), irt);
+        }
+        if let Ok(im) = record.ion_mobility.parse::<f32>() {
+            if im > 0.0 {
+                im_dict.insert(record.transition_group_id.clone(), im);
+            }
+        }
+    }
+    
+    (irt_dict, im_dict)
+}
+
 // ============================================================================
 // TimsTOF 数据读取相关结构体和函数
 // ============================================================================
@@ -243,6 +297,24 @@ impl IndexedTimsTOFData {
         }
     }
 
+    /// Replace every m/z by `correct(mz, rt)` and restore the m/z-ascending order.
+    pub fn recalibrate_mz<F: Fn(f32, f32) -> f32 + Sync>(&mut self, correct: F) {
+        let corrected: Vec<f32> = self.mz_values
+            .par_iter()
+            .zip(self.rt_values_min.par_iter())
+            .map(|(&mz, &rt)| correct(mz, rt))
+            .collect();
+        let data = TimsTOFData {
+            rt_values_min: std::mem::take(&mut self.rt_values_min),
+            mobility_values: std::mem::take(&mut self.mobility_values),
+            mz_values: corrected,
+            intensity_values: std::mem::take(&mut self.intensity_values),
+            frame_indices: std::mem::take(&mut self.frame_indices),
+            scan_indices: std::mem::take(&mut self.scan_indices),
+        };
+        *self = Self::from_timstof_data(data);
+    }
+
     /// Locate the slice boundaries (binary search)
     #[inline]
     fn range_indices(&self, mz_min: f32, mz_max: f32) -> std::ops::Range<usize> {
@@ -294,6 +366,28 @@ impl IndexedTimsTOFData {
         td
     }
 
+    /// Every peak inside an RT and ion mobility range (full m/z range)
+    pub fn slice_by_rt_im_range(&self, rt_min: f32, rt_max: f32, im_min: f32, im_max: f32) -> TimsTOFData {
+        let indices: Vec<usize> = (0..self.mz_values.len())
+            .into_par_iter()
+            .filter(|&i| {
+                let (rt, im) = (self.rt_values_min[i], self.mobility_values[i]);
+                rt >= rt_min && rt <= rt_max && im >= im_min && im <= im_max
+            })
+            .collect();
+        
+        let mut td = TimsTOFData::with_capacity(indices.len());
+        for i in indices {
+            td.rt_values_min.push(self.rt_values_min[i]);
+            td.mobility_values.push(self.mobility_values[i]);
+            td.mz_values.push(self.mz_values[i]);
+            td.intensity_values.push(self.intensity_values[i]);
+            td.frame_indices.push(self.frame_indices[i]);
+            td.scan_indices.push(self.scan_indices[i]);
+        }
+        td
+    }
+
     /// Multiply m/z by 1000 (monotonic transform keeps sorting)
     pub fn convert_mz_to_integer(&mut self) {
         self.mz_values.iter_mut().for_each(|v| *v = (*v * 1000.0).ceil());
@@ -329,6 +423,40 @@ pub fn quantize(x: f32) -> u32 {
     (x * 10_000.0).round() as u32 
 }
 
+/// Small deterministic SplitMix64 generator, so seeded runs are reproducible
+/// without pulling in an RNG crate.
+#[derive(Debug, Clone)]
+pub struct SeededRng {
+    state: u64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+    
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+    
+    /// Uniform index in `0..bound`.
+    pub fn next_index(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+    
+    /// Fisher-Yates shuffle.
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.next_index(i + 1);
+            items.swap(i, j);
+        }
+    }
+}
+
 pub struct FrameSplit {
     pub ms1: TimsTOFData,
     pub ms2: Vec<((u32, u32), TimsTOFData)>,
@@ -456,18 +584,72 @@ pub struct LibraryRecord {
     pub precursor_charge: String,
     pub precursor_mz: String,
     pub tr_recalibrated: String,
+    pub ion_mobility: String,
     pub product_mz: String,
     pub fragment_type: String,
     pub fragment_charge: String,
     pub fragment_number: String,
+    pub fragment_loss_type: String,
     pub library_intensity: String,
     pub protein_id: String,
     pub protein_name: String,
     pub gene: String,
     pub decoy: String,
+    pub paired_precursor_id: String,
     pub other_columns: HashMap<String, String>,
 }
 
+#[cfg(test)]
+impl LibraryRecord {
+    /// Target y3 fragment of `sequence` (unmodified), RT 10 and IM 1; tests
+    /// override the rest with struct update syntax.
+    pub fn test_record(id: &str, sequence: &str, charge: &str) -> Self {
+        LibraryRecord {
+            transition_group_id: id.to_string(),
+            peptide_sequence: sequence.to_string(),
+            full_unimod_peptide_name: sequence.to_string(),
+            precursor_charge: charge.to_string(),
+            precursor_mz: "500".to_string(),
+            tr_recalibrated: "10".to_string(),
+            ion_mobility: "1".to_string(),
+            product_mz: "300".to_string(),
+            fragment_type: "2".to_string(),
+            fragment_charge: "1".to_string(),
+            fragment_number: "3".to_string(),
+            fragment_loss_type: "noloss".to_string(),
+            library_intensity: "1".to_string(),
+            protein_id: String::new(),
+            protein_name: String::new(),
+            gene: String::new(),
+            decoy: "0".to_string(),
+            paired_precursor_id: String::new(),
+            other_columns: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl PrecursorLibData {
+    /// Test precursor at `precursor_mz` with one original-variant MS2 row
+    /// (and one `test_record`) per fragment m/z.
+    pub fn test_precursor(id: &str, precursor_mz: f32, rt: f32, im: f32, fragment_mzs: &[f32]) -> Self {
+        PrecursorLibData {
+            precursor_id: id.to_string(),
+            im,
+            rt,
+            lib_records: fragment_mzs
+                .iter()
+                .map(|mz| LibraryRecord { product_mz: mz.to_string(), ..LibraryRecord::test_record(id, "PEPTIDEK", "2") })
+                .collect(),
+            ms1_data: Vec::new(),
+            ms2_data: fragment_mzs.iter().map(|&mz| vec![mz, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, VARIANT_ORIGINAL]).collect(),
+            precursor_info: vec![0.0, precursor_mz, 2.0, 0.0, fragment_mzs.len() as f32, 0.0],
+            interference: Vec::new(),
+            peak_width: None,
+        }
+    }
+}
+
 pub fn find_scan_for_index(index: usize, scan_offsets: &[usize]) -> usize {
     for (scan, window) in scan_offsets.windows(2).enumerate() {
         if index >= window[0] && index < window[1] {
@@ -516,21 +698,49 @@ pub fn get_lib_col_dict() -> HashMap<&'static str, &'static str> {
     for key in ["PrecursorCharge", "Charge", "prec_z"] { lib_col_dict.insert(key, "PrecursorCharge"); }
     for key in ["PrecursorMz", "Q1"] { lib_col_dict.insert(key, "PrecursorMz"); }
     for key in ["Tr_recalibrated", "iRT", "RetentionTime", "NormalizedRetentionTime", "RT_detected"] { lib_col_dict.insert(key, "Tr_recalibrated"); }
+    for key in ["IonMobility", "PrecursorIonMobility", "IM", "1/K0"] { lib_col_dict.insert(key, "IonMobility"); }
     for key in ["ProductMz", "FragmentMz", "Q3"] { lib_col_dict.insert(key, "ProductMz"); }
     for key in ["FragmentType", "FragmentIonType", "ProductType", "ProductIonType", "frg_type"] { lib_col_dict.insert(key, "FragmentType"); }
     for key in ["FragmentCharge", "FragmentIonCharge", "ProductCharge", "ProductIonCharge", "frg_z"] { lib_col_dict.insert(key, "FragmentCharge"); }
     for key in ["FragmentNumber", "frg_nr", "FragmentSeriesNumber"] { lib_col_dict.insert(key, "FragmentNumber"); }
+    for key in ["FragmentLossType", "FragmentLoss", "frg_loss"] { lib_col_dict.insert(key, "FragmentLossType"); }
     for key in ["LibraryIntensity", "RelativeIntensity", "RelativeFragmentIntensity", "RelativeFragmentIonIntensity", "relative_intensity"] { lib_col_dict.insert(key, "LibraryIntensity"); }
     for key in ["ProteinID", "ProteinId", "UniprotID", "uniprot_id", "UniProtIds"] { lib_col_dict.insert(key, "ProteinID"); }
     for key in ["ProteinName", "Protein Name", "Protein_name", "protein_name"] { lib_col_dict.insert(key, "ProteinName"); }
     for key in ["Gene", "Genes", "GeneName"] { lib_col_dict.insert(key, "Gene"); }
     for key in ["Decoy", "decoy"] { lib_col_dict.insert(key, "decoy"); }
+    for key in ["PairedPrecursorId", "paired_precursor_id", "DecoyPairId"] { lib_col_dict.insert(key, "PairedPrecursorId"); }
     lib_col_dict
 }
 
 // ... 继续包含所有其他的辅助函数 ...
 // Helper functions for MS data processing
-pub fn build_ms1_data(fragment_list: &[Vec<f32>], isotope_range: f32, max_mz: f32) -> MSDataArray {
+/// Theoretical isotope abundances (M, M+1, ...) of a precursor, relative to the
+/// most abundant peak, with peaks below `min_isotope_abundance` set to zero.
+/// Returns an empty vector for the legacy `none` model.
+pub fn theoretical_isotope_envelope(record: &LibraryRecord, library_cfg: &LibraryConfig) -> Vec<f32> {
+    let composition = match library_cfg.isotope_model.as_str() {
+        "composition" => ModifiedPeptide::parse(&record.full_unimod_peptide_name)
+            .and_then(|peptide| peptide.composition())
+            .ok(),
+        "averagine" => None,
+        _ => return Vec::new(),
+    };
+    
+    // Averagine (or composition fallback) from the neutral precursor mass
+    let composition = composition.unwrap_or_else(|| {
+        let mz = record.precursor_mz.parse::<f64>().unwrap_or(0.0);
+        let charge = record.precursor_charge.parse::<f64>().unwrap_or(1.0);
+        Composition::averagine((mz - PROTON_MASS) * charge)
+    });
+    
+    isotope_distribution(&composition, MS1_ISOTOPE_COUNT)
+        .into_iter()
+        .map(|v| if (v as f32) < library_cfg.min_isotope_abundance { 0.0 } else { v as f32 })
+        .collect()
+}
+
+pub fn build_ms1_data(fragment_list: &[Vec<f32>], isotope_range: f32, max_mz: f32, isotope_envelope: &[f32]) -> MSDataArray {
     let first_fragment = &fragment_list[0];
     let charge = first_fragment[1];
     let precursor_mz = first_fragment[5];
@@ -538,19 +748,36 @@ pub fn build_ms1_data(fragment_list: &[Vec<f32>], isotope_range: f32, max_mz: f3
     let available_range = (max_mz - precursor_mz) * charge;
     let iso_shift_max = (isotope_range.min(available_range) as i32) + 1;
     
-    let mut isotope_mz_list: Vec<f32> = (0..iso_shift_max)
-        .map(|iso_shift| precursor_mz + (iso_shift as f32) / charge)
-        .collect();
-    
-    isotope_mz_list = intercept_frags_sort(isotope_mz_list, MS1_ISOTOPE_COUNT);
+    // (m/z, expected intensity) per isotope; without an envelope keep the
+    // legacy 1/charge spacing and the first fragment's library intensity
+    let isotope_list: Vec<(f32, f32)> = if isotope_envelope.is_empty() {
+        let isotope_mz_list: Vec<f32> = (0..iso_shift_max)
+            .map(|iso_shift| precursor_mz + (iso_shift as f32) / charge)
+            .collect();
+        intercept_frags_sort(isotope_mz_list, MS1_ISOTOPE_COUNT)
+            .into_iter()
+            .map(|mz| (mz, first_fragment[3]))
+            .collect()
+    } else {
+        let mut isotope_list: Vec<(f32, f32)> = (0..iso_shift_max)
+            .map(|iso_shift| {
+                let mz = precursor_mz + (iso_shift as f64 * NEUTRON_SPACING) as f32 / charge;
+                (mz, isotope_envelope.get(iso_shift as usize).copied().unwrap_or(0.0))
+            })
+            .filter(|&(_, abundance)| abundance > 0.0)
+            .collect();
+        isotope_list.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+        isotope_list.truncate(MS1_ISOTOPE_COUNT);
+        isotope_list
+    };
     
     let mut ms1_data = Vec::new();
-    for mz in isotope_mz_list {
+    for (mz, expected_intensity) in isotope_list {
         let row = vec![
             mz,
             first_fragment[1],
             first_fragment[2],
-            first_fragment[3],
+            expected_intensity,
             3.0,
             first_fragment[5],
             MS1_TYPE_MARKER,
@@ -638,9 +865,10 @@ pub fn format_ms_data(
     fragment_list: &[Vec<f32>], 
     isotope_range: f32, 
     max_mz: f32, 
-    max_fragment: usize
+    max_fragment: usize,
+    isotope_envelope: &[f32],
 ) -> (MSDataArray, MSDataArray, Vec<f32>) {
-    let ms1_data = build_ms1_data(fragment_list, isotope_range, max_mz);
+    let ms1_data = build_ms1_data(fragment_list, isotope_range, max_mz, isotope_envelope);
     
     let fragment_list_subset: Vec<Vec<f32>> = fragment_list.iter()
         .map(|row| row[..6].to_vec())
@@ -662,9 +890,60 @@ pub fn format_ms_data(
     (ms1_data, ms2_data, precursor_info)
 }
 
+/// Apply the fragment selection rules of `library_cfg` to one precursor's
+/// records, keeping at most `max_fragment` (the number of fragment slots).
+pub fn select_fragments<'a>(
+    records: Vec<&'a LibraryRecord>,
+    library_cfg: &LibraryConfig,
+    max_fragment: usize,
+) -> Vec<&'a LibraryRecord> {
+    let mut selected: Vec<&LibraryRecord> = records
+        .into_iter()
+        .filter(|record| {
+            let mz = record.product_mz.parse::<f32>().unwrap_or(0.0);
+            let precursor_mz = record.precursor_mz.parse::<f32>().unwrap_or(0.0);
+            let is_internal = record.fragment_type == INTERNAL_FRAGMENT_CODE;
+            let has_loss = !matches!(record.fragment_loss_type.as_str(), "" | "noloss");
+
+            if mz < library_cfg.min_fragment_mz {
+                return false;
+            }
+            if library_cfg.isolation_exclusion > 0.0 && (mz - precursor_mz).abs() <= library_cfg.isolation_exclusion {
+                return false;
+            }
+            if !library_cfg.include_neutral_losses && has_loss {
+                return false;
+            }
+            if !library_cfg.include_internal_ions && is_internal {
+                return false;
+            }
+            // precursor and internal ions carry no meaningful fragment number
+            if library_cfg.min_fragment_number > 0 && !is_internal && record.fragment_type != "3" {
+                let number = record.fragment_number.parse::<usize>().unwrap_or(0);
+                if number < library_cfg.min_fragment_number {
+                    return false;
+                }
+            }
+            true
+        })
+        .collect();
+
+    if library_cfg.rank_by_intensity {
+        // stable sort keeps library order among equal intensities
+        selected.sort_by(|a, b| {
+            let ia = a.library_intensity.parse::<f32>().unwrap_or(0.0);
+            let ib = b.library_intensity.parse::<f32>().unwrap_or(0.0);
+            ib.partial_cmp(&ia).unwrap_or(Ordering::Equal)
+        });
+    }
+    selected.truncate(library_cfg.max_fragments.min(max_fragment));
+    selected
+}
+
 pub fn build_lib_matrix(
     lib_data: &[LibraryRecord],
     lib_cols: &LibCols,
+    library_cfg: &LibraryConfig,
     iso_range: f32,
     mz_max: f32,
     max_fragment: usize,
@@ -688,6 +967,12 @@ pub fn build_lib_matrix(
         let first_idx = indices[0];
         let first_record = &lib_data[first_idx];
         
+        let records: Vec<&LibraryRecord> = indices.iter().map(|&idx| &lib_data[idx]).collect();
+        let selected = select_fragments(records, library_cfg, max_fragment);
+        if selected.is_empty() {
+            continue;
+        }
+        
         let precursor_info = vec![
             first_record.transition_group_id.clone(),
             first_record.decoy.clone(),
@@ -695,9 +980,7 @@ pub fn build_lib_matrix(
         all_precursors.push(precursor_info);
         
         let mut group_fragments = Vec::new();
-        for &idx in indices {
-            let record = &lib_data[idx];
-            
+        for record in selected {
             let fragment_row = vec![
                 record.product_mz.parse::<f32>().unwrap_or(0.0),
                 record.precursor_charge.parse::<f32>().unwrap_or(0.0),
@@ -713,7 +996,8 @@ pub fn build_lib_matrix(
             group_fragments.push(fragment_row);
         }
         
-        let (ms1, ms2, info) = format_ms_data(&group_fragments, iso_range, mz_max, max_fragment);
+        let isotope_envelope = theoretical_isotope_envelope(first_record, library_cfg);
+        let (ms1, ms2, info) = format_ms_data(&group_fragments, iso_range, mz_max, max_fragment, &isotope_envelope);
         
         all_ms1_data.push(ms1);
         all_ms2_data.push(ms2);
@@ -1185,15 +1469,18 @@ pub fn process_library_fast(file_path: &str) -> Result<Vec<LibraryRecord>, Box<d
             precursor_charge: String::new(),
             precursor_mz: String::new(),
             tr_recalibrated: String::new(),
+            ion_mobility: String::new(),
             product_mz: String::new(),
             fragment_type: String::new(),
             fragment_charge: String::new(),
             fragment_number: String::new(),
+            fragment_loss_type: String::new(),
             library_intensity: String::new(),
             protein_id: String::new(),
             protein_name: String::new(),
             gene: String::new(),
             decoy: "0".to_string(),
+            paired_precursor_id: String::new(),
             other_columns: HashMap::new(),
         };
         
@@ -1223,15 +1510,16 @@ pub fn process_library_fast(file_path: &str) -> Result<Vec<LibraryRecord>, Box<d
                 rec.product_mz = String::from_utf8_lossy(val).into_owned(); 
             } 
         }
+        let mut annotation = None;
         if let Some(&idx) = mapped_indices.get("FragmentType") {
             if let Some(val) = record.get(idx) {
                 let fragment_str = String::from_utf8_lossy(val);
-                rec.fragment_type = match fragment_str.as_ref() { 
-                    "b" => "1".to_string(), 
-                    "y" => "2".to_string(), 
-                    "p" => "3".to_string(), 
-                    _ => fragment_str.into_owned() 
+                let parsed = parse_fragment_annotation(&fragment_str);
+                rec.fragment_type = match fragment_type_code(&parsed.series) {
+                    Some(code) => code.to_string(),
+                    None => fragment_str.into_owned(),
                 };
+                annotation = Some(parsed);
             }
         }
         if let Some(&idx) = mapped_indices.get("FragmentCharge") { 
@@ -1239,6 +1527,11 @@ pub fn process_library_fast(file_path: &str) -> Result<Vec<LibraryRecord>, Box<d
                 rec.fragment_charge = String::from_utf8_lossy(val).into_owned(); 
             } 
         }
+        if let Some(&idx) = mapped_indices.get("FragmentLossType") { 
+            if let Some(val) = record.get(idx) { 
+                rec.fragment_loss_type = String::from_utf8_lossy(val).into_owned(); 
+            } 
+        }
         if let Some(&idx) = mapped_indices.get("LibraryIntensity") { 
             if let Some(val) = record.get(idx) { 
                 rec.library_intensity = String::from_utf8_lossy(val).into_owned(); 
@@ -1249,6 +1542,11 @@ pub fn process_library_fast(file_path: &str) -> Result<Vec<LibraryRecord>, Box<d
                 rec.tr_recalibrated = String::from_utf8_lossy(val).into_owned(); 
             } 
         }
+        if let Some(&idx) = mapped_indices.get("IonMobility") { 
+            if let Some(val) = record.get(idx) { 
+                rec.ion_mobility = String::from_utf8_lossy(val).into_owned(); 
+            } 
+        }
         if let Some(&idx) = mapped_indices.get("ProteinID") { 
             if let Some(val) = record.get(idx) { 
                 rec.protein_id = String::from_utf8_lossy(val).into_owned(); 
@@ -1265,12 +1563,40 @@ pub fn process_library_fast(file_path: &str) -> Result<Vec<LibraryRecord>, Box<d
             } 
         }
         
+        if let Some(&idx) = mapped_indices.get("decoy") {
+            if let Some(val) = record.get(idx) {
+                rec.decoy = match String::from_utf8_lossy(val).trim() {
+                    "1" | "True" | "true" | "TRUE" => "1".to_string(),
+                    _ => "0".to_string(),
+                };
+            }
+        }
+        if let Some(&idx) = mapped_indices.get("PairedPrecursorId") {
+            if let Some(val) = record.get(idx) {
+                rec.paired_precursor_id = String::from_utf8_lossy(val).into_owned();
+            }
+        }
+        
         if let Some(idx) = fragment_number_idx {
             if let Some(val) = record.get(idx) {
                 rec.fragment_number = String::from_utf8_lossy(val).into_owned();
             }
         }
         
+        // Number and loss embedded in the annotation (y5-H2O) fill missing columns
+        if let Some(annotation) = annotation {
+            if rec.fragment_number.is_empty() {
+                if let Some(number) = annotation.number {
+                    rec.fragment_number = number;
+                }
+            }
+            if rec.fragment_loss_type.is_empty() || rec.fragment_loss_type == "noloss" {
+                if let Some(loss) = annotation.loss {
+                    rec.fragment_loss_type = loss;
+                }
+            }
+        }
+        
         // Generate transition_group_id
         rec.transition_group_id = format!("{}{}", rec.full_unimod_peptide_name, rec.precursor_charge);
         rec
@@ -1322,18 +1648,45 @@ pub fn create_rt_im_dicts(df: &DataFrame) -> PolarsResult<(HashMap<String, f32>,
     Ok((rt_dict, im_dict))
 }
 
+/// Per-precursor value of a numeric report column (e.g. `Q.Value`,
+/// `Precursor.Quantity`); repeated rows are merged with `combine`.
+pub fn create_report_value_dict(
+    report_df: &DataFrame,
+    column: &str,
+    combine: fn(f32, f32) -> f32,
+) -> PolarsResult<HashMap<String, f32>> {
+    let ids = report_df.column("transition_group_id")?.str()?;
+    let value_col = report_df.column(column)?.cast(&DataType::Float32)?;
+    let values = value_col.f32()?;
+    
+    let mut value_dict: HashMap<String, f32> = HashMap::new();
+    for (id, value) in ids.into_iter().zip(values) {
+        if let (Some(id), Some(value)) = (id, value) {
+            let merged = value_dict.entry(id.to_string()).or_insert(value);
+            *merged = combine(*merged, value);
+        }
+    }
+    Ok(value_dict)
+}
+
 // Add these functions to utils.rs after the existing code
 
-pub fn get_rt_list(mut lst: Vec<f32>, target: f32) -> Vec<f32> {
+pub fn get_rt_list(lst: Vec<f32>, target: f32) -> Vec<f32> {
+    fit_rt_list(lst, target, 48)
+}
+
+/// `get_rt_list` for `n_points` RTs: sorted, zero-padded at the end or
+/// cropped to the `n_points` closest around `target`.
+pub fn fit_rt_list(mut lst: Vec<f32>, target: f32, n_points: usize) -> Vec<f32> {
     lst.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
     
     if lst.is_empty() {
-        return vec![0.0; 48];
+        return vec![0.0; n_points];
     }
     
-    if lst.len() <= 48 {
+    if lst.len() <= n_points {
         let mut result = lst;
-        result.resize(48, 0.0);
+        result.resize(n_points, 0.0);
         return result;
     }
     
@@ -1343,13 +1696,14 @@ pub fn get_rt_list(mut lst: Vec<f32>, target: f32) -> Vec<f32> {
         .map(|(idx, _)| idx)
         .unwrap_or(0);
     
-    let start = if closest_idx >= 24 {
-        (closest_idx - 24).min(lst.len() - 48)
+    let half = n_points / 2;
+    let start = if closest_idx >= half {
+        (closest_idx - half).min(lst.len() - n_points)
     } else {
         0
     };
     
-    lst[start..start + 48].to_vec()
+    lst[start..start + n_points].to_vec()
 }
 
 pub fn build_ext_ms1_matrix(ms1_data_tensor: &Array3<f32>, device: &str) -> Array3<f32> {
@@ -1439,4 +1793,112 @@ pub fn build_frag_info(
     }
     
     frag_info
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library_cfg(isotope_model: &str) -> LibraryConfig {
+        LibraryConfig { isotope_model: isotope_model.to_string(), min_isotope_abundance: 0.001, ..LibraryConfig::default() }
+    }
+
+    #[test]
+    fn isotope_envelope_from_composition_or_averagine() {
+        let record = LibraryRecord { precursor_mz: "400.68726".to_string(), ..LibraryRecord::test_record("PEPTIDE2", "PEPTIDE", "2") };
+
+        let composition = theoretical_isotope_envelope(&record, &library_cfg("composition"));
+        assert_eq!(composition.len(), MS1_ISOTOPE_COUNT);
+        assert_eq!(composition[0], 1.0);
+        assert!((composition[1] - 0.4051).abs() < 1e-3);
+        assert_eq!(composition[5], 0.0, "M+5 (0.00057) is below min_isotope_abundance");
+
+        // Averagine of the same mass agrees on the first isotopes
+        let averagine = theoretical_isotope_envelope(&record, &library_cfg("averagine"));
+        assert_eq!(averagine[0], 1.0);
+        assert!((averagine[1] - composition[1]).abs() < 0.05);
+        assert!((averagine[2] - composition[2]).abs() < 0.01);
+
+        assert!(theoretical_isotope_envelope(&record, &library_cfg("none")).is_empty());
+    }
+
+    #[test]
+    fn ms1_rows_keep_six_isotopes_heaviest_first() {
+        // precursor m/z 400.5 at charge 2, library intensity 50
+        let fragment = vec![0.0, 2.0, 0.0, 50.0, 0.0, 400.5, 0.0, 0.0, 0.0];
+
+        let legacy = build_ms1_data(std::slice::from_ref(&fragment), 10.0, 2000.0, &[]);
+        assert_eq!(legacy.len(), MS1_ISOTOPE_COUNT);
+        let mzs: Vec<f32> = legacy.iter().map(|row| row[0]).collect();
+        assert_eq!(mzs, vec![405.5, 405.0, 404.5, 404.0, 403.5, 403.0]);
+        assert!(legacy.iter().all(|row| row[3] == 50.0 && row[6] == MS1_TYPE_MARKER));
+
+        let envelope = [1.0, 0.4, 0.1, 0.02, 0.004, 0.0];
+        let rows = build_ms1_data(std::slice::from_ref(&fragment), 10.0, 2000.0, &envelope);
+        assert_eq!(rows.len(), MS1_ISOTOPE_COUNT);
+        let isotopes: Vec<(f32, f32)> = rows.iter().map(|row| (row[0], row[3])).collect();
+        assert!((isotopes[0].0 - (400.5 + 4.0 * NEUTRON_SPACING as f32 / 2.0)).abs() < 1e-4);
+        assert_eq!(isotopes[4], (400.5, 1.0));
+        assert_eq!(isotopes[5], (0.0, 0.0), "zero-abundance isotope dropped and padded");
+    }
+
+    fn fragment(number: &str, product_mz: &str, intensity: &str) -> LibraryRecord {
+        LibraryRecord {
+            precursor_mz: "500".to_string(),
+            product_mz: product_mz.to_string(),
+            fragment_number: number.to_string(),
+            library_intensity: intensity.to_string(),
+            ..LibraryRecord::test_record("PEPTIDEK2", "PEPTIDEK", "2")
+        }
+    }
+
+    fn selected_numbers(records: &[LibraryRecord], library_cfg: &LibraryConfig, max_fragment: usize) -> Vec<String> {
+        select_fragments(records.iter().collect(), library_cfg, max_fragment).iter().map(|r| r.fragment_number.clone()).collect()
+    }
+
+    #[test]
+    fn fragment_rules_filter_then_rank() {
+        let records = vec![
+            fragment("2", "250", "90"),
+            fragment("3", "380", "10"),
+            fragment("4", "499.5", "100"),
+            fragment("5", "620", "40"),
+            LibraryRecord { fragment_loss_type: "H2O".to_string(), ..fragment("6", "700", "80") },
+            LibraryRecord { fragment_type: INTERNAL_FRAGMENT_CODE.to_string(), ..fragment("", "330", "70") },
+            LibraryRecord { fragment_type: "3".to_string(), ..fragment("", "1000", "5") },
+        ];
+
+        // Defaults keep everything in library order, up to the slot count
+        assert_eq!(selected_numbers(&records, &LibraryConfig::default(), 3), ["2", "3", "4"]);
+
+        let library_cfg = LibraryConfig {
+            rank_by_intensity: true,
+            max_fragments: 3,
+            min_fragment_mz: 300.0,
+            min_fragment_number: 3,
+            isolation_exclusion: 1.0,
+            include_neutral_losses: false,
+            include_internal_ions: false,
+            ..LibraryConfig::default()
+        };
+        // 2 (number and m/z), 4 (isolation window), 6 (loss) and the internal ion are dropped;
+        // the precursor ion has no fragment number and stays
+        assert_eq!(selected_numbers(&records, &library_cfg, 20), ["5", "3", ""]);
+
+        let with_ions = LibraryConfig { include_neutral_losses: true, include_internal_ions: true, ..library_cfg };
+        assert_eq!(selected_numbers(&records, &with_ions, 20), ["6", "", "5"]);
+    }
+
+    #[test]
+    fn fit_rt_list_pads_short_lists_at_the_end() {
+        assert_eq!(fit_rt_list(vec![3.0, 1.0, 2.0], 2.0, 5), vec![1.0, 2.0, 3.0, 0.0, 0.0]);
+        assert_eq!(fit_rt_list(Vec::new(), 2.0, 3), vec![0.0; 3]);
+    }
+
+    #[test]
+    fn fit_rt_list_crops_around_the_target() {
+        let rts: Vec<f32> = (0..10).map(|k| k as f32).collect();
+        assert_eq!(fit_rt_list(rts.clone(), 5.2, 4), vec![3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(fit_rt_list(rts.clone(), 0.0, 4), vec![0.0, 1.0, 2.0, 3.0]);
+        assert_eq!(fit_rt_list(rts, 9.0, 4), vec![6.0, 7.0, 8.0, 9.0]);
+    }
+}
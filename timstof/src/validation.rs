@@ -0,0 +1,195 @@
+// File: src/validation.rs
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use rayon::prelude::*;
+
+use crate::chemistry::{ModifiedPeptide, fragment_ion_type, fragment_neutral_loss, ion_type_name, mz_from_mass};
+use crate::config::ValidationConfig;
+use crate::utils::{LibraryRecord, group_library_records};
+
+/// Library m/z compared with the value recomputed from the sequence.
+#[derive(Debug, Clone)]
+pub struct MzDeviation {
+    pub transition_group_id: String,
+    pub annotation: String, // "precursor" or e.g. "y5^2-H2O"
+    pub library_mz: f64,
+    pub computed_mz: f64,
+    pub ppm: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct LibraryValidation {
+    pub precursor_deviations: Vec<MzDeviation>,
+    pub fragment_deviations: Vec<MzDeviation>,
+    pub skipped_precursors: usize, // unparseable sequence / unknown modification
+    pub skipped_fragments: usize,  // unsupported fragment annotation
+}
+
+fn deviation(transition_group_id: &str, annotation: String, library_mz: f64, computed_mz: f64) -> MzDeviation {
+    MzDeviation {
+        transition_group_id: transition_group_id.to_string(),
+        annotation,
+        library_mz,
+        computed_mz,
+        ppm: (library_mz - computed_mz) / computed_mz * 1e6,
+    }
+}
+
+/// Recompute precursor and fragment m/z of every library entry from
+/// `FullUniModPeptideName`, `FragmentType`, `FragmentNumber` and `FragmentCharge`.
+pub fn validate_library(library_records: &[LibraryRecord]) -> LibraryValidation {
+    let record_index = group_library_records(library_records);
+    let groups: Vec<&Vec<usize>> = record_index.values().collect();
+
+    groups
+        .par_iter()
+        .map(|indices| {
+            let mut result = LibraryValidation::default();
+            let first = &library_records[indices[0]];
+            let peptide = match ModifiedPeptide::parse(&first.full_unimod_peptide_name) {
+                Ok(peptide) => peptide,
+                Err(_) => {
+                    result.skipped_precursors += 1;
+                    result.skipped_fragments += indices.len();
+                    return result;
+                }
+            };
+
+            let id = first.transition_group_id.as_str();
+            let precursor_charge = first.precursor_charge.parse::<u32>().unwrap_or(0);
+            match (peptide.precursor_isotope_mzs(precursor_charge, 3), first.precursor_mz.parse::<f64>()) {
+                (Ok(isotopes), Ok(library_mz)) if precursor_charge > 0 => {
+                    // ppm is always against the monoisotope; flag libraries that list M+1/M+2 instead
+                    let nearest = (0..isotopes.len())
+                        .min_by(|&a, &b| (isotopes[a] - library_mz).abs().total_cmp(&(isotopes[b] - library_mz).abs()))
+                        .unwrap_or(0);
+                    let annotation = if nearest == 0 { "precursor".to_string() } else { format!("precursor_M+{}", nearest) };
+                    result.precursor_deviations.push(deviation(id, annotation, library_mz, isotopes[0]));
+                }
+                _ => result.skipped_precursors += 1,
+            }
+
+            for &idx in indices.iter() {
+                let record = &library_records[idx];
+                let (Ok(library_mz), Ok(charge)) = (record.product_mz.parse::<f64>(), record.fragment_charge.parse::<u32>()) else {
+                    result.skipped_fragments += 1;
+                    continue;
+                };
+
+                // "3" is the precursor-ion code assigned by process_library_fast
+                let computed = if record.fragment_type == "3" || record.fragment_type == "p" {
+                    peptide.monoisotopic_mass().map(|mass| (mz_from_mass(mass, charge), format!("p^{}", charge)))
+                } else {
+                    let ion_type = fragment_ion_type(&record.fragment_type);
+                    let number = record.fragment_number.parse::<usize>().ok();
+                    let loss = fragment_neutral_loss(&record.fragment_loss_type);
+                    match (ion_type, number) {
+                        (Some(ion_type), Some(number)) => peptide
+                            .fragment_mz(ion_type, number, charge, loss)
+                            .map(|mz| {
+                                let loss_tag = loss.map(|l| format!("-{:?}", l)).unwrap_or_default();
+                                (mz, format!("{}{}^{}{}", ion_type_name(ion_type), number, charge, loss_tag))
+                            }),
+                        _ => Err("unsupported fragment annotation".into()),
+                    }
+                };
+
+                match computed {
+                    Ok((computed_mz, annotation)) => {
+                        result.fragment_deviations.push(deviation(id, annotation, library_mz, computed_mz));
+                    }
+                    Err(_) => result.skipped_fragments += 1,
+                }
+            }
+            result
+        })
+        .reduce(LibraryValidation::default, |mut a, mut b| {
+            a.precursor_deviations.append(&mut b.precursor_deviations);
+            a.fragment_deviations.append(&mut b.fragment_deviations);
+            a.skipped_precursors += b.skipped_precursors;
+            a.skipped_fragments += b.skipped_fragments;
+            a
+        })
+}
+
+fn abs_ppm_percentile(deviations: &[MzDeviation], q: f64) -> f64 {
+    if deviations.is_empty() {
+        return 0.0;
+    }
+    let mut abs_ppm: Vec<f64> = deviations.iter().map(|d| d.ppm.abs()).collect();
+    abs_ppm.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    abs_ppm[((abs_ppm.len() - 1) as f64 * q).round() as usize]
+}
+
+/// Print a summary and write every entry beyond the ppm threshold to a TSV.
+pub fn report_library_validation(validation: &LibraryValidation, config: &ValidationConfig) -> Result<(), Box<dyn Error>> {
+    println!("\n========== LIBRARY VALIDATION ==========");
+    for (label, deviations, skipped) in [
+        ("Precursors", &validation.precursor_deviations, validation.skipped_precursors),
+        ("Fragments", &validation.fragment_deviations, validation.skipped_fragments),
+    ] {
+        let n_outliers = deviations.iter().filter(|d| d.ppm.abs() > config.ppm_threshold).count();
+        println!("{}: {} checked, {} skipped", label, deviations.len(), skipped);
+        println!("  - |ppm| median: {:.3}, 95%: {:.3}, max: {:.3}",
+                 abs_ppm_percentile(deviations, 0.5),
+                 abs_ppm_percentile(deviations, 0.95),
+                 abs_ppm_percentile(deviations, 1.0));
+        println!("  - Beyond {:.1} ppm: {}", config.ppm_threshold, n_outliers);
+    }
+
+    let mut writer = BufWriter::new(File::create(&config.output_path)?);
+    writeln!(writer, "transition_group_id\tannotation\tlibrary_mz\tcomputed_mz\tppm")?;
+    for d in validation.precursor_deviations.iter().chain(&validation.fragment_deviations) {
+        if d.ppm.abs() > config.ppm_threshold {
+            writeln!(writer, "{}\t{}\t{:.6}\t{:.6}\t{:.3}", d.transition_group_id, d.annotation, d.library_mz, d.computed_mz, d.ppm)?;
+        }
+    }
+    println!("Deviations written to: {}", config.output_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chemistry::NEUTRON_SPACING;
+
+    #[test]
+    fn flags_a_wrong_product_mz() {
+        let precursor = LibraryRecord {
+            precursor_mz: "400.68726".to_string(),
+            product_mz: "376.17144".to_string(),
+            ..LibraryRecord::test_record("PEPTIDE2", "PEPTIDE", "2")
+        };
+        let wrong_b2 = LibraryRecord {
+            fragment_type: "1".to_string(),
+            fragment_number: "2".to_string(),
+            product_mz: "227.2026".to_string(),
+            ..precursor.clone()
+        };
+        let internal = LibraryRecord { fragment_type: "8".to_string(), ..precursor.clone() };
+
+        let validation = validate_library(&[precursor, wrong_b2, internal]);
+        assert_eq!(validation.precursor_deviations.len(), 1);
+        assert_eq!(validation.precursor_deviations[0].annotation, "precursor");
+        assert!(validation.precursor_deviations[0].ppm.abs() < 1.0);
+        assert_eq!(validation.skipped_fragments, 1);
+
+        let ppm: Vec<(&str, f64)> = validation.fragment_deviations.iter().map(|d| (d.annotation.as_str(), d.ppm)).collect();
+        assert_eq!(ppm.len(), 2);
+        assert_eq!(ppm[0].0, "y3^1");
+        assert!(ppm[0].1.abs() < 1.0);
+        assert_eq!(ppm[1].0, "b2^1");
+        assert!((ppm[1].1 - 440.3).abs() < 1.0, "{}", ppm[1].1);
+    }
+
+    #[test]
+    fn reports_a_library_listing_a_heavier_isotope() {
+        let record = LibraryRecord {
+            precursor_mz: format!("{}", 400.68726 + NEUTRON_SPACING),
+            ..LibraryRecord::test_record("PEPTIDE2", "PEPTIDE", "2")
+        };
+        let validation = validate_library(&[record]);
+        assert_eq!(validation.precursor_deviations[0].annotation, "precursor_M+2");
+    }
+}